@@ -0,0 +1,166 @@
+"""Generate the fixtures for the parity tests against Python basic-pitch.
+
+Writes synthetic test signals to test_data/parity/*.wav and, for every signal and
+test_data/C_major.wav, the outputs of the Python implementation to test_data/parity/<name>.npz:
+
+    contours, frames, onsets  model outputs of basic_pitch.inference.run_inference
+    audio_n_samples           length of the audio at 22050 Hz
+    inferred_onsets           get_infered_onsets(onsets, frames)
+    onset_peaks               scipy.signal.argrelmax(inferred_onsets, axis=0) as (row, col) pairs
+    notes                     output_to_notes_polyphonic(...) as (start, end, pitch, amplitude) rows
+    pitch_bends               get_pitch_bends(contours, notes) concatenated into one row
+    frame_times               model_frames_to_time(n_frames)
+
+The signals only need the standard library (`--signals-only`), the reference outputs need
+`pip install basic-pitch[onnx]`. The model in model/ is used so both implementations run the same
+weights. Run from the repository root:
+
+    python scripts/generate_parity_fixtures.py
+"""
+
+import argparse
+import math
+import struct
+import wave
+from pathlib import Path
+
+SAMPLE_RATE = 22050
+PARITY_DIR = Path("test_data/parity")
+
+# decoding settings of the fixtures, keep in sync with src/parity_tests.rs
+ONSET_THRESH = 0.5
+FRAME_THRESH = 0.3
+MIN_NOTE_LEN = 11
+ENERGY_TOL = 11
+N_BINS_TOLERANCE = 25
+
+
+def midi_to_hz(pitch):
+    return 440.0 * 2.0 ** ((pitch - 69) / 12)
+
+
+def envelope(t, start, end, fade=0.01):
+    if t < start or t >= end:
+        return 0.0
+    return min(1.0, (t - start) / fade, (end - t) / fade)
+
+
+def sine_a4(t, phase):
+    return 0.5 * envelope(t, 0.1, 1.9) * math.sin(2 * math.pi * 440.0 * t)
+
+
+def c_major_triad(t, phase):
+    # an arpeggio that ends as a chord, with a few harmonics
+    value = 0.0
+    for pitch, start in [(60, 0.2), (64, 0.6), (67, 1.0)]:
+        hz = midi_to_hz(pitch)
+        tone = sum(math.sin(2 * math.pi * k * hz * t) / k for k in range(1, 5))
+        value += 0.2 * envelope(t, start, 1.8) * tone
+    return value
+
+
+def vibrato_g4(t, phase):
+    # +-50 cents at 5.5 Hz, the phase is integrated by the caller
+    return 0.5 * envelope(t, 0.1, 1.9) * math.sin(phase)
+
+
+def vibrato_g4_hz(t):
+    return midi_to_hz(67 + 0.5 * math.sin(2 * math.pi * 5.5 * t))
+
+
+SIGNALS = {
+    "sine_a4": (sine_a4, None),
+    "c_major_triad": (c_major_triad, None),
+    "vibrato_g4": (vibrato_g4, vibrato_g4_hz),
+}
+
+
+def write_signal(name, duration=2.0):
+    function, frequency = SIGNALS[name]
+    samples = []
+    phase = 0.0
+    for n in range(int(duration * SAMPLE_RATE)):
+        t = n / SAMPLE_RATE
+        samples.append(function(t, phase))
+        if frequency is not None:
+            phase += 2 * math.pi * frequency(t) / SAMPLE_RATE
+
+    path = PARITY_DIR / f"{name}.wav"
+    with wave.open(str(path), "wb") as wav:
+        wav.setnchannels(1)
+        wav.setsampwidth(2)
+        wav.setframerate(SAMPLE_RATE)
+        wav.writeframes(b"".join(struct.pack("<h", round(max(-1.0, min(1.0, s)) * 32767)) for s in samples))
+    return path
+
+
+def write_reference(name, audio_path, model):
+    import librosa
+    import numpy as np
+    import scipy.signal
+    from basic_pitch.inference import run_inference
+    from basic_pitch.note_creation import (
+        get_infered_onsets,
+        get_pitch_bends,
+        model_frames_to_time,
+        output_to_notes_polyphonic,
+    )
+
+    output = run_inference(audio_path, model)
+    contours, frames, onsets = output["contour"], output["note"], output["onset"]
+
+    audio, _ = librosa.load(str(audio_path), sr=SAMPLE_RATE, mono=True)
+
+    inferred_onsets = get_infered_onsets(onsets, frames)
+    onset_peaks = np.stack(scipy.signal.argrelmax(inferred_onsets, axis=0), axis=1)
+
+    notes = output_to_notes_polyphonic(
+        frames,
+        onsets,
+        onset_thresh=ONSET_THRESH,
+        frame_thresh=FRAME_THRESH,
+        min_note_len=MIN_NOTE_LEN,
+        infer_onsets=True,
+        max_freq=None,
+        min_freq=None,
+        melodia_trick=True,
+        energy_tol=ENERGY_TOL,
+    )
+    notes_with_bends = get_pitch_bends(contours, notes, N_BINS_TOLERANCE)
+    pitch_bends = [bend for note in notes_with_bends for bend in note[4]]
+
+    np.savez(
+        PARITY_DIR / f"{name}.npz",
+        contours=contours.astype(np.float32),
+        frames=frames.astype(np.float32),
+        onsets=onsets.astype(np.float32),
+        audio_n_samples=np.int64(len(audio)),
+        inferred_onsets=inferred_onsets.astype(np.float32),
+        onset_peaks=onset_peaks.astype(np.float64).reshape(-1, 2),
+        notes=np.array(notes, dtype=np.float64).reshape(-1, 4),
+        pitch_bends=np.array(pitch_bends, dtype=np.float64).reshape(1, -1),
+        frame_times=model_frames_to_time(frames.shape[0]).astype(np.float64).reshape(1, -1),
+    )
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--signals-only", action="store_true", help="only write the synthetic signals")
+    args = parser.parse_args()
+
+    PARITY_DIR.mkdir(parents=True, exist_ok=True)
+    inputs = {name: write_signal(name) for name in SIGNALS}
+    inputs["C_major"] = Path("test_data/C_major.wav")
+    if args.signals_only:
+        return
+
+    from basic_pitch.inference import Model
+
+    model = Model("model/icassp_2022_nmp.onnx")
+    for name, audio_path in inputs.items():
+        write_reference(name, audio_path, model)
+        print(f"wrote {PARITY_DIR / name}.npz")
+
+
+if __name__ == "__main__":
+    main()
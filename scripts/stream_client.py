@@ -0,0 +1,161 @@
+"""Stream a WAV file to `basic-pitch stream` and print the note events it sends back.
+
+Plays the role of a live client for testing: the audio is converted to mono 16-bit PCM at
+22050 Hz and sent in 100 ms pieces, in real time with `--realtime`. Only needs the standard
+library. Start the server and run, from the repository root:
+
+    basic-pitch stream --websocket 127.0.0.1:8001 --tcp 127.0.0.1:8002
+    python scripts/stream_client.py test_data/C_major.wav --websocket ws://127.0.0.1:8001/?onset_threshold=0.6
+    python scripts/stream_client.py test_data/C_major.wav --tcp 127.0.0.1:8002
+"""
+
+import argparse
+import base64
+import os
+import socket
+import struct
+import sys
+import threading
+import time
+import wave
+from array import array
+from urllib.parse import urlsplit
+
+SAMPLE_RATE = 22050
+CHUNK_SECONDS = 0.1
+
+
+def read_pcm(path):
+    """Read a 16-bit WAV file as mono 16-bit PCM at 22050 Hz, resampled linearly if needed."""
+    with wave.open(str(path), "rb") as wav:
+        if wav.getsampwidth() != 2:
+            sys.exit("only 16-bit WAV files are supported")
+        n_channels = wav.getnchannels()
+        rate = wav.getframerate()
+        samples = array("h", wav.readframes(wav.getnframes()))
+    if sys.byteorder == "big":
+        samples.byteswap()
+
+    mono = [sum(samples[i:i + n_channels]) / n_channels for i in range(0, len(samples), n_channels)]
+    if rate != SAMPLE_RATE and mono:
+        n_out = int(len(mono) * SAMPLE_RATE / rate)
+        resampled = []
+        for i in range(n_out):
+            position = i * rate / SAMPLE_RATE
+            j = min(int(position), len(mono) - 1)
+            fraction = position - j
+            following = mono[min(j + 1, len(mono) - 1)]
+            resampled.append(mono[j] * (1 - fraction) + following * fraction)
+        mono = resampled
+
+    pcm = array("h", (max(-32768, min(32767, round(v))) for v in mono))
+    if sys.byteorder == "big":
+        pcm.byteswap()
+    return pcm.tobytes()
+
+
+def chunks(pcm, realtime):
+    size = int(SAMPLE_RATE * CHUNK_SECONDS) * 2
+    for start in range(0, len(pcm), size):
+        yield pcm[start:start + size]
+        if realtime:
+            time.sleep(CHUNK_SECONDS)
+
+
+def stream_tcp(address, pcm, realtime):
+    host, port = address.rsplit(":", 1)
+    with socket.create_connection((host, int(port))) as sock:
+        def receive():
+            for line in sock.makefile("r", encoding="utf-8"):
+                print(line.rstrip())
+
+        receiver = threading.Thread(target=receive)
+        receiver.start()
+        for chunk in chunks(pcm, realtime):
+            sock.sendall(chunk)
+        # the end of the stream
+        sock.shutdown(socket.SHUT_WR)
+        receiver.join()
+
+
+def send_frame(sock, opcode, payload):
+    """Send a masked WebSocket frame, as clients must."""
+    head = bytes([0x80 | opcode])
+    if len(payload) < 126:
+        head += bytes([0x80 | len(payload)])
+    elif len(payload) < 1 << 16:
+        head += bytes([0x80 | 126]) + struct.pack(">H", len(payload))
+    else:
+        head += bytes([0x80 | 127]) + struct.pack(">Q", len(payload))
+    mask = os.urandom(4)
+    sock.sendall(head + mask + bytes(b ^ mask[i % 4] for i, b in enumerate(payload)))
+
+
+def read_exact(file, n):
+    data = file.read(n)
+    if len(data) < n:
+        raise ConnectionError("connection closed")
+    return data
+
+
+def read_frame(file):
+    first, second = read_exact(file, 2)
+    length = second & 0x7F
+    if length == 126:
+        length = struct.unpack(">H", read_exact(file, 2))[0]
+    elif length == 127:
+        length = struct.unpack(">Q", read_exact(file, 8))[0]
+    return first & 0x0F, read_exact(file, length)
+
+
+def stream_websocket(url, pcm, realtime):
+    parts = urlsplit(url)
+    target = (parts.path or "/") + ("?" + parts.query if parts.query else "")
+    with socket.create_connection((parts.hostname, parts.port or 80)) as sock:
+        key = base64.b64encode(os.urandom(16)).decode()
+        sock.sendall(
+            f"GET {target} HTTP/1.1\r\nHost: {parts.netloc}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n"
+            f"Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n".encode()
+        )
+        file = sock.makefile("rb")
+        status = file.readline().decode().strip()
+        while file.readline().strip():
+            pass
+        if status.split()[1:2] != ["101"]:
+            body = file.read().decode(errors="replace")
+            sys.exit(f"handshake failed: {status} {body}")
+
+        def receive():
+            while True:
+                opcode, payload = read_frame(file)
+                if opcode == 0x1:
+                    print(payload.decode())
+                elif opcode == 0x8:
+                    return
+
+        receiver = threading.Thread(target=receive)
+        receiver.start()
+        for chunk in chunks(pcm, realtime):
+            send_frame(sock, 0x2, chunk)
+        send_frame(sock, 0x1, b"end")
+        receiver.join()
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("audio", help="WAV file to stream")
+    target = parser.add_mutually_exclusive_group(required=True)
+    target.add_argument("--websocket", help="WebSocket URL, e.g. ws://127.0.0.1:8001/")
+    target.add_argument("--tcp", help="TCP address, e.g. 127.0.0.1:8002")
+    parser.add_argument("--realtime", action="store_true", help="send the audio at playback speed")
+    args = parser.parse_args()
+
+    pcm = read_pcm(args.audio)
+    if args.websocket:
+        stream_websocket(args.websocket, pcm, args.realtime)
+    else:
+        stream_tcp(args.tcp, pcm, args.realtime)
+
+
+if __name__ == "__main__":
+    main()
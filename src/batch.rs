@@ -0,0 +1,741 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    sync::{atomic::{AtomicUsize, Ordering}, Mutex},
+    thread,
+    time::Instant,
+};
+
+use ndarray::Array1;
+use ort::Session;
+
+use crate::{
+    benchmark::quote_csv_field,
+    constants::AUDIO_SAMPLE_RATE,
+    inference::{run_inference_on_audio, WindowCounts},
+    model_outputs::{save_model_outputs_npz, ModelOutput},
+    postprocessing::{
+        midi::{drop_overlapping_pitch_bends, generate_midi_file_data, ChannelAllocation, MidiOptions},
+        note_event_frames::NoteDecodingOptions,
+        note_event_times::NoteEventTime,
+        note_list::write_note_events_csv,
+        sonification::{sonify_to_wav, Waveform},
+    },
+    preprocessing::{
+        load_audio::{
+            load_and_convert_audio_from_reader, load_and_convert_audio_range, load_and_convert_raw_audio,
+            load_and_convert_raw_audio_from_reader, RawAudioFormat, TimeRange,
+        },
+        silence::SilenceGate,
+    },
+};
+
+const AUDIO_EXTENSIONS: [&str; 3] = ["wav", "raw", "pcm"];
+const RAW_AUDIO_EXTENSIONS: [&str; 2] = ["raw", "pcm"];
+
+const MANIFEST_CSV_HEADER: [&str; 9] = [
+    "audio", "status", "audio_seconds", "inference_seconds", "decoding_seconds", "windows", "silent_windows", "outputs", "error",
+];
+
+/// The output files to write for every input.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TranscriptionOutputs {
+    /// `<name>_basic_pitch.mid`
+    pub midi: bool,
+    /// `<name>_basic_pitch.wav`, a rendering of the MIDI notes.
+    pub sonification: bool,
+    /// `<name>_basic_pitch.npz`, the raw model outputs.
+    pub model_outputs: bool,
+    /// `<name>_basic_pitch.csv`, the note events.
+    pub note_events: bool,
+}
+
+impl TranscriptionOutputs {
+    /// The paths of the selected output files of an audio file.
+    ///
+    /// # Arguments
+    ///
+    /// * `output_dir` - Directory the outputs are written to.
+    /// * `relative_path` - Path of the audio file relative to its input directory.
+    pub fn paths(&self, output_dir: &Path, relative_path: &Path) -> Vec<PathBuf> {
+        [(self.midi, "mid"), (self.sonification, "wav"), (self.model_outputs, "npz"), (self.note_events, "csv")]
+            .into_iter()
+            .filter(|&(enabled, _)| enabled)
+            .map(|(_, extension)| output_path(output_dir, relative_path, extension))
+            .collect()
+    }
+}
+
+/// Settings for transcribing a file to output files.
+#[derive(Debug, Clone)]
+pub struct TranscriptionOptions {
+    /// Settings for turning the model outputs into notes.
+    pub decoding: NoteDecodingOptions,
+    /// The output files to write.
+    pub outputs: TranscriptionOutputs,
+    /// Tempo of the MIDI file in beats per minute.
+    pub midi_tempo: u32,
+    /// Write the pitch bends of overlapping notes to separate MIDI channels instead of dropping them.
+    pub multiple_pitch_bends: bool,
+    /// Sample rate of the sonification.
+    pub sonification_sample_rate: u32,
+    /// What to do with output files that already exist.
+    pub existing_outputs: ExistingOutputs,
+    /// The format of headerless PCM inputs: stdin and all files but WAV files. If None, stdin is read as WAV.
+    pub raw_audio: Option<RawAudioFormat>,
+    /// The segment of every input to transcribe.
+    pub time_range: TimeRange,
+    /// Give the note times from the start of the file instead of from the start of `time_range`.
+    pub absolute_times: bool,
+    /// Skip the model on the windows this detects as silent.
+    pub silence_gate: Option<SilenceGate>,
+}
+
+/// What to do with output files that already exist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExistingOutputs {
+    /// Fail the file, like Python basic-pitch.
+    Fail,
+    /// Keep the existing outputs and only write the missing ones. A file whose outputs all exist
+    /// is skipped without running the model.
+    Skip,
+    /// Replace the existing outputs.
+    Overwrite,
+}
+
+impl Default for TranscriptionOptions {
+    fn default() -> Self {
+        TranscriptionOptions {
+            decoding: NoteDecodingOptions::default(),
+            outputs: TranscriptionOutputs { midi: true, ..TranscriptionOutputs::default() },
+            midi_tempo: 120,
+            multiple_pitch_bends: false,
+            sonification_sample_rate: 44100,
+            existing_outputs: ExistingOutputs::Fail,
+            raw_audio: None,
+            time_range: TimeRange::default(),
+            absolute_times: false,
+            silence_gate: None,
+        }
+    }
+}
+
+/// An audio file to transcribe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchItem {
+    pub audio_path: PathBuf,
+    /// Path of the audio file relative to the input directory or the fixed part of the glob pattern
+    /// it was found with, the outputs are written with the same layout.
+    pub relative_path: PathBuf,
+}
+
+/// What happened to a file in a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchStatus {
+    Transcribed,
+    /// All outputs already existed.
+    Skipped,
+    Failed(String),
+}
+
+/// Outcome and timing of one file of a batch.
+#[derive(Debug, Clone)]
+pub struct BatchRecord {
+    /// Relative path of the audio file, with '/' separators.
+    pub audio: String,
+    pub status: BatchStatus,
+    pub audio_seconds: f32,
+    pub inference_seconds: f32,
+    pub decoding_seconds: f32,
+    /// The windows of audio the file was split into for the model, and how many were skipped as silent.
+    pub windows: WindowCounts,
+    /// The output files written for this file.
+    pub outputs: Vec<PathBuf>,
+}
+
+/// Find the audio files for a list of inputs.
+///
+/// Every input is a file, a directory that is searched recursively for WAV and PCM (.raw, .pcm) files, a glob
+/// pattern with `*`, `?` and `**` (any number of directories), for shells that don't expand them,
+/// or `-` for stdin, whose outputs are named after `stdin`. Symbolic links to directories are not
+/// followed when searching.
+///
+/// # Arguments
+///
+/// * `inputs` - Files, directories and glob patterns.
+///
+/// # Returns
+///
+/// * The audio files in input order, directories and patterns sorted by path, without duplicates.
+///   An error if two different files would be written to the same outputs, e.g. `a/x.wav` and `b/x.wav`.
+pub fn find_batch_items(inputs: &[PathBuf]) -> Result<Vec<BatchItem>, Box<dyn Error>> {
+    let mut items = vec![];
+    for input in inputs {
+        let mut found = vec![];
+        if is_stdin(input) {
+            items.push(BatchItem { audio_path: input.clone(), relative_path: PathBuf::from("stdin") });
+        } else if input.is_dir() {
+            collect_files(input, &AUDIO_EXTENSIONS, &mut found)?;
+            found.sort();
+            items.extend(found.into_iter().map(|audio_path| BatchItem {
+                relative_path: audio_path.strip_prefix(input).unwrap_or(&audio_path).to_path_buf(),
+                audio_path,
+            }));
+        } else if input.is_file() {
+            items.push(BatchItem {
+                audio_path: input.clone(),
+                relative_path: PathBuf::from(input.file_name().ok_or_else(|| format!("invalid path '{}'", input.display()))?),
+            });
+        } else if is_glob_pattern(input) {
+            let (base, pattern) = split_glob_pattern(input);
+            expand_glob(&base, &pattern, &mut found)?;
+            if found.is_empty() {
+                return Err(format!("no files match '{}'", input.display()).into());
+            }
+            found.sort();
+            items.extend(found.into_iter().map(|audio_path| BatchItem {
+                relative_path: audio_path.strip_prefix(&base).unwrap_or(&audio_path).to_path_buf(),
+                audio_path,
+            }));
+        } else {
+            return Err(format!("input '{}' does not exist", input.display()).into());
+        }
+    }
+
+    let mut seen = HashSet::new();
+    items.retain(|item| seen.insert(item.audio_path.clone()));
+
+    // the outputs are named after the relative path without its extension
+    let mut output_names: HashMap<PathBuf, &Path> = HashMap::new();
+    for item in &items {
+        if let Some(other) = output_names.insert(item.relative_path.with_extension(""), &item.audio_path) {
+            return Err(format!(
+                "'{}' and '{}' would be written to the same output files, transcribe them separately or pass a common parent directory",
+                other.display(),
+                item.audio_path.display()
+            ).into());
+        }
+    }
+    Ok(items)
+}
+
+/// Whether a path is `-`, which stands for stdin or stdout.
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.iter().any(|a| e.eq_ignore_ascii_case(a)))
+}
+
+/// Whether a path has the extension of a WAV or headerless PCM file.
+pub fn is_audio_file(path: &Path) -> bool {
+    has_extension(path, &AUDIO_EXTENSIONS)
+}
+
+/// Collect the files with one of the extensions in a directory, recursively, without following symbolic links to directories.
+///
+/// # Arguments
+///
+/// * `directory` - Directory to search.
+/// * `extensions` - File extensions to collect, compared case-insensitively.
+/// * `paths` - The paths of the files found are appended to this.
+pub fn collect_files(directory: &Path, extensions: &[&str], paths: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if is_real_dir(&entry)? {
+            collect_files(&path, extensions, paths)?;
+        } else if has_extension(&path, extensions) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether a directory entry is a directory and not a symbolic link to one, which could lead back up the tree.
+pub fn is_real_dir(entry: &fs::DirEntry) -> io::Result<bool> {
+    Ok(entry.file_type()?.is_dir())
+}
+
+fn is_glob_pattern(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?'])
+}
+
+/// Split a glob pattern into the directory before the first wildcard and the components after it.
+fn split_glob_pattern(path: &Path) -> (PathBuf, Vec<String>) {
+    let mut base = PathBuf::new();
+    let mut pattern = vec![];
+    for component in path.components() {
+        let part = component.as_os_str().to_string_lossy().to_string();
+        if pattern.is_empty() && !part.contains(['*', '?']) {
+            base.push(component);
+        } else if component != Component::CurDir {
+            pattern.push(part);
+        }
+    }
+    if base.as_os_str().is_empty() {
+        base.push(".");
+    }
+    (base, pattern)
+}
+
+fn expand_glob(directory: &Path, pattern: &[String], paths: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let Some((first, rest)) = pattern.split_first() else {
+        return Ok(());
+    };
+
+    if first == "**" {
+        // zero directories, or one more and still in the `**`
+        expand_glob(directory, rest, paths)?;
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if is_real_dir(&entry)? {
+                expand_glob(&entry.path(), pattern, paths)?;
+            }
+        }
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if !wildcard_match(first, &entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let path = entry.path();
+        if rest.is_empty() {
+            if path.is_file() {
+                paths.push(path);
+            }
+        } else if path.is_dir() {
+            expand_glob(&path, rest, paths)?;
+        }
+    }
+    Ok(())
+}
+
+/// Match a file name against a pattern where `*` matches any characters and `?` one character.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // greedy matching, backtracking to the last `*`
+    let (mut p, mut n) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            last_star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = last_star {
+            p = star_p + 1;
+            n = star_n + 1;
+            last_star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The path of an output file: `<output_dir>/<relative directory>/<audio file stem>_basic_pitch.<extension>`,
+/// named like Python basic-pitch does.
+///
+/// # Arguments
+///
+/// * `output_dir` - Directory the outputs are written to.
+/// * `relative_path` - Path of the audio file relative to its input directory.
+/// * `extension` - Extension of the output file.
+pub fn output_path(output_dir: &Path, relative_path: &Path, extension: &str) -> PathBuf {
+    let stem = relative_path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = format!("{}_basic_pitch.{}", stem, extension);
+    match relative_path.parent() {
+        Some(parent) => output_dir.join(parent).join(file_name),
+        None => output_dir.join(file_name),
+    }
+}
+
+/// The path of an audio file relative to its input directory, with '/' separators.
+pub fn relative_name(relative_path: &Path) -> String {
+    relative_path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// Transcribe an audio file and write the selected output files.
+///
+/// # Arguments
+///
+/// * `item` - The audio file.
+/// * `output_dir` - Directory to write the outputs to, in the layout of the input directory.
+/// * `model` - The model session from `load_model`.
+/// * `options` - Settings for decoding and the output files.
+///
+/// # Returns
+///
+/// * The outcome and timing. Errors are recorded as a failed status.
+pub fn transcribe_item(item: &BatchItem, output_dir: &Path, model: &Session, options: &TranscriptionOptions) -> BatchRecord {
+    let mut record = BatchRecord {
+        audio: relative_name(&item.relative_path),
+        status: BatchStatus::Transcribed,
+        audio_seconds: 0.0,
+        inference_seconds: 0.0,
+        decoding_seconds: 0.0,
+        windows: WindowCounts::default(),
+        outputs: vec![],
+    };
+    if let Err(e) = write_outputs(item, output_dir, model, options, &mut record) {
+        record.status = BatchStatus::Failed(e.to_string());
+    }
+    record
+}
+
+fn write_outputs(
+    item: &BatchItem,
+    output_dir: &Path,
+    model: &Session,
+    options: &TranscriptionOptions,
+    record: &mut BatchRecord,
+) -> Result<(), Box<dyn Error>> {
+    let outputs = &options.outputs;
+    let midi_path = output_path(output_dir, &item.relative_path, "mid");
+    let sonification_path = output_path(output_dir, &item.relative_path, "wav");
+    let model_outputs_path = output_path(output_dir, &item.relative_path, "npz");
+    let note_events_path = output_path(output_dir, &item.relative_path, "csv");
+
+    // the outputs that still need to be written
+    let pending = |enabled: bool, path: &Path| -> Result<bool, Box<dyn Error>> {
+        if !enabled {
+            return Ok(false);
+        }
+        if path.exists() {
+            return match options.existing_outputs {
+                ExistingOutputs::Fail => Err(format!("output file '{}' already exists", path.display()).into()),
+                ExistingOutputs::Skip => Ok(false),
+                ExistingOutputs::Overwrite => Ok(true),
+            };
+        }
+        Ok(true)
+    };
+    let write_midi = pending(outputs.midi, &midi_path)?;
+    let write_sonification = pending(outputs.sonification, &sonification_path)?;
+    let write_model_outputs = pending(outputs.model_outputs, &model_outputs_path)?;
+    let write_note_events = pending(outputs.note_events, &note_events_path)?;
+    if !(write_midi || write_sonification || write_model_outputs || write_note_events) {
+        record.status = BatchStatus::Skipped;
+        return Ok(());
+    }
+
+    let inference_start = Instant::now();
+    let (model_output, windows) = run_inference_on_input(&item.audio_path, model, options)?;
+    record.windows = windows;
+    record.inference_seconds = inference_start.elapsed().as_secs_f32();
+    record.audio_seconds = model_output.duration_seconds();
+
+    let decoding_start = Instant::now();
+    let note_events = decode_note_events(&model_output, options);
+    record.decoding_seconds = decoding_start.elapsed().as_secs_f32();
+
+    if let Some(parent) = midi_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if write_model_outputs {
+        write_atomically(&model_outputs_path, |path| save_model_outputs_npz(path, &model_output))?;
+        record.outputs.push(model_outputs_path);
+    }
+
+    if write_note_events {
+        write_atomically(&note_events_path, |path| {
+            let mut writer = BufWriter::new(File::create(path)?);
+            write_note_events_csv(&note_events, &mut writer)?;
+            writer.flush()?;
+            Ok(())
+        })?;
+        record.outputs.push(note_events_path);
+    }
+
+    let (midi_notes, channel_allocation) = midi_notes_and_channels(note_events, options.multiple_pitch_bends);
+
+    if write_midi {
+        let midi_options = MidiOptions { channel_allocation, ..MidiOptions::default() };
+        write_atomically(&midi_path, |path| Ok(fs::write(path, generate_midi_file_data(&midi_notes, options.midi_tempo, &midi_options))?))?;
+        record.outputs.push(midi_path);
+    }
+
+    if write_sonification {
+        write_atomically(&sonification_path, |path| sonify_to_wav(path, &midi_notes, options.sonification_sample_rate, Waveform::Sine, None))?;
+        record.outputs.push(sonification_path);
+    }
+
+    Ok(())
+}
+
+/// Write an output file to a temporary file next to it and rename it into place, so an interrupted
+/// batch never leaves a truncated output behind that `ExistingOutputs::Skip` would keep.
+fn write_atomically<F: FnOnce(&Path) -> Result<(), Box<dyn Error>>>(path: &Path, write: F) -> Result<(), Box<dyn Error>> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    if let Err(e) = write(&temporary_path) {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(e);
+    }
+    fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+/// Run a loaded model on an audio file, or on stdin if the path is `-`.
+///
+/// # Arguments
+///
+/// * `audio_path` - Path of the WAV or PCM file, or `-`.
+/// * `model` - The model session from `load_model`.
+/// * `options` - The format of headerless PCM inputs, the time range and the silence gate.
+///
+/// # Returns
+///
+/// * The model output and the number of windows that were skipped as silent.
+pub fn run_inference_on_input(
+    audio_path: &Path,
+    model: &Session,
+    options: &TranscriptionOptions,
+) -> Result<(ModelOutput, WindowCounts), Box<dyn Error>> {
+    let (audio, original_length) = load_input(audio_path, options.raw_audio.as_ref(), &options.time_range)?;
+    run_inference_on_audio(&audio, original_length, options.silence_gate.as_ref(), model)
+}
+
+/// Load an input at the model sample rate. Files are only read from the start of the time range,
+/// stdin is read and dropped up to it.
+fn load_input(audio_path: &Path, raw_audio: Option<&RawAudioFormat>, range: &TimeRange) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+    let sample_rate = AUDIO_SAMPLE_RATE as u32;
+    if is_stdin(audio_path) {
+        let stdin = io::stdin().lock();
+        return match raw_audio {
+            Some(format) => load_and_convert_raw_audio_from_reader(stdin, format, range, sample_rate),
+            None => load_and_convert_audio_from_reader(stdin, range, sample_rate),
+        };
+    }
+    match raw_audio {
+        Some(format) if !has_extension(audio_path, &["wav"]) => load_and_convert_raw_audio(audio_path, format, range, sample_rate),
+        None if has_extension(audio_path, &RAW_AUDIO_EXTENSIONS) => {
+            Err("the file is headerless PCM, declare its format with --pcm-format, --pcm-sample-rate and --pcm-channels".into())
+        }
+        _ => load_and_convert_audio_range(audio_path, range, sample_rate),
+    }
+}
+
+/// Decode the notes of a model output, with the note times from the start of the file if
+/// `absolute_times` is set.
+///
+/// # Arguments
+///
+/// * `model_output` - The model output of the input, from `run_inference_on_input`.
+/// * `options` - The decoding settings and time range of the transcription.
+pub fn decode_note_events(model_output: &ModelOutput, options: &TranscriptionOptions) -> Vec<NoteEventTime> {
+    let mut note_events = model_output.decode(&options.decoding);
+    if options.absolute_times {
+        for note in &mut note_events {
+            note.start_time_seconds += options.time_range.start;
+        }
+    }
+    note_events
+}
+
+/// The notes and channel allocation for a MIDI file, as Python basic-pitch writes it.
+///
+/// # Arguments
+///
+/// * `note_events` - List of time-based note events.
+/// * `multiple_pitch_bends` - Write overlapping notes to separate channels so each keeps its pitch bends.
+///   Otherwise all notes are on one channel, where the pitch bends of overlapping notes are dropped
+///   since they would affect each other.
+pub fn midi_notes_and_channels(mut note_events: Vec<NoteEventTime>, multiple_pitch_bends: bool) -> (Vec<NoteEventTime>, ChannelAllocation) {
+    if multiple_pitch_bends {
+        (note_events, ChannelAllocation::MultiChannel { member_channels: 15 })
+    } else {
+        drop_overlapping_pitch_bends(&mut note_events);
+        (note_events, ChannelAllocation::Single)
+    }
+}
+
+/// Transcribe audio files concurrently with one shared model.
+///
+/// # Arguments
+///
+/// * `items` - The audio files, e.g. from `find_batch_items`.
+/// * `output_dir` - Directory to write the outputs to, in the layout of the inputs.
+/// * `model` - The model session from `load_model`, shared by all workers.
+/// * `options` - Settings for decoding and the output files.
+/// * `jobs` - Number of files to transcribe at the same time.
+/// * `on_done` - Called with the index and record of every file as soon as it is done, from the worker threads.
+///
+/// # Returns
+///
+/// * The records in the order of the items.
+pub fn run_batch<F: Fn(usize, &BatchRecord) + Sync>(
+    items: &[BatchItem],
+    output_dir: &Path,
+    model: &Session,
+    options: &TranscriptionOptions,
+    jobs: usize,
+    on_done: F,
+) -> Vec<BatchRecord> {
+    let next_item = AtomicUsize::new(0);
+    let records: Mutex<Vec<Option<BatchRecord>>> = Mutex::new(vec![None; items.len()]);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let item_idx = next_item.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(item_idx) else {
+                    break;
+                };
+                let record = transcribe_item(item, output_dir, model, options);
+                on_done(item_idx, &record);
+                records.lock().unwrap()[item_idx] = Some(record);
+            });
+        }
+    });
+
+    records.into_inner().unwrap().into_iter().flatten().collect()
+}
+
+/// The default number of concurrent files: the model already runs on 4 threads per file.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| (n.get() / 4).max(1))
+}
+
+/// Write the records of a batch as CSV.
+///
+/// # Arguments
+///
+/// * `records` - Records of the files of a batch.
+/// * `writer` - Where to write the CSV to.
+pub fn write_batch_manifest_csv<W: Write>(records: &[BatchRecord], mut writer: W) -> Result<(), Box<dyn Error>> {
+    writeln!(writer, "{}", MANIFEST_CSV_HEADER.join(","))?;
+    for record in records {
+        let (status, error) = match &record.status {
+            BatchStatus::Transcribed => ("transcribed", ""),
+            BatchStatus::Skipped => ("skipped", ""),
+            BatchStatus::Failed(error) => ("failed", error.as_str()),
+        };
+        let outputs = record.outputs.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(";");
+        let row = [
+            quote_csv_field(&record.audio),
+            status.to_string(),
+            record.audio_seconds.to_string(),
+            record.inference_seconds.to_string(),
+            record.decoding_seconds.to_string(),
+            record.windows.total.to_string(),
+            record.windows.silent.to_string(),
+            quote_csv_field(&outputs),
+            quote_csv_field(error),
+        ];
+        writeln!(writer, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::{Path, PathBuf}};
+
+    use super::{find_batch_items, output_path, split_glob_pattern, wildcard_match, write_atomically};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("basic-pitch-batch-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn wildcards_match_file_names() {
+        assert!(wildcard_match("*.wav", "take 1.wav"));
+        assert!(wildcard_match("take?.wav", "take1.wav"));
+        assert!(wildcard_match("*a*b", "xxaxxab"));
+        assert!(!wildcard_match("*.wav", "take.mid"));
+        assert!(!wildcard_match("take?.wav", "take.wav"));
+    }
+
+    #[test]
+    fn glob_patterns_split_at_the_first_wildcard() {
+        let (base, pattern) = split_glob_pattern(Path::new("bounces/2024/**/*.wav"));
+        assert_eq!(base, PathBuf::from("bounces/2024"));
+        assert_eq!(pattern, vec!["**", "*.wav"]);
+        assert_eq!(split_glob_pattern(Path::new("*.wav")).0, PathBuf::from("."));
+    }
+
+    #[test]
+    fn outputs_mirror_the_input_layout() {
+        assert_eq!(output_path(Path::new("out"), Path::new("a/b/take.wav"), "mid"), PathBuf::from("out/a/b/take_basic_pitch.mid"));
+        assert_eq!(output_path(Path::new("out"), Path::new("take.wav"), "csv"), PathBuf::from("out/take_basic_pitch.csv"));
+    }
+
+    #[test]
+    fn inputs_with_the_same_outputs_are_rejected() {
+        let dir = test_dir("duplicates");
+        for path in ["a/x.wav", "b/x.wav", "b/x.raw", "b/y.wav"] {
+            fs::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+            fs::write(dir.join(path), b"").unwrap();
+        }
+
+        let both = find_batch_items(&[dir.join("a/x.wav"), dir.join("b/x.wav")]);
+        let extensions = find_batch_items(&[dir.join("b")]);
+        let distinct = find_batch_items(&[dir.join("a/x.wav"), dir.join("b/y.wav"), dir.join("a/x.wav")]);
+        fs::remove_file(dir.join("b/x.raw")).unwrap();
+        // the outputs mirror the layout below the directory, so a/x.wav and b/x.wav are distinct
+        let mirrored = find_batch_items(std::slice::from_ref(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(both.is_err());
+        assert!(extensions.is_err());
+        assert_eq!(distinct.unwrap().len(), 2);
+        assert_eq!(mirrored.unwrap().len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn globs_do_not_follow_symlinked_directories() {
+        let dir = test_dir("symlinks");
+        fs::create_dir_all(dir.join("takes/day 1")).unwrap();
+        fs::write(dir.join("takes/day 1/a.wav"), b"").unwrap();
+        // a link back up the tree would be searched forever
+        std::os::unix::fs::symlink(dir.join("takes"), dir.join("takes/day 1/loop")).unwrap();
+
+        let items = find_batch_items(&[dir.join("takes/**/*.wav")]);
+        let dir_items = find_batch_items(&[dir.join("takes")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(items.unwrap().len(), 1);
+        assert_eq!(dir_items.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn outputs_are_renamed_into_place() {
+        let dir = test_dir("atomic");
+        let path = dir.join("take_basic_pitch.mid");
+        write_atomically(&path, |temporary_path| {
+            assert_ne!(temporary_path, path);
+            Ok(fs::write(temporary_path, b"notes")?)
+        }).unwrap();
+        let failed = write_atomically(&dir.join("failed_basic_pitch.mid"), |temporary_path| {
+            fs::write(temporary_path, b"half")?;
+            Err("interrupted".into())
+        });
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        let written = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(failed.is_err());
+        assert_eq!(files, vec!["take_basic_pitch.mid"]);
+        assert_eq!(written, b"notes");
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    error::Error,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use ort::Session;
+
+use crate::{
+    batch::collect_files,
+    evaluation::{evaluate_transcription, load_reference_notes, FrameMetrics, NoteMatchingOptions, NoteMetrics, TranscriptionMetrics},
+    inference::run_inference_with_model,
+    model_outputs::{run_inference_cached, ModelOutputCache},
+    postprocessing::note_event_frames::NoteDecodingOptions,
+};
+
+const REFERENCE_EXTENSIONS: [&str; 4] = ["mid", "midi", "csv", "json"];
+
+const RECORD_CSV_HEADER: [&str; 20] = [
+    "audio", "reference", "audio_seconds", "inference_seconds", "decoding_seconds",
+    "onset_precision", "onset_recall", "onset_f_measure", "onset_n_matched",
+    "onset_offset_precision", "onset_offset_recall", "onset_offset_f_measure", "onset_offset_n_matched",
+    "n_reference", "n_estimated",
+    "frame_precision", "frame_recall", "frame_f_measure", "frame_accuracy", "real_time_factor",
+];
+
+/// An audio file of a dataset and its reference notes.
+#[derive(Debug, Clone)]
+pub struct BenchmarkItem {
+    pub audio_path: PathBuf,
+    /// Path of the audio file relative to the dataset directory, used to identify the item.
+    pub name: String,
+    /// Reference MIDI file or note list, `None` if there is none.
+    pub reference_path: Option<PathBuf>,
+}
+
+/// Metrics and timing of one transcribed file.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BenchmarkRecord {
+    pub audio: String,
+    pub reference: String,
+    pub audio_seconds: f32,
+    pub inference_seconds: f32,
+    pub decoding_seconds: f32,
+    pub metrics: TranscriptionMetrics,
+}
+
+/// Metrics averaged over files and total timing of a benchmark run.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BenchmarkSummary {
+    pub n_files: usize,
+    pub audio_seconds: f32,
+    pub inference_seconds: f32,
+    pub decoding_seconds: f32,
+    /// Processing time divided by audio duration.
+    pub real_time_factor: f32,
+    /// Precision, recall and F-measure are averaged over files, the note counts are summed.
+    pub metrics: TranscriptionMetrics,
+}
+
+/// Find the WAV files in a dataset directory and their reference notes.
+///
+/// The reference of `<dir>/a/b.wav` is `a/b` with a .mid, .midi, .csv or .json extension, next to the
+/// audio (MAESTRO-style) or in a separate reference directory with the same layout.
+///
+/// # Arguments
+///
+/// * `audio_dir` - Directory to search for WAV files, recursively.
+/// * `reference_dir` - Directory with the reference notes, the audio directory if `None`.
+///
+/// # Returns
+///
+/// * The items sorted by name.
+pub fn find_benchmark_items<P: AsRef<Path>>(audio_dir: P, reference_dir: Option<&Path>) -> Result<Vec<BenchmarkItem>, Box<dyn Error>> {
+    let audio_dir = audio_dir.as_ref();
+    let reference_dir = reference_dir.unwrap_or(audio_dir);
+
+    let mut audio_paths = vec![];
+    collect_files(audio_dir, &["wav"], &mut audio_paths)?;
+    audio_paths.sort();
+
+    audio_paths.into_iter().map(|audio_path| {
+        let relative_path = audio_path.strip_prefix(audio_dir)?.to_path_buf();
+        let name = relative_path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        let reference_path = REFERENCE_EXTENSIONS
+            .iter()
+            .map(|extension| reference_dir.join(&relative_path).with_extension(extension))
+            .find(|path| path.is_file());
+        Ok(BenchmarkItem { audio_path, name, reference_path })
+    }).collect()
+}
+
+/// Transcribe one file and score it against its reference.
+///
+/// # Arguments
+///
+/// * `item` - The audio file and its reference.
+/// * `model` - The model session from `load_model`, loaded once for all files.
+/// * `decoding_options` - Settings for turning the model outputs into notes.
+/// * `matching_options` - Tolerances for matching the notes to the reference.
+/// * `cache` - Cache for the model outputs, if any. Cached files report the lookup time as inference time.
+///
+/// # Returns
+///
+/// * The metrics and timing of the file.
+pub fn benchmark_item(
+    item: &BenchmarkItem,
+    model: &Session,
+    decoding_options: &NoteDecodingOptions,
+    matching_options: &NoteMatchingOptions,
+    cache: Option<&ModelOutputCache>,
+) -> Result<BenchmarkRecord, Box<dyn Error>> {
+    let reference_path = item.reference_path.as_ref().ok_or_else(|| format!("no reference for '{}'", item.name))?;
+    let reference = load_reference_notes(reference_path)?;
+
+    let audio_path = item.audio_path.to_str().ok_or_else(|| format!("invalid path '{}'", item.audio_path.display()))?;
+    let inference_start = Instant::now();
+    let model_output = match cache {
+        Some(cache) => run_inference_cached(audio_path, model, cache)?,
+        None => run_inference_with_model(audio_path, model)?,
+    };
+    let inference_seconds = inference_start.elapsed().as_secs_f32();
+
+    let decoding_start = Instant::now();
+    let estimated = model_output.decode(decoding_options);
+    let decoding_seconds = decoding_start.elapsed().as_secs_f32();
+
+    let metrics = evaluate_transcription(
+        &reference,
+        &estimated,
+        Some((&model_output.frames, decoding_options.frame_thresh)),
+        matching_options,
+    );
+
+    Ok(BenchmarkRecord {
+        audio: item.name.clone(),
+        reference: reference_path.display().to_string(),
+        audio_seconds: model_output.duration_seconds(),
+        inference_seconds,
+        decoding_seconds,
+        metrics,
+    })
+}
+
+/// Average the metrics of a benchmark run over its files and sum the timing.
+///
+/// # Arguments
+///
+/// * `records` - Records of the scored files.
+///
+/// # Returns
+///
+/// * The summary of the run.
+pub fn summarize_benchmark(records: &[BenchmarkRecord]) -> BenchmarkSummary {
+    let n_files = records.len();
+    if n_files == 0 {
+        return BenchmarkSummary::default();
+    }
+
+    let audio_seconds: f32 = records.iter().map(|r| r.audio_seconds).sum();
+    let inference_seconds: f32 = records.iter().map(|r| r.inference_seconds).sum();
+    let decoding_seconds: f32 = records.iter().map(|r| r.decoding_seconds).sum();
+
+    let frame_records: Vec<&FrameMetrics> = records.iter().filter_map(|r| r.metrics.frame.as_ref()).collect();
+    let frame = if frame_records.is_empty() {
+        None
+    } else {
+        let mean = |value: fn(&FrameMetrics) -> f32| frame_records.iter().map(|m| value(m)).sum::<f32>() / frame_records.len() as f32;
+        Some(FrameMetrics {
+            precision: mean(|m| m.precision),
+            recall: mean(|m| m.recall),
+            f_measure: mean(|m| m.f_measure),
+            accuracy: mean(|m| m.accuracy),
+        })
+    };
+
+    BenchmarkSummary {
+        n_files,
+        audio_seconds,
+        inference_seconds,
+        decoding_seconds,
+        real_time_factor: if audio_seconds > 0.0 { (inference_seconds + decoding_seconds) / audio_seconds } else { 0.0 },
+        metrics: TranscriptionMetrics {
+            note_onset: mean_note_metrics(records.iter().map(|r| &r.metrics.note_onset)),
+            note_onset_offset: mean_note_metrics(records.iter().map(|r| &r.metrics.note_onset_offset)),
+            frame,
+        },
+    }
+}
+
+fn mean_note_metrics<'a, I: Iterator<Item = &'a NoteMetrics>>(metrics: I) -> NoteMetrics {
+    let mut total = NoteMetrics::default();
+    let mut n = 0;
+    for m in metrics {
+        total.precision += m.precision;
+        total.recall += m.recall;
+        total.f_measure += m.f_measure;
+        total.n_reference += m.n_reference;
+        total.n_estimated += m.n_estimated;
+        total.n_matched += m.n_matched;
+        n += 1;
+    }
+    if n > 0 {
+        total.precision /= n as f32;
+        total.recall /= n as f32;
+        total.f_measure /= n as f32;
+    }
+    total
+}
+
+/// Write the header of a per-file CSV.
+pub fn write_benchmark_csv_header<W: Write>(mut writer: W) -> Result<(), Box<dyn Error>> {
+    writeln!(writer, "{}", RECORD_CSV_HEADER.join(","))?;
+    Ok(())
+}
+
+/// Write a record as a row of a per-file CSV.
+///
+/// # Arguments
+///
+/// * `record` - Record of a scored file.
+/// * `writer` - Where to write the row to.
+pub fn write_benchmark_csv_record<W: Write>(record: &BenchmarkRecord, mut writer: W) -> Result<(), Box<dyn Error>> {
+    let metrics = &record.metrics;
+    let frame = metrics.frame.as_ref();
+    let optional = |value: Option<f32>| value.map_or(String::new(), |v| v.to_string());
+    let processing_seconds = record.inference_seconds + record.decoding_seconds;
+
+    let row = [
+        quote_csv_field(&record.audio),
+        quote_csv_field(&record.reference),
+        record.audio_seconds.to_string(),
+        record.inference_seconds.to_string(),
+        record.decoding_seconds.to_string(),
+        metrics.note_onset.precision.to_string(),
+        metrics.note_onset.recall.to_string(),
+        metrics.note_onset.f_measure.to_string(),
+        metrics.note_onset.n_matched.to_string(),
+        metrics.note_onset_offset.precision.to_string(),
+        metrics.note_onset_offset.recall.to_string(),
+        metrics.note_onset_offset.f_measure.to_string(),
+        metrics.note_onset_offset.n_matched.to_string(),
+        metrics.note_onset.n_reference.to_string(),
+        metrics.note_onset.n_estimated.to_string(),
+        optional(frame.map(|f| f.precision)),
+        optional(frame.map(|f| f.recall)),
+        optional(frame.map(|f| f.f_measure)),
+        optional(frame.map(|f| f.accuracy)),
+        if record.audio_seconds > 0.0 { (processing_seconds / record.audio_seconds).to_string() } else { String::new() },
+    ];
+    writeln!(writer, "{}", row.join(","))?;
+    Ok(())
+}
+
+/// Read the records of a per-file CSV as written by `write_benchmark_csv_record`.
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the CSV from.
+///
+/// # Returns
+///
+/// * The records, in file order.
+pub fn read_benchmark_csv<R: Read>(reader: R) -> Result<Vec<BenchmarkRecord>, Box<dyn Error>> {
+    let mut records = vec![];
+
+    for (line_idx, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || (line_idx == 0 && line.starts_with(RECORD_CSV_HEADER[0])) {
+            continue;
+        }
+
+        let columns = split_csv_line(&line);
+        if columns.len() != RECORD_CSV_HEADER.len() {
+            return Err(format!("line {}: expected {} columns, found {}", line_idx + 1, RECORD_CSV_HEADER.len(), columns.len()).into());
+        }
+        let number = |idx: usize| -> Result<f32, Box<dyn Error>> {
+            columns[idx].parse::<f32>().map_err(|e| format!("line {}: invalid {} '{}': {}", line_idx + 1, RECORD_CSV_HEADER[idx], columns[idx], e).into())
+        };
+        let count = |idx: usize| -> Result<usize, Box<dyn Error>> {
+            columns[idx].parse::<usize>().map_err(|e| format!("line {}: invalid {} '{}': {}", line_idx + 1, RECORD_CSV_HEADER[idx], columns[idx], e).into())
+        };
+
+        let (n_reference, n_estimated) = (count(13)?, count(14)?);
+        let frame = if columns[15].is_empty() {
+            None
+        } else {
+            Some(FrameMetrics { precision: number(15)?, recall: number(16)?, f_measure: number(17)?, accuracy: number(18)? })
+        };
+
+        records.push(BenchmarkRecord {
+            audio: columns[0].clone(),
+            reference: columns[1].clone(),
+            audio_seconds: number(2)?,
+            inference_seconds: number(3)?,
+            decoding_seconds: number(4)?,
+            metrics: TranscriptionMetrics {
+                note_onset: NoteMetrics { precision: number(5)?, recall: number(6)?, f_measure: number(7)?, n_reference, n_estimated, n_matched: count(8)? },
+                note_onset_offset: NoteMetrics { precision: number(9)?, recall: number(10)?, f_measure: number(11)?, n_reference, n_estimated, n_matched: count(12)? },
+                frame,
+            },
+        });
+    }
+
+    Ok(records)
+}
+
+/// Write a summary as a two-column CSV of metric names and values.
+///
+/// # Arguments
+///
+/// * `summary` - Summary of a benchmark run.
+/// * `writer` - Where to write the CSV to.
+pub fn write_benchmark_summary_csv<W: Write>(summary: &BenchmarkSummary, mut writer: W) -> Result<(), Box<dyn Error>> {
+    let metrics = &summary.metrics;
+    let mut rows: Vec<(&str, String)> = vec![
+        ("n_files", summary.n_files.to_string()),
+        ("audio_seconds", summary.audio_seconds.to_string()),
+        ("inference_seconds", summary.inference_seconds.to_string()),
+        ("decoding_seconds", summary.decoding_seconds.to_string()),
+        ("real_time_factor", summary.real_time_factor.to_string()),
+        ("onset_precision", metrics.note_onset.precision.to_string()),
+        ("onset_recall", metrics.note_onset.recall.to_string()),
+        ("onset_f_measure", metrics.note_onset.f_measure.to_string()),
+        ("onset_n_matched", metrics.note_onset.n_matched.to_string()),
+        ("onset_offset_precision", metrics.note_onset_offset.precision.to_string()),
+        ("onset_offset_recall", metrics.note_onset_offset.recall.to_string()),
+        ("onset_offset_f_measure", metrics.note_onset_offset.f_measure.to_string()),
+        ("onset_offset_n_matched", metrics.note_onset_offset.n_matched.to_string()),
+        ("n_reference", metrics.note_onset.n_reference.to_string()),
+        ("n_estimated", metrics.note_onset.n_estimated.to_string()),
+    ];
+    if let Some(frame) = &metrics.frame {
+        rows.extend([
+            ("frame_precision", frame.precision.to_string()),
+            ("frame_recall", frame.recall.to_string()),
+            ("frame_f_measure", frame.f_measure.to_string()),
+            ("frame_accuracy", frame.accuracy.to_string()),
+        ]);
+    }
+
+    writeln!(writer, "metric,value")?;
+    for (name, value) in rows {
+        writeln!(writer, "{},{}", name, value)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn quote_csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut columns = vec![String::new()];
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                columns.last_mut().unwrap().push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => columns.push(String::new()),
+            _ => columns.last_mut().unwrap().push(c),
+        }
+    }
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluation::{FrameMetrics, NoteMetrics, TranscriptionMetrics};
+
+    use super::{find_benchmark_items, read_benchmark_csv, write_benchmark_csv_header, write_benchmark_csv_record, BenchmarkRecord};
+
+    fn note_metrics(n_matched: usize) -> NoteMetrics {
+        NoteMetrics { precision: n_matched as f32 / 8.0, recall: n_matched as f32 / 10.0, f_measure: n_matched as f32 / 9.0, n_reference: 10, n_estimated: 8, n_matched }
+    }
+
+    fn records() -> Vec<BenchmarkRecord> {
+        vec![
+            BenchmarkRecord {
+                audio: "piano/take \"1\", left.wav".to_string(),
+                reference: "piano/take 1.mid".to_string(),
+                audio_seconds: 12.5,
+                inference_seconds: 0.75,
+                decoding_seconds: 0.125,
+                metrics: TranscriptionMetrics {
+                    note_onset: note_metrics(7),
+                    note_onset_offset: note_metrics(3),
+                    frame: Some(FrameMetrics { precision: 0.9, recall: 0.8, f_measure: 0.85, accuracy: 0.7 }),
+                },
+            },
+            BenchmarkRecord {
+                audio: "empty.wav".to_string(),
+                reference: "empty.csv".to_string(),
+                audio_seconds: 0.0,
+                inference_seconds: 0.0,
+                decoding_seconds: 0.0,
+                metrics: TranscriptionMetrics { note_onset: note_metrics(0), note_onset_offset: note_metrics(0), frame: None },
+            },
+        ]
+    }
+
+    #[test]
+    fn csv_records_round_trip() {
+        let mut csv = vec![];
+        write_benchmark_csv_header(&mut csv).unwrap();
+        for record in records() {
+            write_benchmark_csv_record(&record, &mut csv).unwrap();
+        }
+
+        let read = read_benchmark_csv(csv.as_slice()).unwrap();
+        assert_eq!(read.len(), 2);
+        for (read, written) in read.iter().zip(records()) {
+            assert_eq!(read.audio, written.audio);
+            assert_eq!(read.reference, written.reference);
+            assert_eq!(
+                (read.audio_seconds, read.inference_seconds, read.decoding_seconds),
+                (written.audio_seconds, written.inference_seconds, written.decoding_seconds)
+            );
+            for (read, written) in [
+                (&read.metrics.note_onset, &written.metrics.note_onset),
+                (&read.metrics.note_onset_offset, &written.metrics.note_onset_offset),
+            ] {
+                assert_eq!((read.precision, read.recall, read.f_measure), (written.precision, written.recall, written.f_measure));
+                assert_eq!((read.n_reference, read.n_estimated, read.n_matched), (written.n_reference, written.n_estimated, written.n_matched));
+            }
+            let frame = |metrics: &TranscriptionMetrics| metrics.frame.as_ref().map(|f| (f.precision, f.recall, f.f_measure, f.accuracy));
+            assert_eq!(frame(&read.metrics), frame(&written.metrics));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn items_are_found_without_following_symlinked_directories() {
+        let dir = std::env::temp_dir().join(format!("basic-pitch-benchmark-items-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("2004")).unwrap();
+        std::fs::write(dir.join("2004/take.WAV"), b"").unwrap();
+        std::fs::write(dir.join("2004/take.midi"), b"").unwrap();
+        std::fs::write(dir.join("2004/take.raw"), b"").unwrap();
+        // a link back up the tree would be searched forever
+        std::os::unix::fs::symlink(&dir, dir.join("2004/loop")).unwrap();
+
+        let items = find_benchmark_items(&dir, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let items = items.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, "2004/take.WAV");
+        assert!(items[0].reference_path.as_ref().is_some_and(|path| path.ends_with("2004/take.midi")));
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use clap::Args;
+
+use crate::{
+    benchmark::{
+        benchmark_item, find_benchmark_items, read_benchmark_csv, summarize_benchmark, write_benchmark_csv_header,
+        write_benchmark_csv_record, write_benchmark_summary_csv,
+    },
+    constants::MODEL_PATH,
+    inference::load_model,
+    model_outputs::ModelOutputCache,
+};
+
+use super::options::{DecodingArgs, MatchingArgs};
+
+/// Transcribe a dataset and score every file against its reference.
+#[derive(Debug, Args)]
+pub struct BenchmarkArgs {
+    /// Directory with WAV files, searched recursively.
+    pub audio_dir: PathBuf,
+    /// Directory to write results.csv and summary.csv to (and their JSON versions with the `serde` feature).
+    /// Files already in results.csv are skipped, so an interrupted run can be resumed.
+    pub output_dir: PathBuf,
+    /// Directory with the reference notes, in the same layout as the audio directory.
+    /// By default the references are looked up next to the audio files.
+    #[arg(long)]
+    pub reference_dir: Option<PathBuf>,
+    /// Path of the ONNX model.
+    #[arg(long, default_value = MODEL_PATH)]
+    pub model_path: PathBuf,
+    /// Directory to cache the model outputs in, so that only decoding is repeated on later runs.
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// Discard the results of earlier runs and score all files again.
+    #[arg(long)]
+    pub rescore: bool,
+    #[command(flatten)]
+    pub decoding: DecodingArgs,
+    #[command(flatten)]
+    pub matching: MatchingArgs,
+}
+
+/// Run the `benchmark` command.
+pub fn run(args: &BenchmarkArgs) -> Result<(), Box<dyn Error>> {
+    let decoding_options = args.decoding.decoding_options()?;
+    let matching_options = args.matching.matching_options();
+    // loaded once, so the inference time of every file is only the time spent running the model
+    let model_path = args.model_path.to_str().ok_or_else(|| format!("invalid path '{}'", args.model_path.display()))?;
+    let model = load_model(model_path).map_err(|e| format!("could not load model '{}': {}", args.model_path.display(), e))?;
+    let cache = args.cache_dir.as_ref().map(|dir| ModelOutputCache::new(dir, &args.model_path)).transpose()?;
+
+    fs::create_dir_all(&args.output_dir)?;
+    let results_path = args.output_dir.join("results.csv");
+    let options_path = args.output_dir.join("options.txt");
+
+    // scores from runs with other settings can't be mixed with new ones
+    let options_description = format!("{}\n{:#?}\n{:#?}\n", args.model_path.display(), decoding_options, matching_options);
+    if !args.rescore && results_path.exists() && fs::read_to_string(&options_path).ok().as_deref() != Some(options_description.as_str()) {
+        return Err(format!(
+            "'{}' was scored with different settings, use --rescore or another output directory",
+            results_path.display()
+        ).into());
+    }
+
+    let mut records = if !args.rescore && results_path.exists() {
+        read_benchmark_csv(File::open(&results_path)?)?
+    } else {
+        let mut results_file = File::create(&results_path)?;
+        write_benchmark_csv_header(&mut results_file)?;
+        fs::write(&options_path, &options_description)?;
+        vec![]
+    };
+    let scored: HashSet<String> = records.iter().map(|record| record.audio.clone()).collect();
+
+    let items = find_benchmark_items(&args.audio_dir, args.reference_dir.as_deref())?;
+    let pending: Vec<_> = items.iter().filter(|item| !scored.contains(&item.name)).collect();
+    println!("{} files found, {} already scored, {} to go", items.len(), items.len() - pending.len(), pending.len());
+
+    let mut results_file = OpenOptions::new().append(true).open(&results_path)?;
+    let mut n_failed = 0;
+    for (item_idx, item) in pending.iter().enumerate() {
+        if item.reference_path.is_none() {
+            eprintln!("[{}/{}] {}: skipped, no reference", item_idx + 1, pending.len(), item.name);
+            continue;
+        }
+
+        match benchmark_item(item, &model, &decoding_options, &matching_options, cache.as_ref()) {
+            Ok(record) => {
+                println!(
+                    "[{}/{}] {}: onset F {:.3}, onset+offset F {:.3}, {:.2}s for {:.2}s of audio",
+                    item_idx + 1,
+                    pending.len(),
+                    item.name,
+                    record.metrics.note_onset.f_measure,
+                    record.metrics.note_onset_offset.f_measure,
+                    record.inference_seconds + record.decoding_seconds,
+                    record.audio_seconds
+                );
+                // written right away so an interrupted run keeps everything scored so far
+                write_benchmark_csv_record(&record, &mut results_file)?;
+                results_file.flush()?;
+                records.push(record);
+            }
+            Err(e) => {
+                eprintln!("[{}/{}] {}: failed: {}", item_idx + 1, pending.len(), item.name, e);
+                n_failed += 1;
+            }
+        }
+    }
+
+    let summary = summarize_benchmark(&records);
+    write_benchmark_summary_csv(&summary, File::create(args.output_dir.join("summary.csv"))?)?;
+    #[cfg(feature = "serde")]
+    {
+        serde_json::to_writer_pretty(File::create(args.output_dir.join("results.json"))?, &records)?;
+        serde_json::to_writer_pretty(File::create(args.output_dir.join("summary.json"))?, &summary)?;
+    }
+
+    let metrics = &summary.metrics;
+    println!(
+        "{} files: onset F {:.3} (P {:.3}, R {:.3}), onset+offset F {:.3}, real-time factor {:.3}",
+        summary.n_files,
+        metrics.note_onset.f_measure,
+        metrics.note_onset.precision,
+        metrics.note_onset.recall,
+        metrics.note_onset_offset.f_measure,
+        summary.real_time_factor
+    );
+    if let Some(frame) = &metrics.frame {
+        println!("frame F {:.3}, accuracy {:.3}", frame.f_measure, frame.accuracy);
+    }
+
+    if n_failed > 0 {
+        return Err(format!("{} files failed", n_failed).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::{error::Error, path::{Path, PathBuf}};
+
+use clap::Args;
+
+use crate::{
+    evaluation::{evaluate_transcription, load_reference_notes, NoteMetrics, TranscriptionMetrics},
+    inference::run_inference,
+    model_outputs::{load_model_outputs_npz, ModelOutput},
+};
+
+use super::options::{DecodingArgs, MatchingArgs};
+
+/// Compare a transcription with reference notes.
+#[derive(Debug, Args)]
+pub struct EvaluateArgs {
+    /// Reference notes (.mid, .midi, .csv or .json).
+    pub reference: PathBuf,
+    /// Estimated notes (.mid, .midi, .csv or .json), saved model outputs (.npz) or an audio file to transcribe.
+    /// Model outputs and audio are decoded with the decoding settings and also get frame-level metrics.
+    pub estimated: PathBuf,
+    #[command(flatten)]
+    pub decoding: DecodingArgs,
+    #[command(flatten)]
+    pub matching: MatchingArgs,
+    /// Print the metrics as JSON.
+    #[cfg(feature = "serde")]
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Run the `evaluate` command.
+pub fn run(args: &EvaluateArgs) -> Result<(), Box<dyn Error>> {
+    let options = args.matching.matching_options();
+
+    let reference = load_reference_notes(&args.reference)?;
+    let metrics = match load_model_output(&args.estimated)? {
+        Some(model_output) => {
+            let decoding_options = args.decoding.decoding_options()?;
+            let estimated = model_output.decode(&decoding_options);
+            evaluate_transcription(&reference, &estimated, Some((&model_output.frames, decoding_options.frame_thresh)), &options)
+        }
+        None => {
+            let estimated = load_reference_notes(&args.estimated)?;
+            evaluate_transcription(&reference, &estimated, None, &options)
+        }
+    };
+
+    #[cfg(feature = "serde")]
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&metrics)?);
+        return Ok(());
+    }
+
+    print_metrics(&metrics);
+    Ok(())
+}
+
+/// Load or compute the model outputs if the path isn't a note file.
+fn load_model_output(path: &Path) -> Result<Option<ModelOutput>, Box<dyn Error>> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "mid" | "midi" | "csv" | "json" => Ok(None),
+        "npz" => Ok(Some(load_model_outputs_npz(path)?)),
+        _ => {
+            let audio_path = path.to_str().ok_or_else(|| format!("invalid path '{}'", path.display()))?;
+            Ok(Some(run_inference(audio_path)?))
+        }
+    }
+}
+
+fn print_metrics(metrics: &TranscriptionMetrics) {
+    println!("{:<20} {:>9} {:>9} {:>9}", "", "precision", "recall", "f-measure");
+    print_note_metrics("note onset", &metrics.note_onset);
+    print_note_metrics("note onset+offset", &metrics.note_onset_offset);
+    if let Some(frame) = &metrics.frame {
+        println!(
+            "{:<20} {:>9.3} {:>9.3} {:>9.3}   accuracy {:.3}",
+            "frame", frame.precision, frame.recall, frame.f_measure, frame.accuracy
+        );
+    }
+}
+
+fn print_note_metrics(name: &str, metrics: &NoteMetrics) {
+    println!(
+        "{:<20} {:>9.3} {:>9.3} {:>9.3}   {} matched, {} reference, {} estimated",
+        name, metrics.precision, metrics.recall, metrics.f_measure, metrics.n_matched, metrics.n_reference, metrics.n_estimated
+    );
+}
@@ -0,0 +1,198 @@
+use std::{error::Error, path::PathBuf};
+
+use clap::Args;
+use ort::Session;
+
+use crate::{
+    batch::{ExistingOutputs, TranscriptionOptions, TranscriptionOutputs},
+    constants::MODEL_PATH,
+    evaluation::NoteMatchingOptions,
+    inference,
+    postprocessing::note_event_frames::{minimum_note_length_frames, NoteDecodingOptions},
+    preprocessing::{
+        load_audio::{PcmFormat, RawAudioFormat, TimeRange},
+        silence::SilenceGate,
+    },
+};
+
+/// Settings for turning model outputs into notes, with the same names and defaults as Python basic-pitch.
+#[derive(Debug, Clone, Args)]
+pub struct DecodingArgs {
+    /// Minimum amplitude of an onset activation to be considered an onset.
+    #[arg(long, default_value_t = 0.5)]
+    pub onset_threshold: f32,
+    /// Minimum amplitude of a frame activation for a note to remain "on".
+    #[arg(long, default_value_t = 0.3)]
+    pub frame_threshold: f32,
+    /// Minimum note length in milliseconds.
+    #[arg(long, default_value_t = 127.70)]
+    pub minimum_note_length: f32,
+    /// Minimum allowed output frequency in Hz.
+    #[arg(long)]
+    pub minimum_frequency: Option<f32>,
+    /// Maximum allowed output frequency in Hz.
+    #[arg(long)]
+    pub maximum_frequency: Option<f32>,
+    /// Don't use the melodia trick for finding additional notes.
+    #[arg(long)]
+    pub no_melodia: bool,
+    /// Track a single pitch, for monophonic sources like voice. The frame threshold is the voicing threshold.
+    #[arg(long)]
+    pub monophonic: bool,
+    /// JSON file with decoding options, e.g. written by the `tune` command. Replaces the options above.
+    #[cfg(feature = "serde")]
+    #[arg(long)]
+    pub decoding_preset: Option<std::path::PathBuf>,
+}
+
+impl DecodingArgs {
+    /// The note decoding options for these arguments.
+    pub fn decoding_options(&self) -> Result<NoteDecodingOptions, Box<dyn Error>> {
+        #[cfg(feature = "serde")]
+        if let Some(path) = &self.decoding_preset {
+            let file = std::fs::File::open(path)?;
+            return Ok(serde_json::from_reader(std::io::BufReader::new(file))?);
+        }
+
+        Ok(NoteDecodingOptions {
+            onset_thresh: self.onset_threshold,
+            frame_thresh: self.frame_threshold,
+            min_note_len: minimum_note_length_frames(self.minimum_note_length),
+            min_freq: self.minimum_frequency,
+            max_freq: self.maximum_frequency,
+            melodia_trick: !self.no_melodia,
+            monophonic: self.monophonic,
+            ..NoteDecodingOptions::default()
+        })
+    }
+}
+
+/// The model, output files and decoding settings for transcribing files, with the same names and
+/// defaults as Python basic-pitch.
+#[derive(Debug, Clone, Args)]
+pub struct TranscriptionArgs {
+    /// Path of the ONNX model.
+    #[arg(long, default_value = MODEL_PATH)]
+    pub model_path: PathBuf,
+    /// Save a MIDI file of the transcription. The default if no other output is selected.
+    #[arg(long)]
+    pub save_midi: bool,
+    /// Save a WAV rendering of the MIDI file.
+    #[arg(long)]
+    pub sonify_midi: bool,
+    /// Save the raw model outputs as an NPZ file.
+    #[arg(long)]
+    pub save_model_outputs: bool,
+    /// Save the predicted note events as a CSV file.
+    #[arg(long)]
+    pub save_note_events: bool,
+    #[command(flatten)]
+    pub decoding: DecodingArgs,
+    /// Write the pitch bends of overlapping notes to separate MIDI channels instead of dropping them.
+    #[arg(long)]
+    pub multiple_pitch_bends: bool,
+    /// Tempo of the MIDI file in beats per minute.
+    #[arg(long, default_value_t = 120)]
+    pub midi_tempo: u32,
+    /// Sample rate of the sonified MIDI file.
+    #[arg(long, default_value_t = 44100)]
+    pub sonification_samplerate: u32,
+    /// Read stdin (`-`) and the inputs that aren't WAV files as headerless PCM in this sample format:
+    /// s16le, s24le, s32le, f32le or f64le.
+    #[arg(long)]
+    pub pcm_format: Option<PcmFormat>,
+    /// Sample rate of the PCM audio.
+    #[arg(long, default_value_t = 22050)]
+    pub pcm_sample_rate: u32,
+    /// Number of interleaved channels of the PCM audio, they are mixed down to mono.
+    #[arg(long, default_value_t = 1)]
+    pub pcm_channels: u16,
+    /// Only transcribe the audio from this many seconds from the start.
+    #[arg(long, default_value_t = 0.0)]
+    pub start: f32,
+    /// Only transcribe the audio up to this many seconds from the start.
+    #[arg(long)]
+    pub end: Option<f32>,
+    /// Give the note times from the start of the file instead of from `--start`.
+    #[arg(long)]
+    pub absolute_times: bool,
+    /// Don't run the model on windows of audio that are silent or below the noise floor, they get no notes.
+    #[arg(long)]
+    pub skip_silence: bool,
+    /// RMS level in dBFS below which a window can be silent.
+    #[arg(long, default_value_t = -60.0, allow_negative_numbers = true)]
+    pub silence_rms_threshold: f32,
+    /// Peak level in dBFS below which a window can be silent. Both levels have to be below their thresholds.
+    #[arg(long, default_value_t = -50.0, allow_negative_numbers = true)]
+    pub silence_peak_threshold: f32,
+}
+
+impl TranscriptionArgs {
+    /// The transcription options for these arguments.
+    ///
+    /// # Arguments
+    ///
+    /// * `existing_outputs` - What to do with output files that already exist.
+    pub fn transcription_options(&self, existing_outputs: ExistingOutputs) -> Result<TranscriptionOptions, Box<dyn Error>> {
+        Ok(TranscriptionOptions {
+            decoding: self.decoding.decoding_options()?,
+            outputs: TranscriptionOutputs {
+                midi: self.save_midi || !(self.sonify_midi || self.save_model_outputs || self.save_note_events),
+                sonification: self.sonify_midi,
+                model_outputs: self.save_model_outputs,
+                note_events: self.save_note_events,
+            },
+            midi_tempo: self.midi_tempo,
+            multiple_pitch_bends: self.multiple_pitch_bends,
+            sonification_sample_rate: self.sonification_samplerate,
+            existing_outputs,
+            raw_audio: self.pcm_format.map(|pcm_format| RawAudioFormat {
+                pcm_format,
+                sample_rate: self.pcm_sample_rate,
+                channels: self.pcm_channels,
+            }),
+            time_range: TimeRange::new(self.start, self.end)?,
+            absolute_times: self.absolute_times,
+            silence_gate: self.skip_silence.then_some(SilenceGate {
+                rms_threshold_db: self.silence_rms_threshold,
+                peak_threshold_db: self.silence_peak_threshold,
+            }),
+        })
+    }
+
+    /// Load the model, with the path in the error message if it fails.
+    pub fn load_model(&self) -> Result<Session, Box<dyn Error>> {
+        let model_path = self.model_path.to_str().ok_or_else(|| format!("invalid path '{}'", self.model_path.display()))?;
+        inference::load_model(model_path)
+            .map_err(|e| format!("could not load model '{}': {}", self.model_path.display(), e).into())
+    }
+}
+
+/// Tolerances for matching estimated notes to reference notes.
+#[derive(Debug, Clone, Args)]
+pub struct MatchingArgs {
+    /// Maximum difference between onsets in seconds.
+    #[arg(long, default_value_t = 0.05)]
+    pub onset_tolerance: f32,
+    /// Maximum difference between pitches in cents.
+    #[arg(long, default_value_t = 50.0)]
+    pub pitch_tolerance: f32,
+    /// Maximum difference between offsets as a ratio of the reference note duration.
+    #[arg(long, default_value_t = 0.2)]
+    pub offset_ratio: f32,
+    /// Minimum offset tolerance in seconds.
+    #[arg(long, default_value_t = 0.05)]
+    pub offset_min_tolerance: f32,
+}
+
+impl MatchingArgs {
+    /// The note matching options for these arguments.
+    pub fn matching_options(&self) -> NoteMatchingOptions {
+        NoteMatchingOptions {
+            onset_tolerance: self.onset_tolerance,
+            pitch_tolerance_cents: self.pitch_tolerance,
+            offset_ratio: self.offset_ratio,
+            offset_min_tolerance: self.offset_min_tolerance,
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::{error::Error, path::PathBuf};
+
+use clap::Args;
+
+use crate::{
+    evaluation::load_reference_notes,
+    inference::{load_model, run_inference_with_model},
+    model_outputs::load_model_outputs_npz,
+    osc::OscSender,
+    streaming::note_events_to_stream_events,
+};
+
+use super::options::DecodingArgs;
+
+/// Play back notes as Open Sound Control messages over UDP.
+#[derive(Debug, Args)]
+pub struct OscArgs {
+    /// Notes (.mid, .midi, .csv or .json), saved model outputs (.npz) or an audio file to transcribe.
+    pub input: PathBuf,
+    /// Address to send the messages to.
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    pub target: String,
+    /// Prefix of the OSC addresses, e.g. /basic-pitch.
+    #[arg(long, default_value = "")]
+    pub prefix: String,
+    /// Playback speed, 2.0 plays twice as fast.
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f32,
+    /// Path of the ONNX model, for transcribing audio.
+    #[arg(long, default_value = crate::constants::MODEL_PATH)]
+    pub model_path: PathBuf,
+    #[command(flatten)]
+    pub decoding: DecodingArgs,
+}
+
+/// Run the `osc` command.
+pub fn run(args: &OscArgs) -> Result<(), Box<dyn Error>> {
+    if args.speed.is_nan() || args.speed <= 0.0 {
+        return Err("the speed must be positive".into());
+    }
+
+    let extension = args.input.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    let notes = match extension.as_str() {
+        "mid" | "midi" | "csv" | "json" => load_reference_notes(&args.input)?,
+        "npz" => load_model_outputs_npz(&args.input)?.decode(&args.decoding.decoding_options()?),
+        _ => {
+            let audio_path = args.input.to_str().ok_or_else(|| format!("invalid path '{}'", args.input.display()))?;
+            let model_path = args.model_path.to_str().ok_or_else(|| format!("invalid path '{}'", args.model_path.display()))?;
+            let model = load_model(model_path).map_err(|e| format!("could not load model '{}': {}", args.model_path.display(), e))?;
+            run_inference_with_model(audio_path, &model)?.decode(&args.decoding.decoding_options()?)
+        }
+    };
+
+    let sender = OscSender::new(&args.target, &args.prefix).map_err(|e| format!("could not send to {}: {}", args.target, e))?;
+    let events = note_events_to_stream_events(&notes);
+    println!("Playing {} notes to {}", notes.len(), args.target);
+    sender.replay(&events, args.speed)?;
+    Ok(())
+}
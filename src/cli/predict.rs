@@ -0,0 +1,164 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use clap::{Args, ValueEnum};
+
+use crate::{
+    batch::{
+        decode_note_events, default_jobs, find_batch_items, is_stdin, midi_notes_and_channels, run_batch, run_inference_on_input,
+        write_batch_manifest_csv, BatchRecord, BatchStatus, ExistingOutputs,
+    },
+    postprocessing::{
+        midi::{generate_midi_file_data, MidiOptions},
+        note_list::write_note_events_csv,
+    },
+};
+
+use super::options::TranscriptionArgs;
+
+/// Transcribe audio files, with the same arguments and output files as Python basic-pitch.
+#[derive(Debug, Args)]
+pub struct PredictArgs {
+    /// Directory to save the outputs to, or `-` to write one output to stdout.
+    #[arg(required = true)]
+    pub output_dir: Option<PathBuf>,
+    /// Audio files, directories to search for WAV files, or glob patterns like 'takes/**/*.wav'.
+    /// The outputs of files found in a directory or with a pattern mirror its layout. `-` reads stdin.
+    #[arg(required = true)]
+    pub audio_paths: Vec<PathBuf>,
+    #[command(flatten)]
+    pub transcription: TranscriptionArgs,
+    /// Number of files to transcribe at the same time. Defaults to a quarter of the CPU threads.
+    #[arg(long, short)]
+    pub jobs: Option<usize>,
+    /// Skip outputs that already exist instead of failing, so an interrupted batch can be resumed.
+    #[arg(long)]
+    pub skip_existing: bool,
+    /// Write a CSV with the status, timing and outputs of every file.
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+    /// What to write when the output directory is `-`.
+    #[arg(long, value_enum, default_value_t = StdoutFormat::Midi)]
+    pub stdout_format: StdoutFormat,
+}
+
+/// The output written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum StdoutFormat {
+    /// A MIDI file.
+    Midi,
+    /// The note events as CSV.
+    Csv,
+    /// The note events as JSON.
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+/// Run the root command: transcribe every input file into the output directory.
+///
+/// Continues with the next file when a file fails, and returns an error at the end if any did.
+pub fn run(args: &PredictArgs) -> Result<(), Box<dyn Error>> {
+    let output_dir = args.output_dir.as_deref().ok_or("missing output directory")?;
+    if is_stdin(output_dir) {
+        return write_to_stdout(args);
+    }
+    if !output_dir.is_dir() {
+        return Err(format!("output directory '{}' does not exist", output_dir.display()).into());
+    }
+    let existing_outputs = if args.skip_existing { ExistingOutputs::Skip } else { ExistingOutputs::Fail };
+    let options = args.transcription.transcription_options(existing_outputs)?;
+    let items = find_batch_items(&args.audio_paths)?;
+    let model = args.transcription.load_model()?;
+
+    let jobs = args.jobs.unwrap_or_else(default_jobs);
+    let n_done = AtomicUsize::new(0);
+    let records = run_batch(&items, output_dir, &model, &options, jobs, |_, record| {
+        let n_done = n_done.fetch_add(1, Ordering::Relaxed) + 1;
+        match &record.status {
+            BatchStatus::Transcribed => println!(
+                "[{}/{}] {}: {:.2}s for {:.2}s of audio{}",
+                n_done,
+                items.len(),
+                record.audio,
+                record.inference_seconds + record.decoding_seconds,
+                record.audio_seconds,
+                silent_windows_note(record)
+            ),
+            BatchStatus::Skipped => println!("[{}/{}] {}: skipped, outputs exist", n_done, items.len(), record.audio),
+            BatchStatus::Failed(e) => eprintln!("[{}/{}] {}: failed: {}", n_done, items.len(), record.audio, e),
+        }
+    });
+
+    if let Some(manifest_path) = &args.manifest {
+        let mut writer = BufWriter::new(File::create(manifest_path)?);
+        write_batch_manifest_csv(&records, &mut writer)?;
+        writer.flush()?;
+    }
+
+    let count = |status: fn(&BatchStatus) -> bool| records.iter().filter(|r| status(&r.status)).count();
+    let n_failed = count(|s| matches!(s, BatchStatus::Failed(_)));
+    let audio_seconds: f32 = records.iter().map(|r| r.audio_seconds).sum();
+    let processing_seconds: f32 = records.iter().map(|r| r.inference_seconds + r.decoding_seconds).sum();
+    println!(
+        "{} transcribed, {} skipped, {} failed, {:.2}s of audio in {:.2}s of processing",
+        count(|s| *s == BatchStatus::Transcribed),
+        count(|s| *s == BatchStatus::Skipped),
+        n_failed,
+        audio_seconds,
+        processing_seconds
+    );
+    if options.silence_gate.is_some() {
+        let n_windows: usize = records.iter().map(|r| r.windows.total).sum();
+        let n_silent: usize = records.iter().map(|r| r.windows.silent).sum();
+        println!("skipped the model on {} of {} windows as silent", n_silent, n_windows);
+    }
+
+    if n_failed > 0 {
+        return Err(format!("{} of {} files failed", n_failed, records.len()).into());
+    }
+    Ok(())
+}
+
+/// `, n of m windows silent` if windows were skipped as silent.
+pub fn silent_windows_note(record: &BatchRecord) -> String {
+    if record.windows.silent == 0 {
+        return String::new();
+    }
+    format!(", {} of {} windows silent", record.windows.silent, record.windows.total)
+}
+
+/// Transcribe a single input and write the output selected with `--stdout-format` to stdout, for pipelines.
+fn write_to_stdout(args: &PredictArgs) -> Result<(), Box<dyn Error>> {
+    let transcription = &args.transcription;
+    if transcription.save_midi || transcription.sonify_midi || transcription.save_model_outputs || transcription.save_note_events {
+        return Err("with '-' as output directory, select the output with --stdout-format".into());
+    }
+    let items = find_batch_items(&args.audio_paths)?;
+    let [item] = items.as_slice() else {
+        return Err(format!("only one input can be written to stdout, got {}", items.len()).into());
+    };
+
+    let options = transcription.transcription_options(ExistingOutputs::Overwrite)?;
+    let model = transcription.load_model()?;
+    let (model_output, _) = run_inference_on_input(&item.audio_path, &model, &options)?;
+    let notes = decode_note_events(&model_output, &options);
+
+    let mut stdout = io::stdout().lock();
+    match args.stdout_format {
+        StdoutFormat::Midi => {
+            let (notes, channel_allocation) = midi_notes_and_channels(notes, options.multiple_pitch_bends);
+            let midi_options = MidiOptions { channel_allocation, ..MidiOptions::default() };
+            stdout.write_all(&generate_midi_file_data(&notes, options.midi_tempo, &midi_options))?;
+        }
+        StdoutFormat::Csv => write_note_events_csv(&notes, &mut stdout)?,
+        #[cfg(feature = "serde")]
+        StdoutFormat::Json => crate::postprocessing::note_list::write_note_events_json(&notes, &mut stdout)?,
+    }
+    stdout.flush()?;
+    Ok(())
+}
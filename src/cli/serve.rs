@@ -0,0 +1,57 @@
+use std::{error::Error, net::TcpListener, path::PathBuf, time::Duration};
+
+use clap::Args;
+
+use crate::{
+    batch::default_jobs,
+    model_outputs::ModelOutputCache,
+    server::{serve, ServerOptions},
+};
+
+use super::options::DecodingArgs;
+
+/// Serve transcription over a local HTTP API.
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8000")]
+    pub address: String,
+    /// Path of the ONNX model.
+    #[arg(long, default_value = crate::constants::MODEL_PATH)]
+    pub model_path: PathBuf,
+    /// Maximum size of an uploaded audio file in megabytes.
+    #[arg(long, default_value_t = 64)]
+    pub max_request_size: usize,
+    /// Maximum number of transcriptions at the same time, including their uploads. Requests beyond that get a 503 response.
+    /// Defaults to a quarter of the CPU threads.
+    #[arg(long)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Seconds to wait for a client to send data.
+    #[arg(long, default_value_t = 30.0)]
+    pub read_timeout: f32,
+    /// Directory to cache the model outputs of uploaded audio in.
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// Decoding settings for requests that don't set them in the query.
+    #[command(flatten)]
+    pub decoding: DecodingArgs,
+}
+
+/// Run the `serve` command.
+pub fn run(args: &ServeArgs) -> Result<(), Box<dyn Error>> {
+    let model_path = args.model_path.to_str().ok_or_else(|| format!("invalid path '{}'", args.model_path.display()))?;
+    let model = crate::inference::load_model(model_path)
+        .map_err(|e| format!("could not load model '{}': {}", args.model_path.display(), e))?;
+
+    let options = ServerOptions {
+        max_request_bytes: args.max_request_size * 1024 * 1024,
+        max_concurrent_requests: args.max_concurrent_requests.unwrap_or_else(default_jobs),
+        decoding: args.decoding.decoding_options()?,
+        cache: args.cache_dir.as_ref().map(|dir| ModelOutputCache::new(dir, &args.model_path)).transpose()?,
+        read_timeout: Duration::from_secs_f32(args.read_timeout),
+    };
+
+    let listener = TcpListener::bind(&args.address).map_err(|e| format!("could not listen on {}: {}", args.address, e))?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    serve(listener, &model, &options)
+}
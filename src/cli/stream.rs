@@ -0,0 +1,79 @@
+use std::{error::Error, net::TcpListener, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::Args;
+
+use crate::{
+    osc::OscSender,
+    preprocessing::load_audio::PcmFormat,
+    stream_server::{serve_streams, StreamServerOptions},
+    streaming::StreamingOptions,
+};
+
+use super::options::DecodingArgs;
+
+/// Serve live transcription of PCM audio streamed over WebSocket or plain TCP.
+#[derive(Debug, Args)]
+pub struct StreamArgs {
+    /// Address to accept WebSocket connections on.
+    #[arg(long, required_unless_present = "tcp")]
+    pub websocket: Option<String>,
+    /// Address to accept plain TCP connections on.
+    #[arg(long)]
+    pub tcp: Option<String>,
+    /// Sample format of the mono 22050 Hz audio: s16le, s24le, s32le, f32le or f64le. WebSocket clients can set it in
+    /// the query.
+    #[arg(long, default_value = "s16le")]
+    pub pcm_format: PcmFormat,
+    /// Path of the ONNX model.
+    #[arg(long, default_value = crate::constants::MODEL_PATH)]
+    pub model_path: PathBuf,
+    /// Maximum number of streams at the same time.
+    #[arg(long, default_value_t = 4)]
+    pub max_connections: usize,
+    /// Seconds to wait for a client to send data.
+    #[arg(long, default_value_t = 30.0)]
+    pub read_timeout: f32,
+    /// Seconds of model output that are decoded again for every window. Notes held longer are ended.
+    #[arg(long, default_value_t = 30.0)]
+    pub max_context: f32,
+    /// Also send the events of all streams as Open Sound Control messages to this address, e.g. 127.0.0.1:9000.
+    #[arg(long)]
+    pub osc: Option<String>,
+    /// Prefix of the OSC addresses, e.g. /basic-pitch.
+    #[arg(long, default_value = "")]
+    pub osc_prefix: String,
+    /// Decoding settings for streams that don't set them in the query.
+    #[command(flatten)]
+    pub decoding: DecodingArgs,
+}
+
+/// Run the `stream` command.
+pub fn run(args: &StreamArgs) -> Result<(), Box<dyn Error>> {
+    let model_path = args.model_path.to_str().ok_or_else(|| format!("invalid path '{}'", args.model_path.display()))?;
+    let model = crate::inference::load_model(model_path)
+        .map_err(|e| format!("could not load model '{}': {}", args.model_path.display(), e))?;
+
+    let options = StreamServerOptions {
+        streaming: StreamingOptions { decoding: args.decoding.decoding_options()?, max_context_seconds: args.max_context },
+        pcm_format: args.pcm_format,
+        max_connections: args.max_connections,
+        read_timeout: Duration::from_secs_f32(args.read_timeout),
+        osc: args
+            .osc
+            .as_ref()
+            .map(|target| OscSender::new(target, &args.osc_prefix).map(Arc::new))
+            .transpose()
+            .map_err(|e| format!("could not send OSC messages: {}", e))?,
+    };
+
+    let bind = |address: &String| TcpListener::bind(address).map_err(|e| format!("could not listen on {}: {}", address, e));
+    let websocket = args.websocket.as_ref().map(bind).transpose()?;
+    let tcp = args.tcp.as_ref().map(bind).transpose()?;
+    if let Some(listener) = &websocket {
+        println!("Listening for WebSocket streams on ws://{}", listener.local_addr()?);
+    }
+    if let Some(listener) = &tcp {
+        println!("Listening for TCP streams on {}", listener.local_addr()?);
+    }
+    serve_streams(websocket, tcp, &model, &options)
+}
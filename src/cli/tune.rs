@@ -0,0 +1,119 @@
+use std::{error::Error, path::PathBuf};
+
+use clap::Args;
+
+use crate::{
+    benchmark::find_benchmark_items,
+    constants::MODEL_PATH,
+    evaluation::load_reference_notes,
+    inference::{load_model, run_inference_with_model},
+    model_outputs::{run_inference_cached, ModelOutputCache},
+    postprocessing::note_event_frames::minimum_note_length_frames,
+    tuning::{tune_decoding_options, TuningMetric, TuningSpace, TuningStrategy},
+};
+
+use super::options::{DecodingArgs, MatchingArgs};
+
+/// Search for the decoding options with the best note F-measure on a dataset.
+#[derive(Debug, Args)]
+pub struct TuneArgs {
+    /// Directory with WAV files, searched recursively, with reference notes next to them.
+    pub audio_dir: PathBuf,
+    /// Directory with the reference notes, in the same layout as the audio directory.
+    #[arg(long)]
+    pub reference_dir: Option<PathBuf>,
+    /// Path of the ONNX model.
+    #[arg(long, default_value = MODEL_PATH)]
+    pub model_path: PathBuf,
+    /// Directory to cache the model outputs in. Strongly recommended, so the model only runs once per file.
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// Onset thresholds to try, comma separated.
+    #[arg(long, value_delimiter = ',')]
+    pub onset_thresholds: Option<Vec<f32>>,
+    /// Frame thresholds to try, comma separated. NaN stands for the adaptive mean + std threshold.
+    #[arg(long, value_delimiter = ',')]
+    pub frame_thresholds: Option<Vec<f32>>,
+    /// Minimum note lengths in milliseconds to try, comma separated.
+    #[arg(long, value_delimiter = ',')]
+    pub minimum_note_lengths: Option<Vec<f32>>,
+    /// Energy tolerances in frames to try, comma separated.
+    #[arg(long, value_delimiter = ',')]
+    pub energy_tolerances: Option<Vec<usize>>,
+    /// Try every combination instead of optimizing one parameter at a time.
+    #[arg(long)]
+    pub grid: bool,
+    /// Maximum number of rounds over all parameters for the coordinate search.
+    #[arg(long, default_value_t = 5)]
+    pub max_rounds: usize,
+    /// Maximize the F-measure of notes matched on onset and offset instead of onset only.
+    #[arg(long)]
+    pub match_offsets: bool,
+    /// Write the best options as JSON, to be used with --decoding-preset.
+    #[cfg(feature = "serde")]
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+    /// Options to start from. Parameters that aren't tuned are kept.
+    #[command(flatten)]
+    pub decoding: DecodingArgs,
+    #[command(flatten)]
+    pub matching: MatchingArgs,
+}
+
+/// Run the `tune` command.
+pub fn run(args: &TuneArgs) -> Result<(), Box<dyn Error>> {
+    let base_options = args.decoding.decoding_options()?;
+    let model_path = args.model_path.to_str().ok_or_else(|| format!("invalid path '{}'", args.model_path.display()))?;
+    let model = load_model(model_path).map_err(|e| format!("could not load model '{}': {}", args.model_path.display(), e))?;
+    let cache = args.cache_dir.as_ref().map(|dir| ModelOutputCache::new(dir, &args.model_path)).transpose()?;
+
+    let default_space = TuningSpace::default();
+    let space = TuningSpace {
+        onset_thresh: args.onset_thresholds.clone().unwrap_or(default_space.onset_thresh),
+        frame_thresh: args.frame_thresholds.clone().unwrap_or(default_space.frame_thresh),
+        min_note_len: args
+            .minimum_note_lengths
+            .as_ref()
+            .map(|lengths| lengths.iter().map(|&ms| minimum_note_length_frames(ms)).collect())
+            .unwrap_or(default_space.min_note_len),
+        energy_tolerance: args.energy_tolerances.clone().unwrap_or(default_space.energy_tolerance),
+    };
+
+    let items = find_benchmark_items(&args.audio_dir, args.reference_dir.as_deref())?;
+    let mut dataset = vec![];
+    for (item_idx, item) in items.iter().enumerate() {
+        let Some(reference_path) = &item.reference_path else {
+            eprintln!("[{}/{}] {}: skipped, no reference", item_idx + 1, items.len(), item.name);
+            continue;
+        };
+        let audio_path = item.audio_path.to_str().ok_or_else(|| format!("invalid path '{}'", item.audio_path.display()))?;
+        let model_output = match &cache {
+            Some(cache) => run_inference_cached(audio_path, &model, cache)?,
+            None => run_inference_with_model(audio_path, &model)?,
+        };
+        dataset.push((model_output, load_reference_notes(reference_path)?));
+        println!("[{}/{}] {}: loaded", item_idx + 1, items.len(), item.name);
+    }
+    if dataset.is_empty() {
+        return Err(format!("no audio files with reference notes in '{}'", args.audio_dir.display()).into());
+    }
+
+    let metric = if args.match_offsets { TuningMetric::NoteOnsetOffset } else { TuningMetric::NoteOnset };
+    let strategy = if args.grid { TuningStrategy::Grid } else { TuningStrategy::CoordinateSearch { max_rounds: args.max_rounds } };
+    let result = tune_decoding_options(&dataset, &base_options, &space, metric, strategy, &args.matching.matching_options());
+
+    println!("{} combinations evaluated on {} files", result.n_evaluations, dataset.len());
+    println!("F-measure {:.4} (base options {:.4})", result.score, result.base_score);
+    println!("onset_thresh: {}", result.options.onset_thresh);
+    println!("frame_thresh: {}", result.options.frame_thresh);
+    println!("min_note_len: {} frames", result.options.min_note_len);
+    println!("energy_tolerance: {} frames", result.options.energy_tolerance);
+
+    #[cfg(feature = "serde")]
+    if let Some(output) = &args.output {
+        serde_json::to_writer_pretty(std::fs::File::create(output)?, &result.options)?;
+        println!("written to {}", output.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use clap::Args;
+
+use crate::{
+    batch::{BatchStatus, ExistingOutputs},
+    watch::{watch_folder, WatchOptions},
+};
+
+use super::{options::TranscriptionArgs, predict::silent_windows_note};
+
+/// Watch a folder and transcribe audio files as they appear.
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// Directory to watch for audio files, recursively.
+    pub input_dir: PathBuf,
+    /// Directory to write the outputs to, in the layout of the input directory.
+    pub output_dir: PathBuf,
+    /// Directory failed files are moved to, with an error report. Defaults to `quarantine` in the output directory.
+    #[arg(long)]
+    pub quarantine_dir: Option<PathBuf>,
+    /// Seconds between scans of the input directory.
+    #[arg(long, default_value_t = 1.0)]
+    pub poll_interval: f32,
+    /// Seconds a file must be left unmodified before it is considered fully written.
+    #[arg(long, default_value_t = 2.0)]
+    pub settle_time: f32,
+    /// Transcribe the files that are there now and exit.
+    #[arg(long)]
+    pub once: bool,
+    #[command(flatten)]
+    pub transcription: TranscriptionArgs,
+}
+
+/// Run the `watch` command.
+pub fn run(args: &WatchArgs) -> Result<(), Box<dyn Error>> {
+    if !args.input_dir.is_dir() {
+        return Err(format!("input directory '{}' does not exist", args.input_dir.display()).into());
+    }
+    let options = args.transcription.transcription_options(ExistingOutputs::Overwrite)?;
+    let watch_options = WatchOptions {
+        poll_interval: Duration::from_secs_f32(args.poll_interval),
+        settle_time: Duration::from_secs_f32(args.settle_time),
+        quarantine_dir: args.quarantine_dir.clone().unwrap_or_else(|| args.output_dir.join("quarantine")),
+        once: args.once,
+    };
+    std::fs::create_dir_all(&args.output_dir)?;
+    let model = args.transcription.load_model()?;
+
+    println!("Watching {} for audio files", args.input_dir.display());
+    watch_folder(&args.input_dir, &args.output_dir, &model, &options, &watch_options, |record, report_path| {
+        match &record.status {
+            BatchStatus::Failed(e) => match report_path {
+                Some(report_path) => eprintln!("{}: failed: {}, moved to quarantine, see {}", record.audio, e, report_path.display()),
+                None => eprintln!("{}: failed: {}", record.audio, e),
+            },
+            _ => println!(
+                "{}: {:.2}s for {:.2}s of audio{}",
+                record.audio,
+                record.inference_seconds + record.decoding_seconds,
+                record.audio_seconds,
+                silent_windows_note(record)
+            ),
+        }
+    })
+}
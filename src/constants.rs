@@ -4,14 +4,17 @@ pub const FFT_HOP: usize = 256;
 pub const ANNOTATIONS_FPS: usize = AUDIO_SAMPLE_RATE / FFT_HOP; // Should Math.floor
 pub const AUDIO_WINDOW_LENGTH: usize = 2;
 pub const AUDIO_N_SAMPLES: usize = AUDIO_SAMPLE_RATE * AUDIO_WINDOW_LENGTH - FFT_HOP;
+pub const N_OVERLAPPING_FRAMES: usize = 30;
+pub const OVERLAP_LEN: usize = N_OVERLAPPING_FRAMES * FFT_HOP;
+pub const HOP_SIZE: usize = AUDIO_N_SAMPLES - OVERLAP_LEN;
 pub const MODEL_PATH: &str = "./model/icassp_2022_nmp.onnx";
 
 // MIDI Conversion
 pub const MIDI_OFFSET: usize = 21;
 pub const ANNOT_N_FRAMES: usize = ANNOTATIONS_FPS * AUDIO_WINDOW_LENGTH;
+pub const N_FRAMES_PER_WINDOW: usize = ANNOT_N_FRAMES - N_OVERLAPPING_FRAMES; // frames kept per window after unwrapping
 pub const WINDOW_OFFSET: f32 =
-    ((FFT_HOP / AUDIO_SAMPLE_RATE) * (ANNOT_N_FRAMES - AUDIO_N_SAMPLES / FFT_HOP)) as f32 +
-    0.0018; //  this is a magic number, but it's needed for this to align properly
+    (N_FRAMES_PER_WINDOW * FFT_HOP - HOP_SIZE) as f32 / AUDIO_SAMPLE_RATE as f32; // the kept frames span more time than the hop between windows
 pub const MAX_FREQ_IDX: usize = 87;
 pub const CONTOURS_BINS_PER_SEMITONE: f32 = 3.0;
 pub const ANNOTATIONS_BASE_FREQUENCY: f32 = 27.5; // lowest key on a piano
@@ -0,0 +1,346 @@
+use std::{error::Error, fs::{self, File}, path::Path};
+
+use ndarray::Array2;
+
+use crate::{
+    constants::MIDI_OFFSET,
+    postprocessing::{
+        helpers::ported::librosa::model_frame_to_time,
+        midi::read_midi_file_data,
+        note_event_times::NoteEventTime,
+        note_list::read_note_events_csv,
+    },
+};
+
+/// Tolerances for matching estimated notes to reference notes, following mir_eval's transcription metrics.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NoteMatchingOptions {
+    /// Maximum difference between onsets in seconds.
+    pub onset_tolerance: f32,
+    /// Maximum difference between pitches in cents.
+    pub pitch_tolerance_cents: f32,
+    /// Maximum difference between offsets as a ratio of the reference note duration.
+    pub offset_ratio: f32,
+    /// Minimum offset tolerance in seconds, for short reference notes.
+    pub offset_min_tolerance: f32,
+}
+
+impl Default for NoteMatchingOptions {
+    fn default() -> Self {
+        NoteMatchingOptions {
+            onset_tolerance: 0.05,
+            pitch_tolerance_cents: 50.0,
+            offset_ratio: 0.2,
+            offset_min_tolerance: 0.05,
+        }
+    }
+}
+
+/// Precision, recall and F-measure of matched notes.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NoteMetrics {
+    pub precision: f32,
+    pub recall: f32,
+    pub f_measure: f32,
+    pub n_reference: usize,
+    pub n_estimated: usize,
+    pub n_matched: usize,
+}
+
+/// Frame-level precision, recall, F-measure and accuracy of a piano roll.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameMetrics {
+    pub precision: f32,
+    pub recall: f32,
+    pub f_measure: f32,
+    /// True positives / (true positives + false positives + false negatives).
+    pub accuracy: f32,
+}
+
+/// All metrics of a transcription.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TranscriptionMetrics {
+    /// Notes match on onset and pitch.
+    pub note_onset: NoteMetrics,
+    /// Notes match on onset, pitch and offset.
+    pub note_onset_offset: NoteMetrics,
+    /// Frame-level metrics, if the frame activations are available.
+    pub frame: Option<FrameMetrics>,
+}
+
+/// Load reference notes from a MIDI file, a CSV note list or (with the `serde` feature) a JSON note list.
+///
+/// # Arguments
+///
+/// * `path` - Path of the file, the format is picked by its extension.
+///
+/// # Returns
+///
+/// * List of time-based note events.
+pub fn load_reference_notes<P: AsRef<Path>>(path: P) -> Result<Vec<NoteEventTime>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "mid" | "midi" => read_midi_file_data(&fs::read(path)?),
+        "csv" => read_note_events_csv(File::open(path)?),
+        #[cfg(feature = "serde")]
+        "json" => crate::postprocessing::note_list::read_note_events_json(File::open(path)?),
+        _ => Err(format!("unsupported note file '{}', expected .mid, .midi, .csv or .json", path.display()).into()),
+    }
+}
+
+/// Match estimated notes to reference notes, each note is matched at most once.
+///
+/// A pair can be matched when the onsets and pitches are within tolerance and, if `match_offsets`
+/// is set, the offsets as well. The maximum number of pairs is found with bipartite matching.
+///
+/// # Arguments
+///
+/// * `reference` - Reference notes.
+/// * `estimated` - Estimated notes.
+/// * `options` - Matching tolerances.
+/// * `match_offsets` - Whether the offsets have to match.
+///
+/// # Returns
+///
+/// * Pairs of (reference index, estimated index).
+pub fn match_notes(
+    reference: &[NoteEventTime],
+    estimated: &[NoteEventTime],
+    options: &NoteMatchingOptions,
+    match_offsets: bool,
+) -> Vec<(usize, usize)> {
+    // like mir_eval, time differences are rounded to 4 decimals, so times exactly on the tolerance match
+    let time_distance = |a: f32, b: f32| ((a - b).abs() * 1e4).round() / 1e4;
+
+    let candidates: Vec<Vec<usize>> = reference.iter().map(|ref_note| {
+        let ref_offset = ref_note.start_time_seconds + ref_note.duration_seconds;
+        let offset_tolerance = (options.offset_ratio * ref_note.duration_seconds).max(options.offset_min_tolerance);
+
+        estimated.iter().enumerate().filter(|(_, est_note)| {
+            let onset_ok = time_distance(est_note.start_time_seconds, ref_note.start_time_seconds) <= options.onset_tolerance;
+            // the pitches are whole MIDI notes, so the difference in cents is exact
+            let cents = 100.0 * est_note.pitch_midi.abs_diff(ref_note.pitch_midi) as f32;
+            let pitch_ok = cents <= options.pitch_tolerance_cents;
+            let offset_ok = !match_offsets
+                || time_distance(est_note.start_time_seconds + est_note.duration_seconds, ref_offset) <= offset_tolerance;
+            onset_ok && pitch_ok && offset_ok
+        }).map(|(est_idx, _)| est_idx).collect()
+    }).collect();
+
+    // maximum bipartite matching with augmenting paths
+    let mut matched_reference: Vec<Option<usize>> = vec![None; estimated.len()];
+    for ref_idx in 0..reference.len() {
+        let mut visited = vec![false; estimated.len()];
+        augment(ref_idx, &candidates, &mut matched_reference, &mut visited);
+    }
+
+    let mut pairs: Vec<(usize, usize)> = matched_reference
+        .iter()
+        .enumerate()
+        .filter_map(|(est_idx, ref_idx)| ref_idx.map(|ref_idx| (ref_idx, est_idx)))
+        .collect();
+    pairs.sort_unstable();
+    pairs
+}
+
+fn augment(ref_idx: usize, candidates: &[Vec<usize>], matched_reference: &mut [Option<usize>], visited: &mut [bool]) -> bool {
+    for &est_idx in &candidates[ref_idx] {
+        if visited[est_idx] {
+            continue;
+        }
+        visited[est_idx] = true;
+        let can_take = match matched_reference[est_idx] {
+            None => true,
+            Some(other_ref_idx) => augment(other_ref_idx, candidates, matched_reference, visited),
+        };
+        if can_take {
+            matched_reference[est_idx] = Some(ref_idx);
+            return true;
+        }
+    }
+    false
+}
+
+/// Note-level precision, recall and F-measure.
+///
+/// # Arguments
+///
+/// * `reference` - Reference notes.
+/// * `estimated` - Estimated notes.
+/// * `options` - Matching tolerances.
+/// * `match_offsets` - Whether the offsets have to match.
+///
+/// # Returns
+///
+/// * The note metrics.
+pub fn evaluate_notes(
+    reference: &[NoteEventTime],
+    estimated: &[NoteEventTime],
+    options: &NoteMatchingOptions,
+    match_offsets: bool,
+) -> NoteMetrics {
+    let n_matched = match_notes(reference, estimated, options, match_offsets).len();
+    let (precision, recall, f_measure) = precision_recall_f_measure(n_matched, estimated.len(), reference.len());
+    NoteMetrics {
+        precision,
+        recall,
+        f_measure,
+        n_reference: reference.len(),
+        n_estimated: estimated.len(),
+        n_matched,
+    }
+}
+
+/// Frame-level metrics of the frame activations against a piano roll of the reference notes.
+///
+/// # Arguments
+///
+/// * `reference` - Reference notes.
+/// * `frames` - Frame activation matrix (n_times, n_freqs).
+/// * `frame_thresh` - Minimum amplitude of a frame activation to count as an active pitch. If NaN, mean + std of the frames is used.
+///
+/// # Returns
+///
+/// * The frame metrics.
+pub fn evaluate_frames(reference: &[NoteEventTime], frames: &Array2<f32>, frame_thresh: f32) -> FrameMetrics {
+    let frame_thresh = if frame_thresh.is_nan() {
+        frames.mean().unwrap_or(0.0) + frames.std(0.0)
+    } else {
+        frame_thresh
+    };
+    let (mut true_positives, mut false_positives, mut false_negatives) = (0usize, 0usize, 0usize);
+
+    for (frame_idx, row) in frames.outer_iter().enumerate() {
+        let time = model_frame_to_time(frame_idx);
+        let mut reference_active = vec![false; row.len()];
+        for note in reference.iter().filter(|note| note.start_time_seconds <= time && time < note.start_time_seconds + note.duration_seconds) {
+            if let Some(active) = note.pitch_midi.checked_sub(MIDI_OFFSET).and_then(|freq_idx| reference_active.get_mut(freq_idx)) {
+                *active = true;
+            }
+        }
+
+        for (&activation, &active) in row.iter().zip(reference_active.iter()) {
+            match (activation >= frame_thresh, active) {
+                (true, true) => true_positives += 1,
+                (true, false) => false_positives += 1,
+                (false, true) => false_negatives += 1,
+                (false, false) => {}
+            }
+        }
+    }
+
+    let (precision, recall, f_measure) = precision_recall_f_measure(true_positives, true_positives + false_positives, true_positives + false_negatives);
+    let total = true_positives + false_positives + false_negatives;
+    FrameMetrics {
+        precision,
+        recall,
+        f_measure,
+        accuracy: if total == 0 { 0.0 } else { true_positives as f32 / total as f32 },
+    }
+}
+
+/// Evaluate a transcription on note level, with and without offsets, and optionally on frame level.
+///
+/// # Arguments
+///
+/// * `reference` - Reference notes.
+/// * `estimated` - Estimated notes.
+/// * `frames` - Frame activations the notes were decoded from and the frame threshold, for frame-level metrics.
+/// * `options` - Matching tolerances.
+///
+/// # Returns
+///
+/// * The transcription metrics.
+pub fn evaluate_transcription(
+    reference: &[NoteEventTime],
+    estimated: &[NoteEventTime],
+    frames: Option<(&Array2<f32>, f32)>,
+    options: &NoteMatchingOptions,
+) -> TranscriptionMetrics {
+    TranscriptionMetrics {
+        note_onset: evaluate_notes(reference, estimated, options, false),
+        note_onset_offset: evaluate_notes(reference, estimated, options, true),
+        frame: frames.map(|(frames, frame_thresh)| evaluate_frames(reference, frames, frame_thresh)),
+    }
+}
+
+fn precision_recall_f_measure(n_correct: usize, n_estimated: usize, n_reference: usize) -> (f32, f32, f32) {
+    let precision = if n_estimated == 0 { 0.0 } else { n_correct as f32 / n_estimated as f32 };
+    let recall = if n_reference == 0 { 0.0 } else { n_correct as f32 / n_reference as f32 };
+    let f_measure = if precision + recall == 0.0 { 0.0 } else { 2.0 * precision * recall / (precision + recall) };
+    (precision, recall, f_measure)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use crate::{
+        constants::MIDI_OFFSET,
+        postprocessing::{helpers::ported::librosa::model_frame_to_time, note_event_times::NoteEventTime},
+    };
+
+    use super::{evaluate_frames, match_notes, NoteMatchingOptions};
+
+    /// (case, reference, estimated, options, match offsets, expected pairs)
+    type MatchingCase<'a> = (&'a str, Vec<NoteEventTime>, Vec<NoteEventTime>, &'a NoteMatchingOptions, bool, Vec<(usize, usize)>);
+
+    fn note(start_time_seconds: f32, end_time_seconds: f32, pitch_midi: usize) -> NoteEventTime {
+        NoteEventTime {
+            start_time_seconds,
+            duration_seconds: end_time_seconds - start_time_seconds,
+            pitch_midi,
+            amplitude: 0.8,
+            pitch_bends: None,
+            amplitude_envelope: None,
+        }
+    }
+
+    #[test]
+    fn notes_match_within_the_tolerances() {
+        let defaults = NoteMatchingOptions::default();
+        let semitone_tolerance = NoteMatchingOptions { pitch_tolerance_cents: 100.0, ..NoteMatchingOptions::default() };
+        let cases: Vec<MatchingCase> = vec![
+            ("onset on the tolerance", vec![note(1.0, 2.0, 60)], vec![note(1.05, 2.0, 60)], &defaults, false, vec![(0, 0)]),
+            ("onset on the tolerance late in a file", vec![note(100.0, 101.0, 60)], vec![note(100.05, 101.0, 60)], &defaults, false, vec![(0, 0)]),
+            ("onset past the tolerance", vec![note(1.0, 2.0, 60)], vec![note(1.06, 2.0, 60)], &defaults, false, vec![]),
+            ("a semitone is past 50 cents", vec![note(1.0, 2.0, 60)], vec![note(1.0, 2.0, 61)], &defaults, false, vec![]),
+            ("pitch on the tolerance", vec![note(1.0, 2.0, 60)], vec![note(1.0, 2.0, 61)], &semitone_tolerance, false, vec![(0, 0)]),
+            ("offsets are ignored", vec![note(1.0, 2.0, 60)], vec![note(1.0, 3.0, 60)], &defaults, false, vec![(0, 0)]),
+            ("offset within 20% of the duration", vec![note(1.0, 2.0, 60)], vec![note(1.0, 2.2, 60)], &defaults, true, vec![(0, 0)]),
+            ("offset past 20% of the duration", vec![note(1.0, 2.0, 60)], vec![note(1.0, 2.25, 60)], &defaults, true, vec![]),
+            ("short note, offset on the minimum tolerance", vec![note(1.0, 1.1, 60)], vec![note(1.0, 1.15, 60)], &defaults, true, vec![(0, 0)]),
+            ("short note, offset past the minimum tolerance", vec![note(1.0, 1.1, 60)], vec![note(1.0, 1.16, 60)], &defaults, true, vec![]),
+            // matching each reference note to its closest free estimate would pair 0 with 0 and leave 1 unmatched
+            ("bipartite beats greedy", vec![note(1.0, 2.0, 60), note(1.06, 2.0, 60)], vec![note(1.03, 2.0, 60), note(0.96, 2.0, 60)], &defaults, false, vec![(0, 1), (1, 0)]),
+            ("each note is matched once", vec![note(1.0, 2.0, 60)], vec![note(1.0, 2.0, 60), note(1.01, 2.0, 60)], &defaults, false, vec![(0, 0)]),
+        ];
+
+        for (case, reference, estimated, options, match_offsets, expected) in cases {
+            assert_eq!(match_notes(&reference, &estimated, options, match_offsets), expected, "{}", case);
+        }
+    }
+
+    #[test]
+    fn frames_are_scored_against_the_reference_piano_roll() {
+        // the reference is active in frames 2 to 4, the activations in frames 3 to 5
+        let reference = [note(model_frame_to_time(2), model_frame_to_time(5), 60)];
+        let mut frames = Array2::zeros((8, 88));
+        for frame in 3..6 {
+            frames[[frame, 60 - MIDI_OFFSET]] = 0.9;
+        }
+        // below the threshold
+        frames[[6, 70 - MIDI_OFFSET]] = 0.2;
+
+        let metrics = evaluate_frames(&reference, &frames, 0.3);
+        assert!((metrics.precision - 2.0 / 3.0).abs() < 1e-6);
+        assert!((metrics.recall - 2.0 / 3.0).abs() < 1e-6);
+        assert!((metrics.f_measure - 2.0 / 3.0).abs() < 1e-6);
+        assert!((metrics.accuracy - 0.5).abs() < 1e-6);
+    }
+}
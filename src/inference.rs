@@ -1,10 +1,15 @@
-use std::{collections::HashMap, error::Error};
+use std::{collections::HashMap, error::Error, io::Read};
 
-use ndarray::{concatenate, s, Array2, Array3, ArrayView3, Axis, Ix2};
+use ndarray::{concatenate, s, Array1, Array2, Array3, ArrayView1, ArrayView3, Axis, Ix2};
 use ort::{GraphOptimizationLevel, Session, Tensor};
 
-use crate::constants::{ANNOTATIONS_FPS, AUDIO_N_SAMPLES, AUDIO_SAMPLE_RATE, FFT_HOP, MODEL_PATH};
-use crate::preprocessing::load_audio::get_audio_input;
+use crate::constants::{
+    ANNOTATIONS_FPS, ANNOTATIONS_N_SEMITONES, ANNOT_N_FRAMES, AUDIO_SAMPLE_RATE, HOP_SIZE, MODEL_PATH, N_FREQ_BINS_CONTOURS,
+    N_OVERLAPPING_FRAMES, OVERLAP_LEN,
+};
+use crate::model_outputs::ModelOutput;
+use crate::preprocessing::load_audio::{get_audio_input, load_and_convert_audio_from_reader, window_audio_input, TimeRange};
+use crate::preprocessing::silence::SilenceGate;
 
 fn unwrap_output(
     output: Array3<f32>,
@@ -32,20 +37,129 @@ fn unwrap_output(
     Some(unwrapped_output.slice(s![..n_output_frames_original, ..]).to_owned())
 }
 
+/// Load the ONNX model.
+///
+/// # Arguments
+///
+/// * `model_path` - Path of the ONNX model file.
+///
+/// # Returns
+///
+/// * The model session, which can be reused for any number of audio files.
+pub fn load_model(model_path: &str) -> Result<Session, Box<dyn Error>> {
+    let model = Session::builder()?
+        .with_optimization_level(GraphOptimizationLevel::Level3)?
+        .with_intra_threads(4)?
+        .commit_from_file(model_path)?;
+    Ok(model)
+}
+
 pub fn run_inference(
     audio_path: &str,
-) -> Result<(Array2<f32>, Array2<f32>, Array2<f32>), Box<dyn Error>> {
-    let n_overlapping_frames = 30;
-    let overlap_len = n_overlapping_frames * FFT_HOP;
-    let hop_size = AUDIO_N_SAMPLES - overlap_len;
+) -> Result<ModelOutput, Box<dyn Error>> {
+    run_inference_with_model(audio_path, &load_model(MODEL_PATH)?)
+}
 
-    let (audio_windows, original_length) = get_audio_input(audio_path, overlap_len, hop_size)?;
+/// Run a loaded model on an audio file.
+///
+/// # Arguments
+///
+/// * `audio_path` - Path of the WAV file.
+/// * `model` - The model session from `load_model`.
+///
+/// # Returns
+///
+/// * The model output.
+pub fn run_inference_with_model(
+    audio_path: &str,
+    model: &Session,
+) -> Result<ModelOutput, Box<dyn Error>> {
+    let (audio_windows, original_length) = get_audio_input(audio_path, OVERLAP_LEN, HOP_SIZE)?;
+    Ok(run_model(audio_windows, original_length, None, model)?.0)
+}
 
-    let model = Session::builder()?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_intra_threads(4)?
-        .commit_from_file(MODEL_PATH)?;
+/// Run a loaded model on WAV data from a reader.
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the WAV data from, e.g. an uploaded file in memory.
+/// * `range` - The segment to transcribe.
+/// * `model` - The model session from `load_model`.
+///
+/// # Returns
+///
+/// * The model output.
+pub fn run_inference_on_reader<R: Read>(
+    reader: R,
+    range: &TimeRange,
+    model: &Session,
+) -> Result<ModelOutput, Box<dyn Error>> {
+    let (audio, original_length) = load_and_convert_audio_from_reader(reader, range, AUDIO_SAMPLE_RATE as u32)?;
+    Ok(run_model(window_audio_input(&audio, OVERLAP_LEN, HOP_SIZE)?, original_length, None, model)?.0)
+}
+
+/// Run a loaded model on mono audio samples that are already at the model sample rate.
+///
+/// # Arguments
+///
+/// * `audio` - Audio samples at 22050 Hz, in [-1, 1].
+/// * `model` - The model session from `load_model`.
+///
+/// # Returns
+///
+/// * The model output.
+pub fn run_inference_on_samples(
+    audio: &Array1<f32>,
+    model: &Session,
+) -> Result<ModelOutput, Box<dyn Error>> {
+    Ok(run_model(window_audio_input(audio, OVERLAP_LEN, HOP_SIZE)?, audio.len(), None, model)?.0)
+}
+
+/// The number of windows of audio and how many of them were silent, for which the model wasn't run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WindowCounts {
+    pub total: usize,
+    pub silent: usize,
+}
+
+/// Run a loaded model on converted mono audio, optionally skipping the silent windows.
+///
+/// # Arguments
+///
+/// * `audio` - Audio samples at 22050 Hz, in [-1, 1], e.g. from `load_and_convert_audio`.
+/// * `original_length` - The length of the audio in samples, as returned with it.
+/// * `silence_gate` - If set, windows it detects as silent get zero activations instead of running the model.
+/// * `model` - The model session from `load_model`.
+///
+/// # Returns
+///
+/// * The model output and the number of windows that were skipped.
+pub fn run_inference_on_audio(
+    audio: &Array1<f32>,
+    original_length: usize,
+    silence_gate: Option<&SilenceGate>,
+    model: &Session,
+) -> Result<(ModelOutput, WindowCounts), Box<dyn Error>> {
+    run_model(window_audio_input(audio, OVERLAP_LEN, HOP_SIZE)?, original_length, silence_gate, model)
+}
+
+fn run_model(
+    audio_windows: Vec<Array2<f32>>,
+    original_length: usize,
+    silence_gate: Option<&SilenceGate>,
+    model: &Session,
+) -> Result<(ModelOutput, WindowCounts), Box<dyn Error>> {
+    run_windows(audio_windows, original_length, silence_gate, |window| run_window(window, model))
+}
 
+/// Run `run_window` on every window that isn't silent and unwrap the outputs, so the gating can be tested without a model.
+fn run_windows<F: FnMut(Array2<f32>) -> Result<[Array3<f32>; 3], Box<dyn Error>>>(
+    audio_windows: Vec<Array2<f32>>,
+    original_length: usize,
+    silence_gate: Option<&SilenceGate>,
+    mut run_window: F,
+) -> Result<(ModelOutput, WindowCounts), Box<dyn Error>> {
+    let mut window_counts = WindowCounts { total: audio_windows.len(), silent: 0 };
     let mut output: HashMap<String, Vec<Array3<f32>>> = HashMap::from([
         ("contours".to_string(), vec![]),
         ("onsets".to_string(), vec![]),
@@ -53,42 +167,154 @@ pub fn run_inference(
     ]);
 
     for window in audio_windows {
-        // Ensure the window has the correct shape
-        let window = window.insert_axis(Axis(2)).to_owned();
-        let input_shape: Vec<i64> = window.shape().iter().map(|&dim| dim as i64).collect();
-        let input_data: Vec<f32> = window.into_raw_vec();
-        let input_tensor = Tensor::from_array((input_shape, input_data))?;
-        let outputs = model.run(ort::inputs![input_tensor]?)?;
-    
-        for (&k, v) in outputs.iter() {
-            let value = v
-                .try_extract_tensor::<f32>()?
-                .index_axis(Axis(0), 0)
-                .into_dimensionality::<Ix2>()?
-                .insert_axis(Axis(0))
-                .to_owned();
-
-            if k == "StatefulPartitionedCall:0" {
-                output.get_mut("contours").unwrap().push(value.clone())
-            } else if k == "StatefulPartitionedCall:1" { 
-                output.get_mut("frames").unwrap().push(value.clone())
-            } else if k == "StatefulPartitionedCall:2" {
-                output.get_mut("onsets").unwrap().push(value.clone())
-            }
-        }
-
+        let [contours, frames, onsets] = if silence_gate.is_some_and(|gate| gate.is_silent(window.row(0))) {
+            window_counts.silent += 1;
+            [
+                Array3::zeros((1, ANNOT_N_FRAMES, N_FREQ_BINS_CONTOURS)),
+                Array3::zeros((1, ANNOT_N_FRAMES, ANNOTATIONS_N_SEMITONES as usize)),
+                Array3::zeros((1, ANNOT_N_FRAMES, ANNOTATIONS_N_SEMITONES as usize)),
+            ]
+        } else {
+            run_window(window)?
+        };
+        output.get_mut("contours").unwrap().push(contours);
+        output.get_mut("frames").unwrap().push(frames);
+        output.get_mut("onsets").unwrap().push(onsets);
     }
     
     let unwrapped_output: HashMap<String, Array2<f32>> = output.into_iter().map(|(k, v)| {
         let views: Vec<ArrayView3<f32>> = v.iter().map(|array| array.view()).collect();
         let concatenated = concatenate(Axis(0), views.as_slice()).unwrap();
-        let unwrapped = unwrap_output(concatenated, original_length, n_overlapping_frames).unwrap();
+        let unwrapped = unwrap_output(concatenated, original_length, N_OVERLAPPING_FRAMES).unwrap();
         (k, unwrapped)
     }).collect();
 
-    Ok((
-        unwrapped_output.get("contours").unwrap().clone(),
-        unwrapped_output.get("frames").unwrap().clone(),
-        unwrapped_output.get("onsets").unwrap().clone(),
-    ))
-}
\ No newline at end of file
+    let model_output = ModelOutput {
+        contours: unwrapped_output.get("contours").unwrap().clone(),
+        frames: unwrapped_output.get("frames").unwrap().clone(),
+        onsets: unwrapped_output.get("onsets").unwrap().clone(),
+        audio_n_samples: original_length,
+    };
+    Ok((model_output, window_counts))
+}
+
+/// Run a loaded model on a single window of audio, for processing a stream window by window.
+///
+/// # Arguments
+///
+/// * `window` - `AUDIO_N_SAMPLES` samples at 22050 Hz. Consecutive windows start `HOP_SIZE` samples
+///   apart, and the first starts `OVERLAP_LEN / 2` samples before the audio.
+/// * `model` - The model session from `load_model`.
+///
+/// # Returns
+///
+/// * The model output for the `N_FRAMES_PER_WINDOW` frames the window contributes. The frames at
+///   its edges are left to the neighbouring windows, like `run_inference` does.
+pub fn run_inference_on_window(
+    window: ArrayView1<f32>,
+    model: &Session,
+) -> Result<ModelOutput, Box<dyn Error>> {
+    let n_olap = N_OVERLAPPING_FRAMES / 2;
+    let [contours, frames, onsets] = run_window(window.insert_axis(Axis(0)).to_owned(), model)?;
+    let trim = |output: Array3<f32>| output.index_axis(Axis(0), 0).slice(s![n_olap..ANNOT_N_FRAMES - n_olap, ..]).to_owned();
+
+    Ok(ModelOutput {
+        contours: trim(contours),
+        frames: trim(frames),
+        onsets: trim(onsets),
+        audio_n_samples: HOP_SIZE,
+    })
+}
+
+/// Run the model on one window, returning the contours, frames and onsets with a leading window axis.
+fn run_window(window: Array2<f32>, model: &Session) -> Result<[Array3<f32>; 3], Box<dyn Error>> {
+    // Ensure the window has the correct shape
+    let window = window.insert_axis(Axis(2)).to_owned();
+    let input_shape: Vec<i64> = window.shape().iter().map(|&dim| dim as i64).collect();
+    let input_data: Vec<f32> = window.into_raw_vec();
+    let input_tensor = Tensor::from_array((input_shape, input_data))?;
+    let outputs = model.run(ort::inputs![input_tensor]?)?;
+
+    let mut contours = None;
+    let mut frames = None;
+    let mut onsets = None;
+    for (&k, v) in outputs.iter() {
+        let value = v
+            .try_extract_tensor::<f32>()?
+            .index_axis(Axis(0), 0)
+            .into_dimensionality::<Ix2>()?
+            .insert_axis(Axis(0))
+            .to_owned();
+
+        if k == "StatefulPartitionedCall:0" {
+            contours = Some(value)
+        } else if k == "StatefulPartitionedCall:1" {
+            frames = Some(value)
+        } else if k == "StatefulPartitionedCall:2" {
+            onsets = Some(value)
+        }
+    }
+
+    match (contours, frames, onsets) {
+        (Some(contours), Some(frames), Some(onsets)) => Ok([contours, frames, onsets]),
+        _ => Err("the model is missing an output".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use ndarray::{s, Array2, Array3};
+
+    use crate::{
+        constants::{
+            ANNOTATIONS_FPS, ANNOTATIONS_N_SEMITONES, ANNOT_N_FRAMES, AUDIO_N_SAMPLES, AUDIO_SAMPLE_RATE, HOP_SIZE, N_FRAMES_PER_WINDOW,
+            N_FREQ_BINS_CONTOURS,
+        },
+        preprocessing::silence::SilenceGate,
+    };
+
+    use super::{run_windows, WindowCounts};
+
+    /// A stand-in for the model with activations of 1 everywhere.
+    fn ones(_window: Array2<f32>) -> Result<[Array3<f32>; 3], Box<dyn Error>> {
+        Ok([
+            Array3::ones((1, ANNOT_N_FRAMES, N_FREQ_BINS_CONTOURS)),
+            Array3::ones((1, ANNOT_N_FRAMES, ANNOTATIONS_N_SEMITONES as usize)),
+            Array3::ones((1, ANNOT_N_FRAMES, ANNOTATIONS_N_SEMITONES as usize)),
+        ])
+    }
+
+    #[test]
+    fn silent_windows_get_zero_activations_without_running_the_model() {
+        let tone = Array2::from_shape_fn((1, AUDIO_N_SAMPLES), |(_, i)| (i as f32 * 0.1).sin() * 0.5);
+        let silence = Array2::zeros((1, AUDIO_N_SAMPLES));
+        let windows = vec![tone.clone(), silence.clone(), tone, silence];
+        // the last window reaches past the end of the audio, its frames there are cut off
+        let original_length = 4 * HOP_SIZE;
+        let n_frames = original_length * ANNOTATIONS_FPS / AUDIO_SAMPLE_RATE;
+        assert!(n_frames > 3 * N_FRAMES_PER_WINDOW);
+
+        for (silence_gate, expected_runs) in [(Some(SilenceGate::default()), 2), (None, 4)] {
+            let mut n_runs = 0;
+            let (model_output, window_counts) = run_windows(windows.clone(), original_length, silence_gate.as_ref(), |window| {
+                n_runs += 1;
+                ones(window)
+            }).unwrap();
+
+            assert_eq!(n_runs, expected_runs);
+            assert_eq!(window_counts, WindowCounts { total: 4, silent: 4 - expected_runs });
+            assert_eq!(model_output.contours.dim(), (n_frames, N_FREQ_BINS_CONTOURS));
+            assert_eq!(model_output.frames.dim(), (n_frames, ANNOTATIONS_N_SEMITONES as usize));
+            assert_eq!(model_output.onsets.dim(), model_output.frames.dim());
+            for activations in [&model_output.contours, &model_output.frames, &model_output.onsets] {
+                for window_idx in 0..4 {
+                    let expected = if silence_gate.is_some() && window_idx % 2 == 1 { 0.0 } else { 1.0 };
+                    let rows = activations.slice(s![window_idx * N_FRAMES_PER_WINDOW..((window_idx + 1) * N_FRAMES_PER_WINDOW).min(n_frames), ..]);
+                    assert!(rows.iter().all(|&value| value == expected), "window {}", window_idx);
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,40 @@
-use std::{error::Error, fs::File, io::Write, path::Path};
+use std::process::ExitCode;
 
-use inference::run_inference;
-use postprocessing::{midi::generate_midi_file_data, note_event_frames::{add_pitch_bends_to_note_events, output_to_notes_poly}, note_event_times::note_frames_to_time};
+use clap::{Parser, Subcommand};
+use cli::{benchmark::BenchmarkArgs, evaluate::EvaluateArgs, osc::OscArgs, predict::PredictArgs, tune::TuneArgs, watch::WatchArgs};
 
+pub mod batch;
+pub mod benchmark;
+pub mod cli {
+    pub mod benchmark;
+    pub mod evaluate;
+    pub mod options;
+    pub mod osc;
+    pub mod predict;
+    #[cfg(feature = "server")]
+    pub mod serve;
+    #[cfg(feature = "server")]
+    pub mod stream;
+    pub mod tune;
+    pub mod watch;
+}
 pub mod constants;
+pub mod evaluation;
 pub mod inference;
+pub mod model_outputs;
+pub mod osc;
+#[cfg(test)]
+mod parity_tests;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod stream_server;
+pub mod streaming;
+pub mod tuning;
+pub mod watch;
 pub mod preprocessing {
     pub mod load_audio;
+    pub mod silence;
     pub mod windowed_audio;
 }
 pub mod postprocessing {
@@ -20,41 +48,67 @@ pub mod postprocessing {
     pub mod note_event_frames;
     pub mod note_event_times;
     pub mod midi;
+    pub mod musicxml;
+    pub mod note_list;
+    pub mod pitch_tracking;
+    pub mod sonification;
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let (contours_array, frames_array, onsets_array) = run_inference("test_data/C_major.wav")?;
-
-    let contours: Vec<Vec<f32>> = contours_array.outer_iter().map(|row| row.to_vec()).collect();
-    let frames: Vec<Vec<f32>> = frames_array.outer_iter().map(|row| row.to_vec()).collect();
-    let onsets: Vec<Vec<f32>> = onsets_array.outer_iter().map(|row| row.to_vec()).collect();
-
-    let note_event_frames = output_to_notes_poly(
-        frames,
-        onsets,
-        0.5,
-        0.3,
-        5,
-        true,
-        None,
-        None,
-        true,
-        11,
-    );
-
-    let notes_event_frames_with_bend = add_pitch_bends_to_note_events(
-        &contours,
-        &note_event_frames,
-        25
-    );
+#[derive(Debug, Parser)]
+#[command(
+    name = "basic-pitch",
+    version,
+    about = "Automatic music transcription",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    predict: PredictArgs,
+}
 
-    
-    let note_event_times = note_frames_to_time(&notes_event_frames_with_bend);
-    let midi_buffer = generate_midi_file_data(&note_event_times, 120);
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compute note- and frame-level metrics of a transcription against reference notes.
+    Evaluate(EvaluateArgs),
+    /// Transcribe a dataset of audio files with reference notes and write per-file and aggregate metrics.
+    Benchmark(BenchmarkArgs),
+    /// Search for the decoding options with the best note F-measure on a dataset.
+    Tune(TuneArgs),
+    /// Watch a folder and transcribe new and changed audio files as they are written.
+    Watch(WatchArgs),
+    /// Play back notes as Open Sound Control messages over UDP.
+    Osc(OscArgs),
+    /// Serve transcription over a local HTTP API.
+    #[cfg(feature = "server")]
+    Serve(cli::serve::ServeArgs),
+    /// Serve live transcription of audio streamed over WebSocket or plain TCP.
+    #[cfg(feature = "server")]
+    Stream(cli::stream::StreamArgs),
+}
 
-    let output_file_path = Path::new("./output/output.midi");
-    let mut file = File::create(output_file_path)?;
-    file.write_all(&midi_buffer)?;
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Some(Command::Evaluate(args)) => cli::evaluate::run(args),
+        Some(Command::Benchmark(args)) => cli::benchmark::run(args),
+        Some(Command::Tune(args)) => cli::tune::run(args),
+        Some(Command::Watch(args)) => cli::watch::run(args),
+        Some(Command::Osc(args)) => cli::osc::run(args),
+        #[cfg(feature = "server")]
+        Some(Command::Serve(args)) => cli::serve::run(args),
+        #[cfg(feature = "server")]
+        Some(Command::Stream(args)) => cli::stream::run(args),
+        None => cli::predict::run(&cli.predict),
+    };
 
-    Ok(())
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
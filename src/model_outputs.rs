@@ -0,0 +1,490 @@
+use std::{error::Error, fs::{self, File}, io::{Read, Seek, Write}, path::{Path, PathBuf}};
+
+use ndarray::Array2;
+use ort::Session;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    constants::{ANNOTATIONS_FPS, AUDIO_SAMPLE_RATE},
+    inference::run_inference_with_model,
+    postprocessing::{
+        helpers::ported::{librosa::{midi_to_hz, FrameTimeAlignment}, numpy::mean_std_dev},
+        note_event_frames::{add_amplitude_envelopes_to_note_events, add_pitch_bends_to_note_events, output_to_notes_poly, NoteDecodingOptions, NoteEventFrame},
+        note_event_times::{note_frames_to_time, note_frames_to_time_refined, NoteEventTime},
+        pitch_tracking::{pitch_track_to_notes, track_pitch, MONOPHONIC_MAX_TRANSITION_BINS, MONOPHONIC_SPLIT_SEMITONES, MONOPHONIC_SWITCH_PROB},
+    },
+};
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+const NPZ_NAMES: [&str; 3] = ["contours", "frames", "onsets"];
+const NPZ_AUDIO_N_SAMPLES: &str = "audio_n_samples";
+
+/// The activations of the model for a piece of audio, which can be decoded to notes any number of times.
+#[derive(Debug, Clone)]
+pub struct ModelOutput {
+    /// Contour activation matrix (n_times, n_freq_bins_contours).
+    pub contours: Array2<f32>,
+    /// Frame activation matrix (n_times, n_freqs).
+    pub frames: Array2<f32>,
+    /// Onset activation matrix (n_times, n_freqs).
+    pub onsets: Array2<f32>,
+    /// Length of the audio in samples at `AUDIO_SAMPLE_RATE`.
+    pub audio_n_samples: usize,
+}
+
+impl ModelOutput {
+    /// Duration of the audio in seconds.
+    pub fn duration_seconds(&self) -> f32 {
+        self.audio_n_samples as f32 / AUDIO_SAMPLE_RATE as f32
+    }
+
+    /// Decode the activations to frame-based note events.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Settings for decoding the model output to note events. `refine_times` is ignored.
+    ///
+    /// # Returns
+    ///
+    /// * List of frame-based note events.
+    pub fn decode_frames(&self, options: &NoteDecodingOptions) -> Vec<NoteEventFrame> {
+        let frames = array_to_rows(&self.frames);
+
+        if options.monophonic {
+            let voicing_thresh = if options.frame_thresh.is_nan() {
+                let (mean, std) = mean_std_dev(&frames);
+                mean + std
+            } else {
+                options.frame_thresh
+            };
+            let pitch_track = track_pitch(&array_to_rows(&self.contours), voicing_thresh, MONOPHONIC_MAX_TRANSITION_BINS, MONOPHONIC_SWITCH_PROB);
+            return pitch_track_to_notes(&pitch_track, options.min_note_len, MONOPHONIC_SPLIT_SEMITONES)
+                .into_iter()
+                .filter(|note| {
+                    let hz = midi_to_hz(note.pitch_midi as f32);
+                    options.min_freq.is_none_or(|min_freq| hz >= min_freq) && options.max_freq.is_none_or(|max_freq| hz <= max_freq)
+                })
+                .map(|note| NoteEventFrame {
+                    pitch_bends: note.pitch_bends.filter(|_| options.pitch_bend_bins_tolerance.is_some()),
+                    amplitude_envelope: note.amplitude_envelope.filter(|_| options.amplitude_envelopes),
+                    ..note
+                })
+                .collect();
+        }
+
+        let mut note_event_frames = output_to_notes_poly(
+            frames.clone(),
+            array_to_rows(&self.onsets),
+            options.onset_thresh,
+            options.frame_thresh,
+            options.min_note_len,
+            options.infer_onsets,
+            options.max_freq,
+            options.min_freq,
+            options.melodia_trick,
+            options.energy_tolerance,
+        );
+
+        if let Some(n_bins_tolerance) = options.pitch_bend_bins_tolerance {
+            note_event_frames = add_pitch_bends_to_note_events(&array_to_rows(&self.contours), &note_event_frames, n_bins_tolerance);
+        }
+        if options.amplitude_envelopes {
+            note_event_frames = add_amplitude_envelopes_to_note_events(&frames, &note_event_frames);
+        }
+        note_event_frames
+    }
+
+    /// Decode the activations to time-based note events.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Settings for decoding the model output to note events.
+    ///
+    /// # Returns
+    ///
+    /// * List of time-based note events.
+    pub fn decode(&self, options: &NoteDecodingOptions) -> Vec<NoteEventTime> {
+        let note_event_frames = self.decode_frames(options);
+
+        if options.refine_times {
+            let frames = array_to_rows(&self.frames);
+            let frame_thresh = if options.frame_thresh.is_nan() {
+                let (mean, std) = mean_std_dev(&frames);
+                mean + std
+            } else {
+                options.frame_thresh
+            };
+            note_frames_to_time_refined(&note_event_frames, &array_to_rows(&self.onsets), &frames, frame_thresh, &FrameTimeAlignment::default())
+        } else {
+            note_frames_to_time(&note_event_frames)
+        }
+    }
+}
+
+fn array_to_rows(array: &Array2<f32>) -> Vec<Vec<f32>> {
+    array.outer_iter().map(|row| row.to_vec()).collect()
+}
+
+/// Write a 2D array in the NumPy `.npy` format as little-endian float32 in C order.
+///
+/// # Arguments
+///
+/// * `array` - Array to write.
+/// * `writer` - Where to write the array to.
+pub fn write_npy<W: Write>(array: &Array2<f32>, mut writer: W) -> Result<(), Box<dyn Error>> {
+    let (rows, cols) = array.dim();
+    write_npy_header(&mut writer, "<f4", &format!("({}, {})", rows, cols))?;
+    for value in array.iter() {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn write_npy_header<W: Write>(writer: &mut W, descr: &str, shape: &str) -> Result<(), Box<dyn Error>> {
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+
+    // magic, version and header length take 10 bytes, the header ends with a newline and the total is aligned to 64 bytes
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    header.push_str(&" ".repeat(padding % 64));
+    header.push('\n');
+
+    writer.write_all(NPY_MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+/// Read a 2D array from the NumPy `.npy` format.
+///
+/// Supports little-endian float32 and float64 data in C or Fortran order.
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the array from.
+///
+/// # Returns
+///
+/// * The array, converted to float32.
+pub fn read_npy<R: Read>(mut reader: R) -> Result<Array2<f32>, Box<dyn Error>> {
+    let (descr, fortran_order, shape) = read_npy_header(&mut reader)?;
+    if shape.len() != 2 {
+        return Err(format!("expected a 2D array, found shape {:?}", shape).into());
+    }
+
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    let values: Vec<f32> = match descr.as_str() {
+        "<f4" => data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+        "<f8" => data.chunks_exact(8).map(|b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32).collect(),
+        other => return Err(format!("unsupported .npy dtype {}", other).into()),
+    };
+
+    if fortran_order {
+        Ok(Array2::from_shape_vec((shape[1], shape[0]), values)?.reversed_axes().as_standard_layout().to_owned())
+    } else {
+        Ok(Array2::from_shape_vec((shape[0], shape[1]), values)?)
+    }
+}
+
+/// Returns the dtype, whether the data is in Fortran order and the shape.
+fn read_npy_header<R: Read>(reader: &mut R) -> Result<(String, bool, Vec<usize>), Box<dyn Error>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic[..6] != NPY_MAGIC {
+        return Err("not a .npy file".into());
+    }
+
+    let header_len = if magic[6] == 1 {
+        let mut len = [0u8; 2];
+        reader.read_exact(&mut len)?;
+        u16::from_le_bytes(len) as usize
+    } else {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        u32::from_le_bytes(len) as usize
+    };
+    let mut header = vec![0u8; header_len];
+    reader.read_exact(&mut header)?;
+    let header = String::from_utf8_lossy(&header);
+
+    let descr = header_value(&header, "descr").ok_or("missing 'descr' in .npy header")?;
+    let fortran_order = header_value(&header, "fortran_order").ok_or("missing 'fortran_order' in .npy header")?.starts_with("True");
+    let shape_value = header_value(&header, "shape").ok_or("missing 'shape' in .npy header")?;
+    let shape: Vec<usize> = shape_value
+        .trim_start_matches('(')
+        .split([',', ')'])
+        .map(|dim| dim.trim())
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse::<usize>())
+        .collect::<Result<_, _>>()?;
+
+    Ok((descr.trim_matches(|c| c == '\'' || c == '"').to_string(), fortran_order, shape))
+}
+
+/// Read a single integer stored as a 0-dimensional int64 `.npy` array.
+fn read_npy_u64<R: Read>(mut reader: R) -> Result<u64, Box<dyn Error>> {
+    let (descr, _, shape) = read_npy_header(&mut reader)?;
+    if descr != "<i8" || !shape.is_empty() {
+        return Err(format!("expected an int64 scalar, found dtype {} and shape {:?}", descr, shape).into());
+    }
+    let mut value = [0u8; 8];
+    reader.read_exact(&mut value)?;
+    Ok(i64::from_le_bytes(value) as u64)
+}
+
+/// Find the value of a key in the Python dict literal of a `.npy` header.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+    let value = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if value.starts_with('(') {
+        value.find(')')? + 1
+    } else {
+        value.find([',', '}'])?
+    };
+    Some(value[..end].trim())
+}
+
+/// Save the model output to a NumPy `.npz` archive with `contours`, `frames`, `onsets` and
+/// `audio_n_samples` arrays.
+///
+/// The archive can be loaded in Python with `np.load(path)`.
+///
+/// # Arguments
+///
+/// * `path` - Path of the `.npz` file to create.
+/// * `model_output` - The model output to save.
+pub fn save_model_outputs_npz<P: AsRef<Path>>(path: P, model_output: &ModelOutput) -> Result<(), Box<dyn Error>> {
+    write_model_outputs_npz(File::create(path)?, model_output)
+}
+
+/// Write the model output as a NumPy `.npz` archive, see `save_model_outputs_npz`.
+pub fn write_model_outputs_npz<W: Write + Seek>(writer: W, model_output: &ModelOutput) -> Result<(), Box<dyn Error>> {
+    let mut zip = ZipWriter::new(writer);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    for (name, array) in NPZ_NAMES.iter().zip([&model_output.contours, &model_output.frames, &model_output.onsets]) {
+        zip.start_file(format!("{}.npy", name), options)?;
+        write_npy(array, &mut zip)?;
+    }
+
+    zip.start_file(format!("{}.npy", NPZ_AUDIO_N_SAMPLES), options)?;
+    write_npy_header(&mut zip, "<i8", "()")?;
+    zip.write_all(&(model_output.audio_n_samples as i64).to_le_bytes())?;
+
+    zip.finish()?;
+
+    Ok(())
+}
+
+/// Load a model output from a NumPy `.npz` archive with `contours`, `frames` and `onsets` arrays.
+///
+/// Archives written by `np.savez` and `np.savez_compressed` are supported. If the archive has no
+/// `audio_n_samples` array, the audio length is estimated from the number of frames. The
+/// activations can be decoded with `output_to_notes_poly` and `add_pitch_bends_to_note_events`
+/// like those of `run_inference`.
+///
+/// # Arguments
+///
+/// * `path` - Path of the `.npz` file.
+///
+/// # Returns
+///
+/// * The model output.
+pub fn load_model_outputs_npz<P: AsRef<Path>>(path: P) -> Result<ModelOutput, Box<dyn Error>> {
+    read_model_outputs_npz(File::open(path)?)
+}
+
+/// Read a model output from a NumPy `.npz` archive, see `load_model_outputs_npz`.
+pub fn read_model_outputs_npz<R: Read + Seek>(reader: R) -> Result<ModelOutput, Box<dyn Error>> {
+    let mut zip = ZipArchive::new(reader)?;
+
+    let mut arrays = vec![];
+    for name in NPZ_NAMES {
+        let file = zip
+            .by_name(&format!("{}.npy", name))
+            .map_err(|_| format!("missing '{}' array in .npz file", name))?;
+        arrays.push(read_npy(file)?);
+    }
+
+    let onsets = arrays.pop().unwrap();
+    let frames = arrays.pop().unwrap();
+    let contours = arrays.pop().unwrap();
+
+    let audio_n_samples = match zip.by_name(&format!("{}.npy", NPZ_AUDIO_N_SAMPLES)) {
+        Ok(file) => read_npy_u64(file)? as usize,
+        Err(_) => frames.nrows() * AUDIO_SAMPLE_RATE / ANNOTATIONS_FPS,
+    };
+
+    Ok(ModelOutput { contours, frames, onsets, audio_n_samples })
+}
+
+/// A directory of model outputs, keyed by a hash of the audio file and of the model file.
+#[derive(Debug, Clone)]
+pub struct ModelOutputCache {
+    directory: PathBuf,
+    model_hash: u64,
+}
+
+impl ModelOutputCache {
+    /// Open a cache directory, creating it if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - Directory to store the cached model outputs in.
+    /// * `model_path` - Path of the model the outputs are computed with.
+    pub fn new<P: AsRef<Path>, M: AsRef<Path>>(directory: P, model_path: M) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(&directory)?;
+        Ok(ModelOutputCache {
+            directory: directory.as_ref().to_path_buf(),
+            model_hash: hash_file(model_path)?,
+        })
+    }
+
+    fn entry_path(&self, audio_hash: u64) -> PathBuf {
+        self.directory.join(format!("{:016x}-{:016x}.npz", audio_hash, self.model_hash))
+    }
+
+    /// Returns the cached model output for an audio file, if there is one.
+    pub fn get<P: AsRef<Path>>(&self, audio_path: P) -> Result<Option<ModelOutput>, Box<dyn Error>> {
+        self.get_entry(hash_file(audio_path)?)
+    }
+
+    /// Returns the cached model output for the contents of an audio file, if there is one.
+    pub fn get_for_data(&self, audio_data: &[u8]) -> Result<Option<ModelOutput>, Box<dyn Error>> {
+        self.get_entry(hash_reader(audio_data)?)
+    }
+
+    fn get_entry(&self, audio_hash: u64) -> Result<Option<ModelOutput>, Box<dyn Error>> {
+        let entry_path = self.entry_path(audio_hash);
+        if !entry_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(load_model_outputs_npz(entry_path)?))
+    }
+
+    /// Store the model output for an audio file.
+    pub fn insert<P: AsRef<Path>>(&self, audio_path: P, model_output: &ModelOutput) -> Result<(), Box<dyn Error>> {
+        self.insert_entry(hash_file(audio_path)?, model_output)
+    }
+
+    /// Store the model output for the contents of an audio file.
+    pub fn insert_for_data(&self, audio_data: &[u8], model_output: &ModelOutput) -> Result<(), Box<dyn Error>> {
+        self.insert_entry(hash_reader(audio_data)?, model_output)
+    }
+
+    fn insert_entry(&self, audio_hash: u64, model_output: &ModelOutput) -> Result<(), Box<dyn Error>> {
+        let entry_path = self.entry_path(audio_hash);
+
+        // write to a temporary file first so a crash never leaves a truncated entry behind
+        let temporary_path = entry_path.with_extension("npz.tmp");
+        save_model_outputs_npz(&temporary_path, model_output)?;
+        fs::rename(temporary_path, entry_path)?;
+        Ok(())
+    }
+}
+
+/// Run the model on an audio file, or return the cached model output if the same audio file
+/// was processed with the same model before.
+///
+/// # Arguments
+///
+/// * `audio_path` - Path of the audio file.
+/// * `model` - The model session from `load_model`, loaded from the model file the cache was opened with.
+/// * `cache` - Cache to look up and store the model output in.
+///
+/// # Returns
+///
+/// * The model output.
+pub fn run_inference_cached(audio_path: &str, model: &Session, cache: &ModelOutputCache) -> Result<ModelOutput, Box<dyn Error>> {
+    if let Some(model_output) = cache.get(audio_path)? {
+        return Ok(model_output);
+    }
+
+    let model_output = run_inference_with_model(audio_path, model)?;
+    cache.insert(audio_path, &model_output)?;
+    Ok(model_output)
+}
+
+/// 64-bit FNV-1a hash of the contents of a file. Stable across platforms and builds.
+fn hash_file<P: AsRef<Path>>(path: P) -> Result<u64, Box<dyn Error>> {
+    hash_reader(File::open(path)?)
+}
+
+/// 64-bit FNV-1a hash of everything a reader returns.
+fn hash_reader<R: Read>(mut reader: R) -> Result<u64, Box<dyn Error>> {
+    let mut buffer = vec![0u8; 1 << 16];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        for &byte in &buffer[..n] {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use ndarray::Array2;
+
+    use super::{read_model_outputs_npz, read_npy, write_model_outputs_npz, write_npy, ModelOutput, ModelOutputCache};
+
+    fn activations(n_times: usize, n_bins: usize, seed: f32) -> Array2<f32> {
+        Array2::from_shape_fn((n_times, n_bins), |(t, b)| ((t * n_bins + b) as f32 * 0.37 + seed).sin().abs())
+    }
+
+    fn model_output() -> ModelOutput {
+        ModelOutput { contours: activations(7, 264, 0.1), frames: activations(7, 88, 0.2), onsets: activations(7, 88, 0.3), audio_n_samples: 12345 }
+    }
+
+    #[test]
+    fn npy_round_trip() {
+        let array = activations(5, 3, 0.5);
+        let mut npy = vec![];
+        write_npy(&array, &mut npy).unwrap();
+        // the header is padded so the data starts 64-byte aligned
+        assert_eq!((npy.len() - 5 * 3 * 4) % 64, 0);
+        assert_eq!(read_npy(npy.as_slice()).unwrap(), array);
+    }
+
+    #[test]
+    fn npz_round_trip() {
+        let model_output = model_output();
+        let mut npz = Cursor::new(vec![]);
+        write_model_outputs_npz(&mut npz, &model_output).unwrap();
+        npz.set_position(0);
+
+        let read = read_model_outputs_npz(npz).unwrap();
+        assert_eq!(read.contours, model_output.contours);
+        assert_eq!(read.frames, model_output.frames);
+        assert_eq!(read.onsets, model_output.onsets);
+        assert_eq!(read.audio_n_samples, model_output.audio_n_samples);
+    }
+
+    #[test]
+    fn cache_entries_are_kept_per_model() {
+        let dir = std::env::temp_dir().join(format!("basic-pitch-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (model_a, model_b) = (dir.join("a.onnx"), dir.join("b.onnx"));
+        fs::write(&model_a, b"model a").unwrap();
+        fs::write(&model_b, b"model b").unwrap();
+
+        let cache_a = ModelOutputCache::new(dir.join("cache"), &model_a).unwrap();
+        let cache_b = ModelOutputCache::new(dir.join("cache"), &model_b).unwrap();
+        cache_a.insert_for_data(b"audio", &model_output()).unwrap();
+        let cached = cache_a.get_for_data(b"audio").unwrap();
+        let missing = cache_b.get_for_data(b"audio").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(cached.unwrap().frames, model_output().frames);
+        assert!(missing.is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::streaming::StreamEvent;
+
+/// An argument of an Open Sound Control message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OscArgument {
+    Int(i32),
+    Float(f32),
+}
+
+/// Encode an Open Sound Control message.
+///
+/// # Arguments
+///
+/// * `address` - The address pattern, e.g. `/note/on`.
+/// * `arguments` - The arguments of the message.
+///
+/// # Returns
+///
+/// * The message, to send as one UDP packet.
+pub fn encode_osc_message(address: &str, arguments: &[OscArgument]) -> Vec<u8> {
+    // strings end with a null byte and are padded to a multiple of four bytes
+    fn push_string(message: &mut Vec<u8>, string: &str) {
+        message.extend_from_slice(string.as_bytes());
+        message.resize((message.len() / 4 + 1) * 4, 0);
+    }
+
+    let mut message = vec![];
+    push_string(&mut message, address);
+    let type_tags: String = arguments
+        .iter()
+        .map(|argument| match argument {
+            OscArgument::Int(_) => 'i',
+            OscArgument::Float(_) => 'f',
+        })
+        .collect();
+    push_string(&mut message, &format!(",{}", type_tags));
+    for argument in arguments {
+        match argument {
+            OscArgument::Int(value) => message.extend_from_slice(&value.to_be_bytes()),
+            OscArgument::Float(value) => message.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+    message
+}
+
+/// The Open Sound Control message of a note event: `/note/on pitch velocity`, `/note/off pitch` or
+/// `/bend pitch semitones`, with the prefix before each address.
+pub fn stream_event_to_osc(event: &StreamEvent, prefix: &str) -> Vec<u8> {
+    match *event {
+        StreamEvent::NoteOn { pitch, velocity, .. } => encode_osc_message(
+            &format!("{}/note/on", prefix),
+            &[OscArgument::Int(pitch as i32), OscArgument::Int(velocity as i32)],
+        ),
+        StreamEvent::NoteOff { pitch, .. } => encode_osc_message(&format!("{}/note/off", prefix), &[OscArgument::Int(pitch as i32)]),
+        StreamEvent::PitchBend { pitch, bend, .. } => {
+            encode_osc_message(&format!("{}/bend", prefix), &[OscArgument::Int(pitch as i32), OscArgument::Float(bend)])
+        }
+    }
+}
+
+/// Sends note events as Open Sound Control messages over UDP, e.g. to Max/MSP or TouchDesigner.
+#[derive(Debug)]
+pub struct OscSender {
+    socket: UdpSocket,
+    target: SocketAddr,
+    prefix: String,
+}
+
+impl OscSender {
+    /// Create a sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Host and port to send to, e.g. `127.0.0.1:9000`.
+    /// * `prefix` - Added before every address, e.g. `/basic-pitch`. Can be empty.
+    pub fn new(target: &str, prefix: &str) -> io::Result<Self> {
+        let target = target
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("could not resolve '{}'", target)))?;
+        let local_address = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        Ok(OscSender { socket: UdpSocket::bind(local_address)?, target, prefix: prefix.trim_end_matches('/').to_string() })
+    }
+
+    /// Send an event right away.
+    pub fn send(&self, event: &StreamEvent) -> io::Result<()> {
+        self.socket.send_to(&stream_event_to_osc(event, &self.prefix), self.target)?;
+        Ok(())
+    }
+
+    /// Send events at their times, starting now, like a playback of the notes.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - The events in time order, e.g. from `note_events_to_stream_events`.
+    /// * `speed` - Playback speed, 2.0 sends them twice as fast. Must be positive.
+    pub fn replay(&self, events: &[StreamEvent], speed: f32) -> io::Result<()> {
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the speed must be positive, got {}", speed)));
+        }
+        let start = Instant::now();
+        for event in events {
+            let delay = Duration::try_from_secs_f32(event.time().max(0.0) / speed)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("event at {} s: {}", event.time(), e)))?;
+            let due = start + delay;
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+            self.send(event)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use crate::streaming::StreamEvent;
+
+    use super::{encode_osc_message, OscArgument, OscSender};
+
+    #[test]
+    fn messages_are_padded_and_big_endian() {
+        let message = encode_osc_message("/bend", &[OscArgument::Int(60), OscArgument::Float(0.5)]);
+        let mut expected = b"/bend\0\0\0,if\0".to_vec();
+        expected.extend_from_slice(&[0, 0, 0, 60, 0x3f, 0, 0, 0]);
+        assert_eq!(message, expected);
+        // a string of four bytes still gets a null byte
+        assert_eq!(encode_osc_message("/abc", &[]), b"/abc\0\0\0\0,\0\0\0".to_vec());
+    }
+
+    #[test]
+    fn events_are_sent_over_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = OscSender::new(&receiver.local_addr().unwrap().to_string(), "/piano/").unwrap();
+        sender.send(&StreamEvent::NoteOff { time: 1.0, pitch: 64 }).unwrap();
+
+        let mut buffer = [0u8; 64];
+        let n = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"/piano/note/off\0,i\0\0\0\0\0\x40");
+    }
+
+    #[test]
+    fn replay_rejects_speeds_that_are_not_positive() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = OscSender::new(&receiver.local_addr().unwrap().to_string(), "").unwrap();
+        let events = [StreamEvent::NoteOff { time: 1.0, pitch: 64 }];
+        for speed in [0.0, -1.0, f32::NAN] {
+            assert!(sender.replay(&events, speed).is_err(), "speed {}", speed);
+        }
+        // the delay of the event doesn't fit in a duration
+        assert!(sender.replay(&events, 1e-38).is_err());
+    }
+}
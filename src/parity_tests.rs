@@ -0,0 +1,202 @@
+// Parity tests against Python basic-pitch, on the fixtures in test_data/parity written by
+// scripts/generate_parity_fixtures.py. The postprocessing tests start from the Python model
+// outputs, so a difference in the activations doesn't hide or cause a difference further on.
+// A missing fixture fails the tests, regenerate them with the script when the model or the
+// decoding settings change.
+
+use std::{collections::HashMap, error::Error, fs::File, path::Path};
+
+use ndarray::Array2;
+use zip::ZipArchive;
+
+use crate::{
+    constants::N_FRAMES_PER_WINDOW,
+    inference::run_inference,
+    model_outputs::{read_npy, ModelOutput},
+    postprocessing::{
+        helpers::{helpers::get_inferred_onsets, ported::{librosa::model_frame_to_time, numpy::arg_rel_max}},
+        note_event_frames::{add_pitch_bends_to_note_events, output_to_notes_poly, NoteEventFrame},
+    },
+};
+
+// decoding settings of the fixtures, keep in sync with scripts/generate_parity_fixtures.py
+const ONSET_THRESH: f32 = 0.5;
+const FRAME_THRESH: f32 = 0.3;
+const MIN_NOTE_LEN: usize = 11;
+const ENERGY_TOL: usize = 11;
+const N_BINS_TOLERANCE: usize = 25;
+
+/// (fixture name, audio file, whether the audio is resampled to 22050 Hz).
+const FIXTURES: [(&str, &str, bool); 4] = [
+    ("sine_a4", "test_data/parity/sine_a4.wav", false),
+    ("c_major_triad", "test_data/parity/c_major_triad.wav", false),
+    ("vibrato_g4", "test_data/parity/vibrato_g4.wav", false),
+    ("C_major", "test_data/C_major.wav", true),
+];
+
+type Arrays = HashMap<String, Array2<f32>>;
+
+struct Fixture {
+    name: &'static str,
+    audio_path: &'static str,
+    resampled: bool,
+    model_output: ModelOutput,
+    arrays: Arrays,
+}
+
+impl Fixture {
+    fn array(&self, name: &str) -> &Array2<f32> {
+        self.arrays.get(name).unwrap_or_else(|| panic!("{}: missing '{}' array", self.name, name))
+    }
+
+    fn rows(&self, name: &str) -> Vec<Vec<f32>> {
+        rows(self.array(name))
+    }
+
+    fn notes(&self) -> Vec<NoteEventFrame> {
+        self.array("notes").outer_iter().map(|note| NoteEventFrame {
+            start_frame: note[0] as usize,
+            duration_frames: (note[1] - note[0]) as usize,
+            pitch_midi: note[2] as usize,
+            amplitude: note[3],
+            pitch_bends: None,
+            amplitude_envelope: None,
+        }).collect()
+    }
+}
+
+fn rows(array: &Array2<f32>) -> Vec<Vec<f32>> {
+    array.outer_iter().map(|row| row.to_vec()).collect()
+}
+
+fn read_fixture(path: &Path) -> Result<(ModelOutput, Arrays), Box<dyn Error>> {
+    let model_output = crate::model_outputs::load_model_outputs_npz(path)?;
+
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    let mut arrays = HashMap::new();
+    for idx in 0..zip.len() {
+        let file = zip.by_index(idx)?;
+        let Some(name) = file.name().strip_suffix(".npy").map(|name| name.to_string()) else {
+            continue;
+        };
+        if name != "audio_n_samples" {
+            arrays.insert(name, read_npy(file)?);
+        }
+    }
+    Ok((model_output, arrays))
+}
+
+/// All fixtures, panics if one is missing.
+fn fixtures() -> Vec<Fixture> {
+    FIXTURES.iter().map(|&(name, audio_path, resampled)| {
+        let path = Path::new("test_data/parity").join(format!("{}.npz", name));
+        assert!(
+            path.exists(),
+            "{} not found, run `pip install basic-pitch[onnx]` and `python scripts/generate_parity_fixtures.py` and commit the fixtures",
+            path.display()
+        );
+        let (model_output, arrays) = read_fixture(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        Fixture { name, audio_path, resampled, model_output, arrays }
+    }).collect()
+}
+
+fn max_abs_diff(a: &Array2<f32>, b: &Array2<f32>) -> f32 {
+    assert_eq!(a.dim(), b.dim());
+    a.iter().zip(b.iter()).fold(0.0f32, |max, (x, y)| max.max((x - y).abs()))
+}
+
+#[test]
+fn activations_match_python() {
+    for fixture in fixtures() {
+        let output = run_inference(fixture.audio_path).unwrap();
+        let expected = &fixture.model_output;
+        assert_eq!(output.audio_n_samples, expected.audio_n_samples, "{}: audio length", fixture.name);
+
+        for (name, actual, expected) in [
+            ("contours", &output.contours, &expected.contours),
+            ("frames", &output.frames, &expected.frames),
+            ("onsets", &output.onsets, &expected.onsets),
+        ] {
+            if fixture.resampled {
+                // librosa resamples with soxr and we use a sinc resampler, so only the average error is tight
+                let mean_abs_diff = (actual - expected).mapv(f32::abs).mean().unwrap();
+                assert!(mean_abs_diff < 5e-3, "{} {}: mean difference {}", fixture.name, name, mean_abs_diff);
+            } else {
+                let diff = max_abs_diff(actual, expected);
+                assert!(diff < 1e-3, "{} {}: max difference {}", fixture.name, name, diff);
+            }
+        }
+    }
+}
+
+#[test]
+fn inferred_onsets_match_python() {
+    for fixture in fixtures() {
+        let inferred = get_inferred_onsets(&rows(&fixture.model_output.onsets), &rows(&fixture.model_output.frames), 2);
+        let inferred = Array2::from_shape_vec(fixture.model_output.onsets.dim(), inferred.concat()).unwrap();
+        let diff = max_abs_diff(&inferred, fixture.array("inferred_onsets"));
+        assert!(diff < 1e-5, "{}: max difference {}", fixture.name, diff);
+    }
+}
+
+#[test]
+fn rel_max_matches_scipy() {
+    for fixture in fixtures() {
+        let mut peaks = arg_rel_max(&fixture.rows("inferred_onsets"), 1);
+        peaks.sort_unstable();
+        let expected: Vec<(usize, usize)> = fixture.rows("onset_peaks").iter().map(|p| (p[0] as usize, p[1] as usize)).collect();
+        assert_eq!(peaks, expected, "{}", fixture.name);
+    }
+}
+
+#[test]
+fn notes_match_python() {
+    for fixture in fixtures() {
+        let notes = output_to_notes_poly(
+            rows(&fixture.model_output.frames),
+            rows(&fixture.model_output.onsets),
+            ONSET_THRESH,
+            FRAME_THRESH,
+            MIN_NOTE_LEN,
+            true,
+            None,
+            None,
+            true,
+            ENERGY_TOL,
+        );
+        let expected = fixture.notes();
+        assert_eq!(notes.len(), expected.len(), "{}: number of notes", fixture.name);
+
+        // the same order too, the onsets are visited from the last to the first
+        for (note_idx, (note, expected)) in notes.iter().zip(expected.iter()).enumerate() {
+            assert_eq!(
+                (note.start_frame, note.duration_frames, note.pitch_midi),
+                (expected.start_frame, expected.duration_frames, expected.pitch_midi),
+                "{}: note {}", fixture.name, note_idx
+            );
+            assert!((note.amplitude - expected.amplitude).abs() < 1e-5, "{}: amplitude of note {}", fixture.name, note_idx);
+        }
+    }
+}
+
+#[test]
+fn pitch_bends_match_python() {
+    for fixture in fixtures() {
+        let notes = add_pitch_bends_to_note_events(&rows(&fixture.model_output.contours), &fixture.notes(), N_BINS_TOLERANCE);
+        let bends: Vec<f32> = notes.iter().flat_map(|note| note.pitch_bends.clone().unwrap()).collect();
+        assert_eq!(bends, fixture.rows("pitch_bends").concat(), "{}", fixture.name);
+    }
+}
+
+#[test]
+fn frame_times_match_python_within_first_window() {
+    // Python shifts every ANNOT_N_FRAMES frames by a tuned offset, we shift every N_FRAMES_PER_WINDOW
+    // frames by the exact offset, which doesn't drift on long audio. Before the first shift they agree.
+    for fixture in fixtures() {
+        let expected = fixture.rows("frame_times").concat();
+        for (frame, &expected_time) in expected.iter().enumerate().take(N_FRAMES_PER_WINDOW) {
+            let time = model_frame_to_time(frame);
+            assert!((time - expected_time).abs() < 1e-6, "{}: frame {} at {}, expected {}", fixture.name, frame, time, expected_time);
+        }
+    }
+}
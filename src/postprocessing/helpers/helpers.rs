@@ -123,3 +123,57 @@ pub fn gaussian(m: usize, std: f32) -> Vec<f32> {
 pub fn midi_pitch_to_contour_bin(pitch_midi: f32) -> f32 {
     12.0 * CONTOURS_BINS_PER_SEMITONE * (midi_to_hz(pitch_midi) / ANNOTATIONS_BASE_FREQUENCY).log2()
 }
+
+/// Converts a contour bin to a frequency in Hz.
+///
+/// # Arguments
+///
+/// * `contour_bin` - The contour bin, may be fractional.
+///
+/// # Returns
+///
+/// * The corresponding frequency in Hz.
+pub fn contour_bin_to_hz(contour_bin: f32) -> f32 {
+    ANNOTATIONS_BASE_FREQUENCY * 2.0f32.powf(contour_bin / (12.0 * CONTOURS_BINS_PER_SEMITONE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gaussian, get_inferred_onsets, midi_pitch_to_contour_bin};
+
+    #[test]
+    fn gaussian_matches_scipy() {
+        // scipy.signal.windows.gaussian(5, std=1)
+        let expected = [0.13533528, 0.60653066, 1.0, 0.60653066, 0.13533528];
+        for (value, expected) in gaussian(5, 1.0).iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn contour_bins_are_a_third_of_a_semitone() {
+        // the contours start at A0 (MIDI 21) with 3 bins per semitone
+        assert!(midi_pitch_to_contour_bin(21.0).abs() < 1e-4);
+        assert!((midi_pitch_to_contour_bin(60.0) - 117.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn inferred_onsets_follow_frame_increases() {
+        let mut frames = vec![vec![0.0, 0.0]; 10];
+        let mut onsets = vec![vec![0.0, 0.0]; 10];
+        // a rise within the first n_diff frames is ignored, like in Python
+        frames[1][1] = 0.5;
+        for row in frames.iter_mut().skip(5) {
+            row[0] = 0.8;
+        }
+        onsets[8][1] = 0.6;
+
+        let inferred = get_inferred_onsets(&onsets, &frames, 2);
+
+        // the smallest of the one and two frame differences is only positive on the first frame of
+        // the rise, and is rescaled to the largest onset
+        let mut expected = onsets.clone();
+        expected[5][0] = 0.6;
+        assert_eq!(inferred, expected);
+    }
+}
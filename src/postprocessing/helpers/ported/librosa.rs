@@ -1,6 +1,6 @@
 /* PORTED LIBROSA FUNCTIONS */
 
-use crate::constants::{ANNOT_N_FRAMES, AUDIO_SAMPLE_RATE, FFT_HOP, WINDOW_OFFSET};
+use crate::constants::{AUDIO_SAMPLE_RATE, FFT_HOP, N_FRAMES_PER_WINDOW, WINDOW_OFFSET};
 
 /// Converts a frequency in Hz to the corresponding MIDI pitch.
 /// 
@@ -28,6 +28,27 @@ pub fn midi_to_hz(midi: f32) -> f32 {
     440.0 * 2.0f32.powf((midi - 69.0) / 12.0)
 }
 
+/// Describes how the model's unwrapped frames map to time.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameTimeAlignment {
+    /// Number of unwrapped frames that come from a single audio window.
+    pub frames_per_window: usize,
+    /// Time in seconds that the frames of a window span beyond the hop to the next window.
+    pub window_offset_seconds: f32,
+    /// Constant time in seconds added to every frame, for custom alignment.
+    pub time_offset_seconds: f32,
+}
+
+impl Default for FrameTimeAlignment {
+    fn default() -> Self {
+        FrameTimeAlignment {
+            frames_per_window: N_FRAMES_PER_WINDOW,
+            window_offset_seconds: WINDOW_OFFSET,
+            time_offset_seconds: 0.0,
+        }
+    }
+}
+
 /// Converts from the model's "frame" time to seconds.
 /// 
 /// # Arguments
@@ -38,5 +59,83 @@ pub fn midi_to_hz(midi: f32) -> f32 {
 /// 
 /// * The time the frame maps to in seconds.
 pub fn model_frame_to_time(frame: usize) -> f32 {
-    (frame as f32 * FFT_HOP as f32) / AUDIO_SAMPLE_RATE as f32 - WINDOW_OFFSET * (frame as f32 / ANNOT_N_FRAMES as f32).floor()
+    model_frame_to_time_aligned(frame, &FrameTimeAlignment::default())
+}
+
+/// Converts from the model's "frame" time to seconds with a custom alignment.
+/// 
+/// # Arguments
+/// 
+/// * `frame` - The model's "frame".
+/// * `alignment` - How the frames map to time.
+/// 
+/// # Returns
+/// 
+/// * The time the frame maps to in seconds.
+pub fn model_frame_to_time_aligned(frame: usize, alignment: &FrameTimeAlignment) -> f32 {
+    let window_number = (frame / alignment.frames_per_window) as f64;
+    // computed in f64, f32 loses sub-millisecond precision after a few hours
+    let time = (frame as f64 * FFT_HOP as f64) / AUDIO_SAMPLE_RATE as f64
+        - alignment.window_offset_seconds as f64 * window_number
+        + alignment.time_offset_seconds as f64;
+    time as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{concatenate, Array1, Axis};
+
+    use crate::{constants::{ANNOT_N_FRAMES, AUDIO_SAMPLE_RATE, FFT_HOP, HOP_SIZE, N_FRAMES_PER_WINDOW, N_OVERLAPPING_FRAMES, OVERLAP_LEN, WINDOW_OFFSET}, preprocessing::windowed_audio::window_audio_file};
+
+    use super::model_frame_to_time;
+
+    #[test]
+    fn frame_times_follow_librosa_within_first_window() {
+        // Python's model_frames_to_time is librosa.frames_to_time until its first window shift
+        for frame in 0..N_FRAMES_PER_WINDOW {
+            let expected = frame as f32 * FFT_HOP as f32 / AUDIO_SAMPLE_RATE as f32;
+            assert!((model_frame_to_time(frame) - expected).abs() < 1e-6);
+        }
+
+        let first_shifted = N_FRAMES_PER_WINDOW as f32 * FFT_HOP as f32 / AUDIO_SAMPLE_RATE as f32 - WINDOW_OFFSET;
+        assert!((model_frame_to_time(N_FRAMES_PER_WINDOW) - first_shifted).abs() < 1e-6);
+    }
+
+    #[test]
+    fn onsets_do_not_drift_on_long_audio() {
+        // a click every ~0.52 seconds over 20 minutes, off the frame grid
+        let n_samples = AUDIO_SAMPLE_RATE * 60 * 20;
+        let click_interval = AUDIO_SAMPLE_RATE / 2 + 437;
+        let mut audio = Array1::<f32>::zeros(n_samples);
+        for click in (0..n_samples).step_by(click_interval) {
+            audio[click] = 1.0;
+        }
+
+        // window the audio the same way inference does
+        let padding = Array1::zeros(OVERLAP_LEN / 2);
+        let padded_audio = concatenate(Axis(0), &[padding.view(), audio.view()]).unwrap();
+        let n_olap = N_OVERLAPPING_FRAMES / 2;
+
+        let mut max_error = 0.0f32;
+        let mut n_clicks = 0;
+        for (window_idx, (window, _)) in window_audio_file(&padded_audio, HOP_SIZE).enumerate() {
+            for (sample_idx, _) in window.iter().enumerate().filter(|(_, &v)| v > 0.5) {
+                // the frame the model would detect the click in, keeping only the frames that survive unwrapping
+                let window_frame = (sample_idx as f32 / FFT_HOP as f32).round() as usize;
+                if window_frame < n_olap || window_frame >= ANNOT_N_FRAMES - n_olap {
+                    continue;
+                }
+                let frame = window_idx * N_FRAMES_PER_WINDOW + window_frame - n_olap;
+
+                let click_time = (window_idx * HOP_SIZE + sample_idx - OVERLAP_LEN / 2) as f32 / AUDIO_SAMPLE_RATE as f32;
+                max_error = max_error.max((model_frame_to_time(frame) - click_time).abs());
+                n_clicks += 1;
+            }
+        }
+
+        assert!(n_clicks > 2000);
+        // rounding to the nearest frame, so at most half a frame off anywhere in the file
+        let half_frame = 0.5 * FFT_HOP as f32 / AUDIO_SAMPLE_RATE as f32;
+        assert!(max_error <= half_frame + 5e-4, "max onset error {} exceeds half a frame {}", max_error, half_frame);
+    }
 }
@@ -126,7 +126,8 @@ pub fn min_3d_for_axis0(array: &[Vec<Vec<f32>>]) -> Vec<Vec<f32>> {
 /// https://docs.scipy.org/doc/scipy/reference/generated/scipy.signal.argrelmax.html
 ///
 /// Relative extrema are calculated by finding locations where data[n] > data[n+1:n+order+1]
-/// is true.
+/// is true. As in scipy, indices past the edges are clipped to the first and last row, so those
+/// rows are compared with themselves and are never maxima.
 ///
 /// # Arguments
 ///
@@ -142,7 +143,7 @@ pub fn arg_rel_max(array: &[Vec<f32>], order: usize) -> Vec<(usize, usize)> {
     let mut result = Vec::new();
 
     for col in 0..array[0].len() {
-        for row in 0..array.len() {
+        for row in 1..array.len().saturating_sub(1) {
             let mut is_rel_max = true;
 
             for comparison_row in row.saturating_sub(order)..=usize::min(array.len() - 1, row + order) {
@@ -182,4 +183,31 @@ pub fn max_3d_for_axis0(array: &[Vec<Vec<f32>>]) -> Vec<Vec<f32>> {
     }
 
     max_array
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::arg_rel_max;
+
+    fn column(values: &[f32]) -> Vec<Vec<f32>> {
+        values.iter().map(|&v| vec![v]).collect()
+    }
+
+    #[test]
+    fn arg_rel_max_skips_edges_like_scipy() {
+        // scipy.signal.argrelmax([3, 1, 2, 1, 4]) == [2]
+        assert_eq!(arg_rel_max(&column(&[3.0, 1.0, 2.0, 1.0, 4.0]), 1), vec![(2, 0)]);
+        // plateaus are not maxima
+        assert_eq!(arg_rel_max(&column(&[0.0, 1.0, 1.0, 0.0]), 1), vec![]);
+        assert_eq!(arg_rel_max(&column(&[1.0]), 1), vec![]);
+    }
+
+    #[test]
+    fn arg_rel_max_compares_order_neighbours() {
+        // scipy.signal.argrelmax([1, 3, 2, 4, 1, 0, 2], order=2) == [3]
+        assert_eq!(arg_rel_max(&column(&[1.0, 3.0, 2.0, 4.0, 1.0, 0.0, 2.0]), 2), vec![(3, 0)]);
+        // scipy.signal.argrelmax([[0, 0], [2, 0], [1, 3], [0, 0]], axis=0) == ([1, 2], [0, 1])
+        let array = vec![vec![0.0, 0.0], vec![2.0, 0.0], vec![1.0, 3.0], vec![0.0, 0.0]];
+        assert_eq!(arg_rel_max(&array, 1), vec![(1, 0), (2, 1)]);
+    }
+}
@@ -12,12 +12,238 @@ use midly::TrackEventKind;
 use midly::num::u7;
 
 use std::cmp::max_by;
+use std::collections::HashMap;
+use std::error::Error;
 use std::io::Cursor;
 
-use crate::constants::TICKS_PER_BEAT;
+use crate::constants::{CONTOURS_BINS_PER_SEMITONE, TICKS_PER_BEAT};
 
 use super::note_event_times::NoteEventTime;
 
+/// How the amplitude envelope of a note is written to the MIDI output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmplitudeExpression {
+    /// Polyphonic Key Pressure (aftertouch) messages for the key of the note.
+    PolyPressure,
+    /// Control Change messages for the given controller number, e.g. 11 for expression.
+    Controller(u7),
+}
+
+/// How notes are distributed over MIDI channels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelAllocation {
+    /// All notes on the first channel. Pitch bends of overlapping notes affect each other.
+    Single,
+    /// Rotate overlapping notes over `member_channels` channels, starting at the second channel
+    /// and skipping the General MIDI percussion channel, so every note can bend independently. 0 is taken as 1.
+    MultiChannel { member_channels: u8 },
+    /// MIDI Polyphonic Expression lower zone, with the first channel as master channel and
+    /// `member_channels` member channels that notes are rotated over, from 1 to 15. 0 is taken as 1.
+    Mpe { member_channels: u8 },
+}
+
+impl ChannelAllocation {
+    /// Returns the (zero-based) channels notes can be assigned to.
+    pub fn note_channels(&self) -> Vec<u8> {
+        match *self {
+            ChannelAllocation::Single => vec![0],
+            ChannelAllocation::MultiChannel { member_channels } => (1..16u8)
+                .filter(|&channel| channel != 9)
+                .take(member_channels.max(1) as usize)
+                .collect(),
+            ChannelAllocation::Mpe { member_channels } => (1..=member_channels.clamp(1, 15)).collect(),
+        }
+    }
+}
+
+/// Settings for the MIDI output.
+#[derive(Debug, Clone)]
+pub struct MidiOptions {
+    /// If set, follow the amplitude envelope of each note over time with these messages.
+    pub amplitude_expression: Option<AmplitudeExpression>,
+    /// Maximum number of expression messages per second for a single note. If not positive, only the first is written.
+    pub expression_rate_hz: f32,
+    /// Pitch bend sensitivity in semitones, written to the file with RPN 0. 0 is taken as 1.
+    pub pitch_bend_range_semitones: u8,
+    /// How notes are distributed over MIDI channels.
+    pub channel_allocation: ChannelAllocation,
+    /// Program (instrument) for each channel notes are assigned to, in order. Cycled if there are
+    /// fewer programs than channels. If empty, no program changes are written.
+    pub channel_programs: Vec<u7>,
+}
+
+impl Default for MidiOptions {
+    fn default() -> Self {
+        MidiOptions {
+            amplitude_expression: None,
+            expression_rate_hz: 20.0,
+            pitch_bend_range_semitones: 2,
+            channel_allocation: ChannelAllocation::Single,
+            channel_programs: vec![],
+        }
+    }
+}
+
+/// Convert a pitch bend in contour bins to a MIDI pitch bend value.
+///
+/// # Arguments
+///
+/// * `pitch_bend` - Pitch bend as an offset in contour bins, as returned by `add_pitch_bends_to_note_events`.
+/// * `pitch_bend_range_semitones` - Pitch bend sensitivity of the receiving channel in semitones, 0 is taken as 1.
+///
+/// # Returns
+///
+/// * The MIDI pitch bend, clamped to the pitch bend range.
+pub fn pitch_bend_to_midi(pitch_bend: f32, pitch_bend_range_semitones: u8) -> PitchBend {
+    let cents = pitch_bend * 100.0 / CONTOURS_BINS_PER_SEMITONE;
+    PitchBend::from_f32((cents / (pitch_bend_range_semitones.max(1) as f32 * 100.0)).clamp(-1.0, 1.0))
+}
+
+/// Create the Registered Parameter Number messages that set the pitch bend sensitivity of a channel.
+///
+/// # Arguments
+///
+/// * `channel` - MIDI channel to configure.
+/// * `pitch_bend_range_semitones` - Pitch bend sensitivity in semitones, 0 is taken as 1 like in `pitch_bend_to_midi`.
+///
+/// # Returns
+///
+/// * The track events, all with a delta of 0.
+pub fn pitch_bend_range_events(channel: u8, pitch_bend_range_semitones: u8) -> Vec<TrackEvent<'static>> {
+    registered_parameter_events(channel, 0, pitch_bend_range_semitones.clamp(1, 127))
+}
+
+/// Create the MPE Configuration Message that sets up a lower zone with the given number of member channels.
+///
+/// # Arguments
+///
+/// * `member_channels` - Number of member channels in the zone, 0 disables the zone.
+///
+/// # Returns
+///
+/// * The track events, all with a delta of 0.
+pub fn mpe_configuration_events(member_channels: u8) -> Vec<TrackEvent<'static>> {
+    // RPN 6 on the master channel of the lower zone
+    registered_parameter_events(0, 6, member_channels.min(15))
+}
+
+fn registered_parameter_events(channel: u8, parameter: u8, value: u8) -> Vec<TrackEvent<'static>> {
+    // select the RPN, data entry, then deselect with the null RPN
+    [
+        (101, 0),
+        (100, parameter),
+        (6, value),
+        (38, 0),
+        (101, 127),
+        (100, 127),
+    ].iter().map(|&(controller, value)| TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::Controller {
+                controller: u7::new(controller),
+                value: u7::new(value),
+            },
+        },
+    }).collect()
+}
+
+/// Remove the pitch bends of notes that overlap in time, like Python basic-pitch does when all
+/// notes are written to a single channel, where their pitch bends would affect each other.
+///
+/// # Arguments
+///
+/// * `note_events` - List of time-based note events.
+pub fn drop_overlapping_pitch_bends(note_events: &mut [NoteEventTime]) {
+    let mut order: Vec<usize> = (0..note_events.len()).collect();
+    order.sort_by(|&a, &b| note_events[a].start_time_seconds.total_cmp(&note_events[b].start_time_seconds));
+
+    for (n, &i) in order.iter().enumerate() {
+        let end_time = note_events[i].start_time_seconds + note_events[i].duration_seconds;
+        for &j in &order[n + 1..] {
+            if note_events[j].start_time_seconds >= end_time {
+                break;
+            }
+            note_events[i].pitch_bends = None;
+            note_events[j].pitch_bends = None;
+        }
+    }
+}
+
+/// Assign a channel to each note, rotating overlapping notes over the available channels.
+///
+/// # Arguments
+///
+/// * `note_events` - List of time-based note events.
+/// * `channel_allocation` - How notes are distributed over MIDI channels.
+///
+/// # Returns
+///
+/// * The channel for each note event, in the same order as `note_events`.
+pub fn allocate_channels(note_events: &[NoteEventTime], channel_allocation: ChannelAllocation) -> Vec<u8> {
+    let channels = channel_allocation.note_channels();
+    // (end time of the last note on the channel, order in which the channel was last used)
+    let mut channel_state: Vec<(f32, usize)> = vec![(f32::NEG_INFINITY, 0); channels.len()];
+
+    let mut order: Vec<usize> = (0..note_events.len()).collect();
+    order.sort_by(|&a, &b| note_events[a].start_time_seconds.total_cmp(&note_events[b].start_time_seconds));
+
+    let mut allocated = vec![channels[0]; note_events.len()];
+    for (n, &note_idx) in order.iter().enumerate() {
+        let note_event = &note_events[note_idx];
+
+        // Prefer the least recently used free channel, otherwise take the one that frees up first
+        let channel_idx = (0..channels.len())
+            .filter(|&c| channel_state[c].0 <= note_event.start_time_seconds)
+            .min_by_key(|&c| channel_state[c].1)
+            .unwrap_or_else(|| {
+                (0..channels.len())
+                    .min_by(|&a, &b| channel_state[a].0.total_cmp(&channel_state[b].0))
+                    .unwrap()
+            });
+
+        channel_state[channel_idx] = (note_event.start_time_seconds + note_event.duration_seconds, n + 1);
+        allocated[note_idx] = channels[channel_idx];
+    }
+
+    allocated
+}
+
+/// Create the messages that set up the channels before any notes are played.
+///
+/// # Arguments
+///
+/// * `options` - Settings for the MIDI output.
+///
+/// # Returns
+///
+/// * The track events, all with a delta of 0.
+pub fn channel_setup_events(options: &MidiOptions) -> Vec<TrackEvent<'static>> {
+    let mut track_events = vec![];
+
+    if let ChannelAllocation::Mpe { .. } = options.channel_allocation {
+        // the zone has as many member channels as notes are rotated over, a zone of 0 would be disabled
+        track_events.extend(mpe_configuration_events(options.channel_allocation.note_channels().len() as u8));
+    }
+
+    for (i, channel) in options.channel_allocation.note_channels().into_iter().enumerate() {
+        if !options.channel_programs.is_empty() {
+            track_events.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: MidiMessage::ProgramChange {
+                        program: options.channel_programs[i % options.channel_programs.len()],
+                    },
+                },
+            });
+        }
+        track_events.extend(pitch_bend_range_events(channel, options.pitch_bend_range_semitones));
+    }
+
+    track_events
+}
+
 #[derive(Debug, Clone)]
 struct TrackEventAbsolute<'a> {
     tick: u32,
@@ -25,11 +251,14 @@ struct TrackEventAbsolute<'a> {
     is_note_on: Option<bool>
 }
 
-pub fn generate_ordered_midi_events(note_events: Vec<NoteEventTime>, ticks_per_second: f64) -> Vec<TrackEvent<'static>> {
+pub fn generate_ordered_midi_events(note_events: Vec<NoteEventTime>, ticks_per_second: f64, options: &MidiOptions) -> Vec<TrackEvent<'static>> {
     // notes.sort_by(|a, b| b.start_time_seconds.partial_cmp(&a.duration_seconds).unwrap());
 
+    let channels = allocate_channels(&note_events, options.channel_allocation);
+
     let mut track_events_absolute: Vec<TrackEventAbsolute> = vec![];
-    for note_event in note_events {
+    for (note_event, channel) in note_events.into_iter().zip(channels) {
+        let channel = channel.into();
         // NoteOn event
         let start_tick = (note_event.start_time_seconds as f64 * ticks_per_second).round() as u32;
         let velocity = (note_event.amplitude * 127.0).round() as u8;
@@ -37,7 +266,7 @@ pub fn generate_ordered_midi_events(note_events: Vec<NoteEventTime>, ticks_per_s
         track_events_absolute.push(TrackEventAbsolute {
             tick: start_tick,
             kind: TrackEventKind::Midi {
-                channel: 0.into(),
+                channel,
                 message: MidiMessage::NoteOn {
                     key,
                     vel: u7::new(velocity),
@@ -51,7 +280,7 @@ pub fn generate_ordered_midi_events(note_events: Vec<NoteEventTime>, ticks_per_s
         track_events_absolute.push(TrackEventAbsolute {
             tick: end_tick,
             kind: TrackEventKind::Midi {
-                channel: 0.into(),
+                channel,
                 message: MidiMessage::NoteOff {
                     key,
                     vel: u7::new(velocity),
@@ -70,40 +299,96 @@ pub fn generate_ordered_midi_events(note_events: Vec<NoteEventTime>, ticks_per_s
                 track_events_absolute.push(TrackEventAbsolute {
                     tick: bend_tick,
                     kind: TrackEventKind::Midi {
-                        channel: 0.into(),
+                        channel,
                         message: MidiMessage::PitchBend {
-                            bend: PitchBend((pitch_bend as u16 + 0x2000).into()),
+                            bend: pitch_bend_to_midi(pitch_bend, options.pitch_bend_range_semitones),
                         },
                     },
                     is_note_on: None
                 });
             }
+
+            // Reset the bend once the note is released so it doesn't carry over to the next note
+            track_events_absolute.push(TrackEventAbsolute {
+                tick: end_tick,
+                kind: TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::PitchBend {
+                        bend: PitchBend::mid_raw_value(),
+                    },
+                },
+                is_note_on: None
+            });
+        }
+
+        // Amplitude expression
+        if let (Some(expression), Some(envelope)) = (options.amplitude_expression, &note_event.amplitude_envelope) {
+            let min_tick_interval = if options.expression_rate_hz > 0.0 {
+                (ticks_per_second / options.expression_rate_hz as f64).round() as u32
+            } else {
+                u32::MAX
+            };
+            let mut last_emitted: Option<(u32, u8)> = None;
+            for (i, &amplitude) in envelope.iter().enumerate() {
+                let expression_tick = (((i as f32 * note_event.duration_seconds) / envelope.len() as f32) * ticks_per_second as f32) as u32 + start_tick + 1;
+                if expression_tick >= end_tick {
+                    break;
+                }
+
+                let value = (amplitude.clamp(0.0, 1.0) * 127.0).round() as u8;
+                if let Some((last_tick, last_value)) = last_emitted {
+                    if value == last_value || expression_tick < last_tick.saturating_add(min_tick_interval) {
+                        continue;
+                    }
+                }
+                last_emitted = Some((expression_tick, value));
+
+                let message = match expression {
+                    // In MPE every note has its own channel, so pressure is sent as channel pressure
+                    AmplitudeExpression::PolyPressure if matches!(options.channel_allocation, ChannelAllocation::Mpe { .. }) => MidiMessage::ChannelAftertouch {
+                        vel: u7::new(value),
+                    },
+                    AmplitudeExpression::PolyPressure => MidiMessage::Aftertouch {
+                        key,
+                        vel: u7::new(value),
+                    },
+                    AmplitudeExpression::Controller(controller) => MidiMessage::Controller {
+                        controller,
+                        value: u7::new(value),
+                    },
+                };
+                track_events_absolute.push(TrackEventAbsolute {
+                    tick: expression_tick,
+                    kind: TrackEventKind::Midi {
+                        channel,
+                        message,
+                    },
+                    is_note_on: None
+                });
+            }
         }
     }
 
-    track_events_absolute.sort_by(|a, b| a.tick.cmp(&b.tick));
+    // On the same tick, NoteOffs and pitch bend resets go before NoteOns. MIDI doesn't like it when a note is
+    // pressed again before it was let go, and a reset after the NoteOn would cancel the bend of the new note.
+    track_events_absolute.sort_by_key(|event| {
+        let order = match event.is_note_on {
+            Some(false) => 0,
+            None => 1,
+            Some(true) => 2,
+        };
+        (event.tick, order)
+    });
 
     let mut track_events = vec![];
 
     let mut i = 0;
     while i < track_events_absolute.len() {
         let track_event_absolute = &track_events_absolute[i];
-        let mut delta = if i == 0 { track_event_absolute.tick } else {
+        let delta = if i == 0 { track_event_absolute.tick } else {
             track_event_absolute.tick - track_events_absolute[i-1].tick
         };
 
-        if track_event_absolute.is_note_on.is_some_and(|v| v == true) {
-            // Check if the next event is a NoteOff and it's on the same tick, if so, we want to add that one first. MIDI doesn't like it when a note is pressed again before it was let go.
-            if i + 1 < track_events_absolute.len() && track_events_absolute[i+1].is_note_on.is_some_and(|v| v == false) && track_events_absolute[i+1].tick == track_event_absolute.tick {
-                track_events.push(TrackEvent {
-                    delta: delta.into(),
-                    kind: track_events_absolute[i+1].kind
-                });
-                delta = 0;
-                i += 1;
-            }
-        }
-
         track_events.push(TrackEvent {
             delta: delta.into(),
             kind: track_event_absolute.kind
@@ -120,11 +405,13 @@ pub fn generate_ordered_midi_events(note_events: Vec<NoteEventTime>, ticks_per_s
 /// # Arguments
 ///
 /// * `notes` - List of time-based note events.
+/// * `beats_per_minute` - Tempo of the MIDI file.
+/// * `options` - Additional settings for the MIDI output.
 ///
 /// # Returns
 ///
 /// * A vector of bytes representing the MIDI file.
-pub fn generate_midi_file_data(notes: &[NoteEventTime], beats_per_minute: u32) -> Vec<u8> {
+pub fn generate_midi_file_data(notes: &[NoteEventTime], beats_per_minute: u32, options: &MidiOptions) -> Vec<u8> {
     let timing = Timing::Metrical(TICKS_PER_BEAT.into());
     let ticks_per_second = (TICKS_PER_BEAT as f64) * (beats_per_minute as f64) / 60.0;
 
@@ -143,7 +430,12 @@ pub fn generate_midi_file_data(notes: &[NoteEventTime], beats_per_minute: u32) -
         kind: TrackEventKind::Meta(MetaMessage::Tempo((60_000_000 / beats_per_minute).into()))
     });
 
-    let track_events = generate_ordered_midi_events(notes.to_vec().clone(), ticks_per_second);
+    // Set up the channels so the bends are scaled correctly on playback
+    for track_event in channel_setup_events(options) {
+        track.push(track_event)
+    }
+
+    let track_events = generate_ordered_midi_events(notes.to_vec().clone(), ticks_per_second, options);
     for track_event in track_events {
         track.push(track_event)
     }
@@ -155,3 +447,270 @@ pub fn generate_midi_file_data(notes: &[NoteEventTime], beats_per_minute: u32) -
 
     buffer
 }
+
+/// Read note events from MIDI file data.
+///
+/// All tracks and channels are merged, tempo changes are taken into account. Pitch bends are not read.
+///
+/// # Arguments
+///
+/// * `data` - The bytes of a Standard MIDI File.
+///
+/// # Returns
+///
+/// * List of time-based note events sorted by start time, with the amplitude derived from the velocity.
+pub fn read_midi_file_data(data: &[u8]) -> Result<Vec<NoteEventTime>, Box<dyn Error>> {
+    let smf = Smf::parse(data)?;
+
+    // (absolute tick, track index, event) of all tracks merged
+    let mut events = vec![];
+    for (track_idx, track) in smf.tracks.iter().enumerate() {
+        let mut tick: u64 = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, track_idx, event.kind));
+        }
+    }
+    events.sort_by_key(|&(tick, track_idx, _)| (tick, track_idx));
+
+    let (ticks_per_beat, fixed_seconds_per_tick) = match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => (ticks_per_beat.as_int() as f64, None),
+        Timing::Timecode(fps, subframes) => (1.0, Some(1.0 / (fps.as_f32() as f64 * subframes as f64))),
+    };
+    let mut seconds_per_tick = fixed_seconds_per_tick.unwrap_or(0.5 / ticks_per_beat); // 120 BPM until the first tempo event
+    let mut last_tick = 0;
+    let mut seconds = 0.0;
+
+    // started notes per (channel, key), in the order they were started
+    let mut active_notes: HashMap<(u8, u8), Vec<(f64, u8)>> = HashMap::new();
+    let mut notes = vec![];
+
+    for (tick, _, kind) in events {
+        seconds += (tick - last_tick) as f64 * seconds_per_tick;
+        last_tick = tick;
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(microseconds_per_beat)) if fixed_seconds_per_tick.is_none() => {
+                seconds_per_tick = microseconds_per_beat.as_int() as f64 / 1_000_000.0 / ticks_per_beat;
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let (key, is_note_on, vel) = match message {
+                    MidiMessage::NoteOn { key, vel } => (key.as_int(), vel.as_int() > 0, vel.as_int()),
+                    MidiMessage::NoteOff { key, vel } => (key.as_int(), false, vel.as_int()),
+                    _ => continue,
+                };
+
+                let started = active_notes.entry((channel.as_int(), key)).or_default();
+                if is_note_on {
+                    started.push((seconds, vel));
+                } else if !started.is_empty() {
+                    let (start_seconds, velocity) = started.remove(0);
+                    notes.push(NoteEventTime {
+                        start_time_seconds: start_seconds as f32,
+                        duration_seconds: (seconds - start_seconds) as f32,
+                        pitch_midi: key as usize,
+                        amplitude: velocity as f32 / 127.0,
+                        pitch_bends: None,
+                        amplitude_envelope: None,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    notes.sort_by(|a, b| a.start_time_seconds.partial_cmp(&b.start_time_seconds).unwrap());
+    Ok(notes)
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{num::u7, MidiMessage, TrackEvent, TrackEventKind};
+
+    use crate::postprocessing::note_event_times::NoteEventTime;
+
+    use midly::Smf;
+
+    use crate::constants::CONTOURS_BINS_PER_SEMITONE;
+
+    use super::{
+        allocate_channels, channel_setup_events, drop_overlapping_pitch_bends, generate_midi_file_data, generate_ordered_midi_events,
+        AmplitudeExpression, ChannelAllocation, MidiOptions,
+    };
+
+    // 120 BPM
+    const TICKS_PER_SECOND: f64 = 960.0;
+
+    /// The MIDI messages with their absolute tick and channel.
+    fn absolute_messages(track_events: &[TrackEvent]) -> Vec<(u32, u8, MidiMessage)> {
+        let mut tick = 0;
+        track_events
+            .iter()
+            .filter_map(|event| {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Midi { channel, message } => Some((tick, channel.as_int(), message)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn note(start_time_seconds: f32, duration_seconds: f32, pitch_midi: usize) -> NoteEventTime {
+        NoteEventTime {
+            start_time_seconds,
+            duration_seconds,
+            pitch_midi,
+            amplitude: 0.8,
+            pitch_bends: None,
+            amplitude_envelope: None,
+        }
+    }
+
+    #[test]
+    fn amplitude_envelopes_follow_the_expression_rate() {
+        // a ramp that changes every frame and overshoots 1.0 at the end
+        let envelope: Vec<f32> = (0..100).map(|i| i as f32 / 80.0).collect();
+        let notes = vec![NoteEventTime { amplitude_envelope: Some(envelope), ..note(0.5, 1.0, 60) }];
+
+        for expression in [AmplitudeExpression::PolyPressure, AmplitudeExpression::Controller(u7::new(11))] {
+            let options = MidiOptions { amplitude_expression: Some(expression), expression_rate_hz: 10.0, ..MidiOptions::default() };
+            let values: Vec<(u32, u8)> = absolute_messages(&generate_ordered_midi_events(notes.clone(), TICKS_PER_SECOND, &options))
+                .into_iter()
+                .filter_map(|(tick, _, message)| match (expression, message) {
+                    (AmplitudeExpression::PolyPressure, MidiMessage::Aftertouch { key, vel }) => {
+                        assert_eq!(key.as_int(), 60);
+                        Some((tick, vel.as_int()))
+                    }
+                    (AmplitudeExpression::Controller(_), MidiMessage::Controller { controller, value }) => {
+                        assert_eq!(controller.as_int(), 11);
+                        Some((tick, value.as_int()))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            // at most 10 messages per second of the note, all within the note
+            assert!((9..=10).contains(&values.len()), "{} messages", values.len());
+            assert!(values.windows(2).all(|pair| pair[1].0 - pair[0].0 >= 96));
+            assert!(values.iter().all(|&(tick, value)| (481..1440).contains(&tick) && value <= 127));
+            assert_eq!(values.last().unwrap().1, 127);
+        }
+    }
+
+    #[test]
+    fn an_expression_rate_that_is_not_positive_writes_one_message_per_note() {
+        let envelope: Vec<f32> = (0..100).map(|i| i as f32 / 100.0).collect();
+        let notes = vec![NoteEventTime { amplitude_envelope: Some(envelope), ..note(0.5, 1.0, 60) }];
+
+        for expression_rate_hz in [0.0, 1e-9, -5.0, f32::NAN] {
+            let options = MidiOptions { amplitude_expression: Some(AmplitudeExpression::PolyPressure), expression_rate_hz, ..MidiOptions::default() };
+            let messages = absolute_messages(&generate_ordered_midi_events(notes.clone(), TICKS_PER_SECOND, &options));
+            let n_expression = messages.iter().filter(|(_, _, message)| matches!(message, MidiMessage::Aftertouch { .. })).count();
+            assert_eq!(n_expression, 1, "rate {}", expression_rate_hz);
+        }
+    }
+
+    #[test]
+    fn pitch_bends_are_scaled_to_the_bend_range() {
+        // +3 semitones for the first half of the note, -0.5 for the second
+        let bends = [3.0 * CONTOURS_BINS_PER_SEMITONE, -0.5 * CONTOURS_BINS_PER_SEMITONE];
+        let notes = vec![NoteEventTime { pitch_bends: Some(bends.to_vec()), ..note(0.0, 1.0, 60) }];
+
+        for (range, expected_range) in [(12, 12), (2, 2), (0, 1)] {
+            let options = MidiOptions { pitch_bend_range_semitones: range, ..MidiOptions::default() };
+            let data = generate_midi_file_data(&notes, 120, &options);
+            let smf = Smf::parse(&data).unwrap();
+            let messages = absolute_messages(&smf.tracks[0]);
+
+            // RPN 0 selected, the range as data entry, then the null RPN
+            let controllers: Vec<(u8, u8)> = messages
+                .iter()
+                .filter_map(|&(_, _, message)| match message {
+                    MidiMessage::Controller { controller, value } => Some((controller.as_int(), value.as_int())),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(controllers, vec![(101, 0), (100, 0), (6, expected_range), (38, 0), (101, 127), (100, 127)]);
+
+            let semitones: Vec<f32> = messages
+                .iter()
+                .filter_map(|&(_, _, message)| match message {
+                    MidiMessage::PitchBend { bend } => Some(bend.as_f32() * expected_range as f32),
+                    _ => None,
+                })
+                .collect();
+            // bends beyond the range are clamped, the last message resets the bend
+            let expected = [3.0f32.min(expected_range as f32), -0.5, 0.0];
+            assert_eq!(semitones.len(), expected.len());
+            for (semitones, expected) in semitones.iter().zip(expected) {
+                assert!((semitones - expected).abs() < 0.01, "range {}: {} != {}", range, semitones, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_notes_get_separate_channels() {
+        let notes = vec![note(0.0, 1.0, 60), note(0.5, 1.0, 64), note(1.2, 0.5, 67), note(f32::NAN, 0.5, 72), note(2.0, 0.5, 60)];
+        let channels = allocate_channels(&notes, ChannelAllocation::MultiChannel { member_channels: 3 });
+        // the third note takes the channel that wasn't used yet, the last one the least recently used
+        assert_eq!(&channels[..3], &[1, 2, 3]);
+        assert_eq!(channels[4], 1);
+        assert!(allocate_channels(&notes, ChannelAllocation::Single).iter().all(|&channel| channel == 0));
+        // MPE member channels follow the master channel, without skipping the percussion channel
+        let mpe_channels = allocate_channels(&notes, ChannelAllocation::Mpe { member_channels: 15 });
+        assert!(mpe_channels.iter().all(|&channel| (1..=15).contains(&channel)));
+    }
+
+    #[test]
+    fn mpe_zone_is_set_up_before_the_member_channels() {
+        let options = MidiOptions { channel_allocation: ChannelAllocation::Mpe { member_channels: 3 }, ..MidiOptions::default() };
+        let messages = absolute_messages(&channel_setup_events(&options));
+        let controllers: Vec<(u8, u8, u8)> = messages
+            .iter()
+            .filter_map(|&(_, channel, message)| match message {
+                MidiMessage::Controller { controller, value } => Some((channel, controller.as_int(), value.as_int())),
+                _ => None,
+            })
+            .collect();
+        // RPN 6 on the master channel with the number of member channels, then RPN 0 on every member channel
+        assert_eq!(&controllers[..3], &[(0, 101, 0), (0, 100, 6), (0, 6, 3)]);
+        for channel in 1..=3 {
+            assert!(controllers.windows(3).any(|w| w == [(channel, 101, 0), (channel, 100, 0), (channel, 6, 2)]));
+        }
+        assert_eq!(controllers.len(), 4 * 6);
+    }
+
+    #[test]
+    fn an_mpe_zone_without_member_channels_gets_one() {
+        let options = MidiOptions { channel_allocation: ChannelAllocation::Mpe { member_channels: 0 }, ..MidiOptions::default() };
+        let messages = absolute_messages(&channel_setup_events(&options));
+        assert!(messages.iter().any(|&(_, channel, message)| channel == 0
+            && matches!(message, MidiMessage::Controller { controller, value } if controller.as_int() == 6 && value.as_int() == 1)));
+        assert_eq!(allocate_channels(&[note(0.0, 1.0, 60), note(0.5, 1.0, 64)], options.channel_allocation), vec![1, 1]);
+    }
+
+    #[test]
+    fn bend_reset_is_written_before_the_next_note_on() {
+        // the second note starts on the same tick and channel where the first ends, listed first
+        let bent = NoteEventTime { pitch_bends: Some(vec![1.0, 2.0]), ..note(0.0, 0.5, 60) };
+        let notes = vec![note(0.5, 0.5, 62), bent];
+        let messages = absolute_messages(&generate_ordered_midi_events(notes, TICKS_PER_SECOND, &MidiOptions::default()));
+
+        let at_480: Vec<MidiMessage> = messages.iter().filter(|&&(tick, _, _)| tick == 480).map(|&(_, _, message)| message).collect();
+        assert_eq!(at_480.len(), 3);
+        assert!(matches!(at_480[0], MidiMessage::NoteOff { .. }));
+        assert!(matches!(at_480[1], MidiMessage::PitchBend { bend } if bend == midly::PitchBend::mid_raw_value()));
+        assert!(matches!(at_480[2], MidiMessage::NoteOn { key, .. } if key.as_int() == 62));
+    }
+
+    #[test]
+    fn only_overlapping_notes_lose_their_pitch_bends() {
+        let bent = |start, duration, pitch| NoteEventTime { pitch_bends: Some(vec![1.0]), ..note(start, duration, pitch) };
+        let mut notes = vec![bent(0.0, 1.0, 60), bent(0.9, 1.0, 64), bent(2.0, 0.5, 67), bent(1.9, 0.1, 72)];
+        drop_overlapping_pitch_bends(&mut notes);
+        // the last two notes start exactly when the note before them ends, so they don't overlap it
+        let kept: Vec<bool> = notes.iter().map(|note| note.pitch_bends.is_some()).collect();
+        assert_eq!(kept, vec![false, false, true, true]);
+    }
+}
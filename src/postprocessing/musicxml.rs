@@ -0,0 +1,310 @@
+use std::{error::Error, fmt::Write};
+
+use super::note_event_times::NoteEventTime;
+
+const STEPS: [(&str, i8); 12] = [
+    ("C", 0), ("C", 1), ("D", 0), ("D", 1), ("E", 0), ("F", 0),
+    ("F", 1), ("G", 0), ("G", 1), ("A", 0), ("A", 1), ("B", 0),
+];
+
+/// Note types with their length relative to a quarter note, largest first.
+const NOTE_TYPES: [(&str, f32); 8] = [
+    ("whole", 4.0), ("half", 2.0), ("quarter", 1.0), ("eighth", 0.5),
+    ("16th", 0.25), ("32nd", 0.125), ("64th", 0.0625), ("128th", 0.03125),
+];
+
+/// Settings for the MusicXML output.
+#[derive(Debug, Clone)]
+pub struct MusicXmlOptions {
+    /// Time signature as (beats, beat type), e.g. (3, 4) for three quarter notes per measure.
+    /// Both must be positive and the beat type a power of two of at most 128.
+    pub time_signature: (u32, u32),
+    /// Quantization grid in divisions per quarter note, e.g. 4 for sixteenth notes.
+    /// Rounded up to a power of two, at most 32.
+    pub divisions_per_quarter: u32,
+    /// Notes with a MIDI pitch at or above this go on the treble staff, the others on the bass staff.
+    pub split_pitch_midi: usize,
+}
+
+impl Default for MusicXmlOptions {
+    fn default() -> Self {
+        MusicXmlOptions {
+            time_signature: (4, 4),
+            divisions_per_quarter: 4,
+            split_pitch_midi: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct QuantizedNote {
+    start: u32,
+    end: u32,
+    pitch_midi: usize,
+}
+
+/// A stretch of time on a staff in which the same pitches sound, or a rest if there are none.
+#[derive(Debug, Clone)]
+struct Segment {
+    start: u32,
+    end: u32,
+    // (pitch, tied to the previous segment, tied to the next segment)
+    pitches: Vec<(usize, bool, bool)>,
+}
+
+/// Generate a MusicXML score from note events.
+///
+/// Onsets and durations are quantized to the grid, notes are split and tied across barlines and
+/// wherever another note starts or ends on the same staff, and assigned to a treble or bass staff by pitch.
+///
+/// # Arguments
+///
+/// * `notes` - List of time-based note events.
+/// * `beats_per_minute` - Tempo in quarter notes per minute.
+/// * `options` - Additional settings for the MusicXML output.
+///
+/// # Returns
+///
+/// * The MusicXML document as a string, or an error if the time signature is invalid.
+pub fn generate_musicxml(notes: &[NoteEventTime], beats_per_minute: u32, options: &MusicXmlOptions) -> Result<String, Box<dyn Error>> {
+    let (beats, beat_type) = options.time_signature;
+    // a 128th note is the shortest notated duration, so a beat type above 128 can't be written
+    if beats == 0 || !beat_type.is_power_of_two() || beat_type > 128 {
+        return Err(format!("invalid time signature {}/{}, expected a positive number of beats and a power of two beat type of at most 128", beats, beat_type).into());
+    }
+    let divisions = options.divisions_per_quarter.clamp(1, 32).next_power_of_two().max(beat_type / 4);
+    let measure_length = beats
+        .checked_mul(4 * divisions)
+        .ok_or_else(|| format!("invalid time signature {}/{}, too many beats", beats, beat_type))?
+        / beat_type;
+    let divisions_per_second = divisions as f32 * beats_per_minute as f32 / 60.0;
+
+    let quantized: Vec<QuantizedNote> = notes.iter().map(|note| {
+        let start = (note.start_time_seconds.max(0.0) * divisions_per_second).round() as u32;
+        let end = ((note.start_time_seconds + note.duration_seconds).max(0.0) * divisions_per_second).round() as u32;
+        QuantizedNote { start, end: end.max(start + 1), pitch_midi: note.pitch_midi }
+    }).collect();
+
+    let total_length = quantized.iter().map(|note| note.end).max().unwrap_or(0);
+    let n_measures = total_length.div_ceil(measure_length).max(1);
+
+    let (treble, bass): (Vec<QuantizedNote>, Vec<QuantizedNote>) = quantized
+        .into_iter()
+        .partition(|note| note.pitch_midi >= options.split_pitch_midi);
+    let staves = [staff_segments(&treble, measure_length, n_measures), staff_segments(&bass, measure_length, n_measures)];
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    xml.push_str("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n");
+    xml.push_str("<score-partwise version=\"4.0\">\n");
+    xml.push_str("  <part-list>\n    <score-part id=\"P1\">\n      <part-name>Transcription</part-name>\n    </score-part>\n  </part-list>\n");
+    xml.push_str("  <part id=\"P1\">\n");
+
+    for measure in 0..n_measures {
+        writeln!(xml, "    <measure number=\"{}\">", measure + 1).unwrap();
+        if measure == 0 {
+            writeln!(
+                xml,
+                "      <attributes>\n        <divisions>{}</divisions>\n        <key><fifths>0</fifths></key>\n        <time><beats>{}</beats><beat-type>{}</beat-type></time>\n        <staves>2</staves>\n        <clef number=\"1\"><sign>G</sign><line>2</line></clef>\n        <clef number=\"2\"><sign>F</sign><line>4</line></clef>\n      </attributes>",
+                divisions, beats, beat_type
+            ).unwrap();
+            writeln!(
+                xml,
+                "      <direction placement=\"above\">\n        <direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>{}</per-minute></metronome></direction-type>\n        <staff>1</staff>\n        <sound tempo=\"{}\"/>\n      </direction>",
+                beats_per_minute, beats_per_minute
+            ).unwrap();
+        }
+
+        let measure_start = measure * measure_length;
+        let measure_end = measure_start + measure_length;
+        for (staff_idx, segments) in staves.iter().enumerate() {
+            if staff_idx > 0 {
+                writeln!(xml, "      <backup><duration>{}</duration></backup>", measure_length).unwrap();
+            }
+            let staff = staff_idx + 1;
+            let voice = if staff == 1 { 1 } else { 5 };
+            for segment in segments.iter().filter(|s| s.start >= measure_start && s.end <= measure_end) {
+                write_segment(&mut xml, segment, divisions, staff, voice);
+            }
+        }
+
+        xml.push_str("    </measure>\n");
+    }
+
+    xml.push_str("  </part>\n</score-partwise>\n");
+    Ok(xml)
+}
+
+/// Slice the notes of a staff at every note boundary and barline.
+fn staff_segments(notes: &[QuantizedNote], measure_length: u32, n_measures: u32) -> Vec<Segment> {
+    let mut boundaries: Vec<u32> = (0..=n_measures).map(|m| m * measure_length).collect();
+    for note in notes {
+        boundaries.push(note.start);
+        boundaries.push(note.end);
+    }
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    boundaries.windows(2).map(|bounds| {
+        let (start, end) = (bounds[0], bounds[1]);
+        let mut pitches: Vec<(usize, bool, bool)> = vec![];
+        for note in notes.iter().filter(|note| note.start <= start && note.end > start) {
+            let tie = (note.pitch_midi, note.start < start, note.end > end);
+            match pitches.iter_mut().find(|(pitch, _, _)| *pitch == note.pitch_midi) {
+                // the same pitch twice on a staff, merge them
+                Some(existing) => *existing = (existing.0, existing.1 || tie.1, existing.2 || tie.2),
+                None => pitches.push(tie),
+            }
+        }
+        pitches.sort_by_key(|&(pitch, _, _)| pitch);
+        Segment { start, end, pitches }
+    }).collect()
+}
+
+/// Split a duration into notated durations (type, dotted, length in divisions), largest first.
+fn notated_durations(mut duration: u32, divisions: u32) -> Vec<(&'static str, bool, u32)> {
+    let mut candidates: Vec<(&'static str, bool, u32)> = vec![];
+    for &(name, quarters) in NOTE_TYPES.iter() {
+        let length = quarters * divisions as f32;
+        if length.fract() != 0.0 || length < 1.0 {
+            continue;
+        }
+        let dotted = length * 1.5;
+        if dotted.fract() == 0.0 {
+            candidates.push((name, true, dotted as u32));
+        }
+        candidates.push((name, false, length as u32));
+    }
+
+    let mut durations = vec![];
+    while duration > 0 {
+        let &candidate = candidates.iter().find(|&&(_, _, length)| length <= duration).unwrap();
+        durations.push(candidate);
+        duration -= candidate.2;
+    }
+    durations
+}
+
+fn write_segment(xml: &mut String, segment: &Segment, divisions: u32, staff: usize, voice: usize) {
+    let pieces = notated_durations(segment.end - segment.start, divisions);
+    for (piece_idx, &(note_type, dotted, length)) in pieces.iter().enumerate() {
+        if segment.pitches.is_empty() {
+            writeln!(
+                xml,
+                "      <note>\n        <rest/>\n        <duration>{}</duration>\n        <voice>{}</voice>\n        <type>{}</type>\n{}        <staff>{}</staff>\n      </note>",
+                length, voice, note_type, if dotted { "        <dot/>\n" } else { "" }, staff
+            ).unwrap();
+            continue;
+        }
+
+        for (pitch_idx, &(pitch_midi, tied_before, tied_after)) in segment.pitches.iter().enumerate() {
+            let tie_stop = tied_before || piece_idx > 0;
+            let tie_start = tied_after || piece_idx + 1 < pieces.len();
+            let (step, alter) = STEPS[pitch_midi % 12];
+            let octave = (pitch_midi / 12) as i32 - 1;
+
+            xml.push_str("      <note>\n");
+            if pitch_idx > 0 {
+                xml.push_str("        <chord/>\n");
+            }
+            xml.push_str("        <pitch>\n");
+            writeln!(xml, "          <step>{}</step>", step).unwrap();
+            if alter != 0 {
+                writeln!(xml, "          <alter>{}</alter>", alter).unwrap();
+            }
+            writeln!(xml, "          <octave>{}</octave>", octave).unwrap();
+            xml.push_str("        </pitch>\n");
+            writeln!(xml, "        <duration>{}</duration>", length).unwrap();
+            if tie_stop {
+                xml.push_str("        <tie type=\"stop\"/>\n");
+            }
+            if tie_start {
+                xml.push_str("        <tie type=\"start\"/>\n");
+            }
+            writeln!(xml, "        <voice>{}</voice>", voice).unwrap();
+            writeln!(xml, "        <type>{}</type>", note_type).unwrap();
+            if dotted {
+                xml.push_str("        <dot/>\n");
+            }
+            writeln!(xml, "        <staff>{}</staff>", staff).unwrap();
+            if tie_stop || tie_start {
+                xml.push_str("        <notations>\n");
+                if tie_stop {
+                    xml.push_str("          <tied type=\"stop\"/>\n");
+                }
+                if tie_start {
+                    xml.push_str("          <tied type=\"start\"/>\n");
+                }
+                xml.push_str("        </notations>\n");
+            }
+            xml.push_str("      </note>\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::postprocessing::note_event_times::NoteEventTime;
+
+    use super::{generate_musicxml, MusicXmlOptions};
+
+    fn note(start_time_seconds: f32, duration_seconds: f32, pitch_midi: usize) -> NoteEventTime {
+        NoteEventTime { start_time_seconds, duration_seconds, pitch_midi, amplitude: 0.8, pitch_bends: None, amplitude_envelope: None }
+    }
+
+    /// (step, octave, staff, tie stop, tie start)
+    type WrittenNote = (String, i32, usize, bool, bool);
+
+    /// The pitched notes of each measure.
+    fn measure_notes(xml: &str) -> Vec<Vec<WrittenNote>> {
+        let tag = |chunk: &str, name: &str| -> String {
+            let open = format!("<{}>", name);
+            let start = chunk.find(&open).unwrap() + open.len();
+            chunk[start..start + chunk[start..].find('<').unwrap()].to_string()
+        };
+        xml.split("<measure ").skip(1).map(|measure| {
+            measure.split("<note>").skip(1).filter(|chunk| chunk.contains("<pitch>")).map(|chunk| (
+                tag(chunk, "step"),
+                tag(chunk, "octave").parse().unwrap(),
+                tag(chunk, "staff").parse().unwrap(),
+                chunk.contains("<tie type=\"stop\"/>"),
+                chunk.contains("<tie type=\"start\"/>"),
+            )).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn notes_are_tied_across_barlines() {
+        // 4/4 at 60 bpm: a measure is 4 seconds, C5 runs from beat 4 of the first measure to beat 1 of the second
+        let xml = generate_musicxml(&[note(3.0, 2.0, 72)], 60, &MusicXmlOptions::default()).unwrap();
+        let measures = measure_notes(&xml);
+        assert_eq!(measures.len(), 2);
+        assert_eq!(measures[0], vec![("C".to_string(), 5, 1, false, true)]);
+        assert_eq!(measures[1], vec![("C".to_string(), 5, 1, true, false)]);
+    }
+
+    #[test]
+    fn notes_are_split_between_treble_and_bass_staves() {
+        let notes = [note(0.0, 1.0, 64), note(0.0, 1.0, 60), note(0.0, 1.0, 59), note(1.0, 1.0, 43)];
+        let xml = generate_musicxml(&notes, 60, &MusicXmlOptions::default()).unwrap();
+        let measures = measure_notes(&xml);
+        assert_eq!(measures, vec![vec![
+            ("C".to_string(), 4, 1, false, false),
+            ("E".to_string(), 4, 1, false, false),
+            ("B".to_string(), 3, 2, false, false),
+            ("G".to_string(), 2, 2, false, false),
+        ]]);
+    }
+
+    #[test]
+    fn invalid_time_signatures_are_errors() {
+        for time_signature in [(0, 4), (4, 0), (3, 6), (3, 256), (1 << 30, 4)] {
+            let options = MusicXmlOptions { time_signature, ..MusicXmlOptions::default() };
+            assert!(generate_musicxml(&[note(0.0, 1.0, 60)], 120, &options).is_err());
+        }
+        // the largest beat type still writes a one-division note, as a 128th
+        let options = MusicXmlOptions { time_signature: (3, 128), ..MusicXmlOptions::default() };
+        let xml = generate_musicxml(&[note(0.0, 0.01, 60)], 120, &options).unwrap();
+        assert!(xml.contains("<type>128th</type>"));
+    }
+}
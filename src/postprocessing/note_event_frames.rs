@@ -1,14 +1,80 @@
-use crate::constants::{MAX_FREQ_IDX, MIDI_OFFSET, N_FREQ_BINS_CONTOURS};
+use crate::constants::{AUDIO_SAMPLE_RATE, FFT_HOP, MAX_FREQ_IDX, MIDI_OFFSET, N_FREQ_BINS_CONTOURS};
 
 use super::helpers::{helpers::{constrain_frequency, gaussian, get_inferred_onsets, midi_pitch_to_contour_bin}, ported::numpy::{arg_max, arg_max_axis1, arg_rel_max, global_max, mean_std_dev, where_greater_than_axis1}};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NoteEventFrame {
     pub start_frame: usize,
     pub duration_frames: usize,
     pub pitch_midi: usize,
     pub amplitude: f32,
     pub pitch_bends: Option<Vec<f32>>,
+    pub amplitude_envelope: Option<Vec<f32>>,
+}
+
+/// Settings for decoding the model output to note events.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NoteDecodingOptions {
+    /// Minimum amplitude of an onset activation to be considered an onset.
+    pub onset_thresh: f32,
+    /// Minimum amplitude of a frame activation for a note to remain "on". If NaN, mean + std of the frames is used.
+    /// JSON has no NaN, so it is written as null.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_nan_as_null"))]
+    pub frame_thresh: f32,
+    /// Minimum allowed note length in frames.
+    pub min_note_len: usize,
+    /// If true, add additional onsets when there are large differences in frame amplitudes.
+    pub infer_onsets: bool,
+    /// Maximum allowed output frequency, in Hz.
+    pub max_freq: Option<f32>,
+    /// Minimum allowed output frequency, in Hz.
+    pub min_freq: Option<f32>,
+    /// Remove semitones near a peak.
+    pub melodia_trick: bool,
+    /// Number of frames allowed to drop below the frame threshold.
+    pub energy_tolerance: usize,
+    /// Number of contour bins to search for pitch bends around each note. If None, no pitch bends are added.
+    pub pitch_bend_bins_tolerance: Option<usize>,
+    /// Add the frame activation of each note over its duration as amplitude envelope.
+    pub amplitude_envelopes: bool,
+    /// Refine note start and end times to sub-frame precision.
+    pub refine_times: bool,
+    /// Track a single pitch over time in the contours with `track_pitch` instead of decoding polyphonic notes,
+    /// for monophonic sources like voice or a wind instrument. `frame_thresh` is the voicing threshold.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub monophonic: bool,
+}
+
+impl Default for NoteDecodingOptions {
+    fn default() -> Self {
+        NoteDecodingOptions {
+            onset_thresh: 0.5,
+            frame_thresh: 0.3,
+            min_note_len: 5,
+            infer_onsets: true,
+            max_freq: None,
+            min_freq: None,
+            melodia_trick: true,
+            energy_tolerance: 11,
+            pitch_bend_bins_tolerance: Some(25),
+            amplitude_envelopes: false,
+            refine_times: false,
+            monophonic: false,
+        }
+    }
+}
+
+/// Convert a note length in milliseconds to model frames, like Python basic-pitch does.
+pub fn minimum_note_length_frames(milliseconds: f32) -> usize {
+    (milliseconds / 1000.0 * (AUDIO_SAMPLE_RATE as f32 / FFT_HOP as f32)).round() as usize
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_nan_as_null<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    use serde::Deserialize;
+    Ok(Option::<f32>::deserialize(deserializer)?.unwrap_or(f32::NAN))
 }
 
 /// Decode raw model output to polyphonic note events.
@@ -123,6 +189,7 @@ pub fn output_to_notes_poly(
                 pitch_midi: freq_idx + MIDI_OFFSET,
                 amplitude,
                 pitch_bends: None,
+                amplitude_envelope: None,
             })
         })
         .collect();
@@ -212,6 +279,7 @@ pub fn output_to_notes_poly(
                 pitch_midi: freq_idx + MIDI_OFFSET,
                 amplitude,
                 pitch_bends: None,
+                amplitude_envelope: None,
             });
         }
     }
@@ -271,6 +339,124 @@ pub fn add_pitch_bends_to_note_events(
             pitch_midi: note.pitch_midi,
             amplitude: note.amplitude,
             pitch_bends: Some(bends.iter().map(|&v| v as f32).collect()),
+            amplitude_envelope: note.amplitude_envelope.clone(),
+        }
+    }).collect()
+}
+
+/// Add amplitude envelopes to note events based on the frame activations.
+///
+/// # Arguments
+///
+/// * `frames` - Frame activation matrix (n_times, n_freqs).
+/// * `notes` - List of note events.
+///
+/// # Returns
+///
+/// * List of note events with the frame activation of their pitch over their duration added.
+pub fn add_amplitude_envelopes_to_note_events(
+    frames: &[Vec<f32>],
+    notes: &[NoteEventFrame],
+) -> Vec<NoteEventFrame> {
+    notes.iter().map(|note| {
+        let freq_idx = note.pitch_midi - MIDI_OFFSET;
+        let envelope: Vec<f32> = frames[note.start_frame..note.start_frame + note.duration_frames]
+            .iter()
+            .map(|row| row[freq_idx])
+            .collect();
+
+        NoteEventFrame {
+            amplitude_envelope: Some(envelope),
+            ..note.clone()
         }
     }).collect()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::{MIDI_OFFSET, N_FREQ_BINS_CONTOURS};
+
+    use super::{add_pitch_bends_to_note_events, output_to_notes_poly, NoteEventFrame};
+
+    const N_PITCHES: usize = 88;
+
+    fn decode(frames: &[Vec<f32>], onsets: &[Vec<f32>], melodia_trick: bool) -> Vec<(usize, usize, usize)> {
+        output_to_notes_poly(frames.to_vec(), onsets.to_vec(), 0.5, 0.3, 11, false, None, None, melodia_trick, 11)
+            .iter()
+            .map(|note| (note.start_frame, note.duration_frames, note.pitch_midi))
+            .collect()
+    }
+
+    #[test]
+    fn note_ends_where_frames_drop() {
+        let mut frames = vec![vec![0.0; N_PITCHES]; 100];
+        let mut onsets = vec![vec![0.0; N_PITCHES]; 100];
+        for row in &mut frames[10..40] {
+            row[10] = 0.9;
+        }
+        onsets[10][10] = 0.9;
+
+        assert_eq!(decode(&frames, &onsets, false), vec![(10, 30, 10 + MIDI_OFFSET)]);
+    }
+
+    #[test]
+    fn onsets_are_decoded_from_last_to_first() {
+        // like Python, the later onset takes its part of the frames first, so the earlier one ends there
+        // instead of swallowing the second note
+        let mut frames = vec![vec![0.0; N_PITCHES]; 100];
+        let mut onsets = vec![vec![0.0; N_PITCHES]; 100];
+        for row in &mut frames[10..60] {
+            row[10] = 0.9;
+        }
+        onsets[10][10] = 0.9;
+        onsets[30][10] = 0.9;
+
+        assert_eq!(decode(&frames, &onsets, false), vec![(30, 30, 10 + MIDI_OFFSET), (10, 20, 10 + MIDI_OFFSET)]);
+    }
+
+    #[test]
+    fn melodia_trick_finds_notes_without_onsets() {
+        let mut frames = vec![vec![0.0; N_PITCHES]; 100];
+        let onsets = vec![vec![0.0; N_PITCHES]; 100];
+        for row in &mut frames[20..50] {
+            row[5] = 0.7;
+        }
+        frames[35][5] = 0.8;
+
+        assert_eq!(decode(&frames, &onsets, false), vec![]);
+        // the end is one frame short of the last active frame, as in Python
+        assert_eq!(decode(&frames, &onsets, true), vec![(20, 29, 5 + MIDI_OFFSET)]);
+    }
+
+    #[test]
+    fn pitch_bends_follow_the_contour_peak() {
+        let note = |pitch_midi| NoteEventFrame {
+            start_frame: 0,
+            duration_frames: 4,
+            pitch_midi,
+            amplitude: 1.0,
+            pitch_bends: None,
+            amplitude_envelope: None,
+        };
+        let bends = |contours: &[Vec<f32>], pitch_midi| {
+            add_pitch_bends_to_note_events(contours, &[note(pitch_midi)], 25)[0].pitch_bends.clone().unwrap()
+        };
+
+        // MIDI 60 is contour bin 117
+        let mut contours = vec![vec![0.0; N_FREQ_BINS_CONTOURS]; 4];
+        contours[0][119] = 1.0;
+        contours[1][119] = 1.0;
+        contours[2][116] = 1.0;
+        contours[3][116] = 1.0;
+        assert_eq!(bends(&contours, 60), vec![2.0, 2.0, -1.0, -1.0]);
+
+        // the search window is cut off at the lowest and highest bins
+        let mut contours = vec![vec![0.0; N_FREQ_BINS_CONTOURS]; 4];
+        contours.iter_mut().for_each(|row| row[3] = 1.0);
+        assert_eq!(bends(&contours, 21), vec![3.0; 4]);
+
+        let mut contours = vec![vec![0.0; N_FREQ_BINS_CONTOURS]; 4];
+        contours.iter_mut().for_each(|row| row[262] = 1.0);
+        assert_eq!(bends(&contours, 108), vec![1.0; 4]);
+    }
+}
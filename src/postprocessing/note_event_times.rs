@@ -1,12 +1,16 @@
-use super::{helpers::ported::librosa::model_frame_to_time, note_event_frames::NoteEventFrame};
+use crate::constants::MIDI_OFFSET;
+
+use super::{helpers::ported::librosa::{model_frame_to_time_aligned, FrameTimeAlignment}, note_event_frames::NoteEventFrame};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NoteEventTime {
     pub start_time_seconds: f32,
     pub duration_seconds: f32,
     pub pitch_midi: usize,
     pub amplitude: f32,
     pub pitch_bends: Option<Vec<f32>>,
+    pub amplitude_envelope: Option<Vec<f32>>,
 }
 
 /// Convert note frames to time-based note events.
@@ -19,13 +23,195 @@ pub struct NoteEventTime {
 ///
 /// * List of time-based note events.
 pub fn note_frames_to_time(notes: &[NoteEventFrame]) -> Vec<NoteEventTime> {
+    note_frames_to_time_aligned(notes, &FrameTimeAlignment::default())
+}
+
+/// Convert note frames to time-based note events with a custom alignment.
+///
+/// # Arguments
+///
+/// * `notes` - List of note events.
+/// * `alignment` - How the model frames map to time.
+///
+/// # Returns
+///
+/// * List of time-based note events.
+pub fn note_frames_to_time_aligned(notes: &[NoteEventFrame], alignment: &FrameTimeAlignment) -> Vec<NoteEventTime> {
     notes.iter().map(|note| {
         NoteEventTime {
             pitch_midi: note.pitch_midi,
             amplitude: note.amplitude,
             pitch_bends: note.pitch_bends.clone(),
-            start_time_seconds: model_frame_to_time(note.start_frame),
-            duration_seconds: model_frame_to_time(note.start_frame + note.duration_frames) - model_frame_to_time(note.start_frame),
+            amplitude_envelope: note.amplitude_envelope.clone(),
+            start_time_seconds: model_frame_to_time_aligned(note.start_frame, alignment),
+            duration_seconds: model_frame_to_time_aligned(note.start_frame + note.duration_frames, alignment) - model_frame_to_time_aligned(note.start_frame, alignment),
         }
     }).collect()
 }
+
+/// Convert note frames to time-based note events with sub-frame start and end times.
+///
+/// The start time is refined with parabolic interpolation around the onset activation peak
+/// and the end time by interpolating where the frame activation drops below `frame_thresh`. The
+/// unrefined end is the first frame below the threshold, so the refined end is up to a frame
+/// earlier when the activation crosses the threshold between frames.
+///
+/// # Arguments
+///
+/// * `notes` - List of note events.
+/// * `onsets` - Onset activation matrix (n_times, n_freqs) the notes were decoded from.
+/// * `frames` - Frame activation matrix (n_times, n_freqs) the notes were decoded from.
+/// * `frame_thresh` - Minimum amplitude of a frame activation for a note to remain "on".
+/// * `alignment` - How the model frames map to time.
+///
+/// # Returns
+///
+/// * List of time-based note events.
+pub fn note_frames_to_time_refined(
+    notes: &[NoteEventFrame],
+    onsets: &[Vec<f32>],
+    frames: &[Vec<f32>],
+    frame_thresh: f32,
+    alignment: &FrameTimeAlignment,
+) -> Vec<NoteEventTime> {
+    notes.iter().map(|note| {
+        let freq_idx = note.pitch_midi - MIDI_OFFSET;
+        let start_frame = note.start_frame as f32 + onset_peak_offset(onsets, note.start_frame, freq_idx);
+        let end_frame = offset_crossing(frames, note.start_frame + note.duration_frames, freq_idx, frame_thresh);
+        let start_time_seconds = fractional_frame_to_time(start_frame, alignment);
+
+        NoteEventTime {
+            pitch_midi: note.pitch_midi,
+            amplitude: note.amplitude,
+            pitch_bends: note.pitch_bends.clone(),
+            amplitude_envelope: note.amplitude_envelope.clone(),
+            start_time_seconds,
+            duration_seconds: fractional_frame_to_time(end_frame, alignment) - start_time_seconds,
+        }
+    }).collect()
+}
+
+/// Offset of the true onset peak from `frame` in frames, between -0.5 and 0.5, using parabolic interpolation.
+fn onset_peak_offset(onsets: &[Vec<f32>], frame: usize, freq_idx: usize) -> f32 {
+    if frame == 0 || frame + 1 >= onsets.len() {
+        return 0.0;
+    }
+
+    let (before, peak, after) = (onsets[frame - 1][freq_idx], onsets[frame][freq_idx], onsets[frame + 1][freq_idx]);
+    let curvature = before - 2.0 * peak + after;
+    if curvature >= 0.0 {
+        // not a peak, e.g. an inferred onset or a note found by the melodia trick
+        return 0.0;
+    }
+
+    (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+}
+
+/// The fractional frame where the frame activation drops below `frame_thresh` near `end_frame`.
+///
+/// The decoded end is usually the first frame below the threshold, but notes found by the melodia
+/// trick end a frame earlier and notes decoded from the remaining energy can end a frame later, so
+/// the crossing is searched from two frames before to one frame after the end. Without a crossing,
+/// e.g. when a note is cut off by the next note at the same pitch, the end is kept.
+fn offset_crossing(frames: &[Vec<f32>], end_frame: usize, freq_idx: usize, frame_thresh: f32) -> f32 {
+    // (last frame at or above the threshold, the frame after it), closest to the usual case first
+    for last_on in [end_frame.checked_sub(1), Some(end_frame), end_frame.checked_sub(2)].into_iter().flatten() {
+        if last_on + 1 >= frames.len() {
+            continue;
+        }
+        let (on, off) = (frames[last_on][freq_idx], frames[last_on + 1][freq_idx]);
+        if on >= frame_thresh && off < frame_thresh {
+            return last_on as f32 + (on - frame_thresh) / (on - off);
+        }
+    }
+
+    end_frame as f32
+}
+
+/// Linearly interpolate `model_frame_to_time_aligned` between whole frames.
+fn fractional_frame_to_time(frame: f32, alignment: &FrameTimeAlignment) -> f32 {
+    let whole_frame = frame.floor().max(0.0) as usize;
+    let fraction = frame - whole_frame as f32;
+    let time = model_frame_to_time_aligned(whole_frame, alignment);
+    time + fraction * (model_frame_to_time_aligned(whole_frame + 1, alignment) - time)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        constants::MIDI_OFFSET,
+        postprocessing::{helpers::ported::librosa::FrameTimeAlignment, note_event_frames::{output_to_notes_poly, NoteEventFrame}},
+    };
+
+    use super::{fractional_frame_to_time, note_frames_to_time, note_frames_to_time_refined};
+
+    const N_PITCHES: usize = 88;
+    const PITCH_IDX: usize = 10;
+    const FRAME_THRESH: f32 = 0.3;
+    // the note starts and ends between frames
+    const TRUE_ONSET: f32 = 10.3;
+    const TRUE_OFFSET: f32 = 40.6;
+
+    /// Activations of one note: an onset peak that is a parabola around the true onset and frame
+    /// activations that fall linearly through the threshold at the true offset.
+    fn activations() -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+        let mut frames = vec![vec![0.0; N_PITCHES]; 100];
+        let mut onsets = vec![vec![0.0; N_PITCHES]; 100];
+        for (frame, row) in onsets.iter_mut().enumerate() {
+            row[PITCH_IDX] = (0.9 - 0.1 * (frame as f32 - TRUE_ONSET).powi(2)).max(0.0);
+        }
+        for (frame, row) in frames.iter_mut().enumerate().skip(10) {
+            row[PITCH_IDX] = (FRAME_THRESH + 0.2 * (TRUE_OFFSET - frame as f32)).clamp(0.0, 0.9);
+        }
+        (frames, onsets)
+    }
+
+    fn note(start_frame: usize, duration_frames: usize) -> NoteEventFrame {
+        NoteEventFrame {
+            start_frame,
+            duration_frames,
+            pitch_midi: PITCH_IDX + MIDI_OFFSET,
+            amplitude: 0.9,
+            pitch_bends: None,
+            amplitude_envelope: None,
+        }
+    }
+
+    #[test]
+    fn refined_times_find_the_sub_frame_onset_and_offset() {
+        let (frames, onsets) = activations();
+        let notes = output_to_notes_poly(frames.clone(), onsets.clone(), 0.5, FRAME_THRESH, 11, false, None, None, false, 11);
+        assert_eq!(notes.len(), 1);
+        // the decoder ends the note on the first frame below the threshold
+        assert_eq!((notes[0].start_frame, notes[0].start_frame + notes[0].duration_frames), (10, 41));
+
+        let alignment = FrameTimeAlignment::default();
+        let expected_start = fractional_frame_to_time(TRUE_ONSET, &alignment);
+        let expected_end = fractional_frame_to_time(TRUE_OFFSET, &alignment);
+        let frame_seconds = fractional_frame_to_time(1.0, &alignment) - fractional_frame_to_time(0.0, &alignment);
+
+        let unrefined = &note_frames_to_time(&notes)[0];
+        let refined = &note_frames_to_time_refined(&notes, &onsets, &frames, FRAME_THRESH, &alignment)[0];
+        assert!((refined.start_time_seconds - expected_start).abs() < 0.01 * frame_seconds);
+        assert!((refined.start_time_seconds + refined.duration_seconds - expected_end).abs() < 0.01 * frame_seconds);
+        // closer to the true times than whole frames
+        assert!((unrefined.start_time_seconds - expected_start).abs() > 0.25 * frame_seconds);
+        assert!((unrefined.start_time_seconds + unrefined.duration_seconds - expected_end).abs() > 0.25 * frame_seconds);
+
+        // a note that ends a frame early, like the ones found by the melodia trick, gets the same offset
+        let early = &note_frames_to_time_refined(&[note(10, 30)], &onsets, &frames, FRAME_THRESH, &alignment)[0];
+        assert!((early.start_time_seconds + early.duration_seconds - expected_end).abs() < 0.01 * frame_seconds);
+    }
+
+    #[test]
+    fn notes_cut_off_by_the_next_note_keep_their_end() {
+        let (mut frames, onsets) = activations();
+        // the activation stays on into a following note at the same pitch
+        for row in &mut frames[20..40] {
+            row[PITCH_IDX] = 0.9;
+        }
+        let refined = &note_frames_to_time_refined(&[note(10, 15)], &onsets, &frames, FRAME_THRESH, &FrameTimeAlignment::default())[0];
+        let unrefined = &note_frames_to_time(&[note(10, 15)])[0];
+        assert_eq!(refined.start_time_seconds + refined.duration_seconds, unrefined.start_time_seconds + unrefined.duration_seconds);
+    }
+}
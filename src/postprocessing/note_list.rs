@@ -0,0 +1,162 @@
+use std::{error::Error, io::{BufRead, BufReader, Read, Write}};
+
+use super::note_event_times::NoteEventTime;
+
+const CSV_HEADER: [&str; 5] = ["start_time_s", "end_time_s", "pitch_midi", "velocity", "pitch_bend"];
+
+/// Write note events as CSV, in the same layout as Python basic-pitch's `--save-note-events`.
+///
+/// Every row holds the start time, end time, MIDI pitch and velocity of a note, followed by
+/// its pitch bends (if any) as additional columns.
+///
+/// # Arguments
+///
+/// * `notes` - List of time-based note events.
+/// * `writer` - Where to write the CSV to.
+pub fn write_note_events_csv<W: Write>(notes: &[NoteEventTime], mut writer: W) -> Result<(), Box<dyn Error>> {
+    writeln!(writer, "{}", CSV_HEADER.join(","))?;
+
+    for note in notes {
+        let mut row = vec![
+            note.start_time_seconds.to_string(),
+            (note.start_time_seconds + note.duration_seconds).to_string(),
+            note.pitch_midi.to_string(),
+            ((note.amplitude * 127.0).round() as u8).to_string(),
+        ];
+        if let Some(pitch_bends) = &note.pitch_bends {
+            row.extend(pitch_bends.iter().map(|bend| bend.to_string()));
+        }
+        writeln!(writer, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+/// Read note events from CSV as written by `write_note_events_csv` or Python basic-pitch.
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the CSV from.
+///
+/// # Returns
+///
+/// * List of time-based note events, with the amplitude derived from the velocity.
+pub fn read_note_events_csv<R: Read>(reader: R) -> Result<Vec<NoteEventTime>, Box<dyn Error>> {
+    let mut notes = vec![];
+
+    for (line_idx, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || (line_idx == 0 && line.starts_with(CSV_HEADER[0])) {
+            continue;
+        }
+
+        let columns: Vec<&str> = line.split(',').map(|c| c.trim()).collect();
+        if columns.len() < 4 {
+            return Err(format!("line {}: expected at least 4 columns, found {}", line_idx + 1, columns.len()).into());
+        }
+
+        let parse = |column: &str| -> Result<f32, Box<dyn Error>> {
+            column.parse::<f32>().map_err(|e| format!("line {}: invalid number '{}': {}", line_idx + 1, column, e).into())
+        };
+
+        let start_time_seconds = parse(columns[0])?;
+        let end_time_seconds = parse(columns[1])?;
+        let pitch_bends = columns[4..]
+            .iter()
+            .filter(|c| !c.is_empty())
+            .map(|c| parse(c))
+            .collect::<Result<Vec<f32>, _>>()?;
+
+        notes.push(NoteEventTime {
+            start_time_seconds,
+            duration_seconds: end_time_seconds - start_time_seconds,
+            pitch_midi: parse(columns[2])?.round() as usize,
+            amplitude: parse(columns[3])? / 127.0,
+            pitch_bends: if pitch_bends.is_empty() { None } else { Some(pitch_bends) },
+            amplitude_envelope: None,
+        });
+    }
+
+    Ok(notes)
+}
+
+/// Write note events as a JSON array.
+///
+/// # Arguments
+///
+/// * `notes` - List of time-based note events.
+/// * `writer` - Where to write the JSON to.
+#[cfg(feature = "serde")]
+pub fn write_note_events_json<W: Write>(notes: &[NoteEventTime], writer: W) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer_pretty(writer, notes)?;
+    Ok(())
+}
+
+/// Read note events from a JSON array as written by `write_note_events_json`.
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the JSON from.
+///
+/// # Returns
+///
+/// * List of time-based note events.
+#[cfg(feature = "serde")]
+pub fn read_note_events_json<R: Read>(reader: R) -> Result<Vec<NoteEventTime>, Box<dyn Error>> {
+    Ok(serde_json::from_reader(BufReader::new(reader))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::postprocessing::note_event_times::NoteEventTime;
+
+    use super::{read_note_events_csv, write_note_events_csv};
+
+    fn notes() -> Vec<NoteEventTime> {
+        vec![
+            NoteEventTime {
+                start_time_seconds: 0.25,
+                duration_seconds: 1.5,
+                pitch_midi: 60,
+                amplitude: 100.0 / 127.0,
+                pitch_bends: Some(vec![0.0, 0.5, -1.25]),
+                amplitude_envelope: None,
+            },
+            NoteEventTime {
+                start_time_seconds: 1.0,
+                duration_seconds: 0.125,
+                pitch_midi: 72,
+                amplitude: 64.0 / 127.0,
+                pitch_bends: None,
+                amplitude_envelope: None,
+            },
+        ]
+    }
+
+    fn assert_same_notes(read: &[NoteEventTime], written: &[NoteEventTime]) {
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(written) {
+            assert!((read.start_time_seconds - written.start_time_seconds).abs() < 1e-6);
+            assert!((read.duration_seconds - written.duration_seconds).abs() < 1e-6);
+            assert_eq!(read.pitch_midi, written.pitch_midi);
+            assert!((read.amplitude - written.amplitude).abs() < 1e-6);
+            assert_eq!(read.pitch_bends, written.pitch_bends);
+        }
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let mut csv = vec![];
+        write_note_events_csv(&notes(), &mut csv).unwrap();
+        assert_same_notes(&read_note_events_csv(csv.as_slice()).unwrap(), &notes());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let mut json = vec![];
+        super::write_note_events_json(&notes(), &mut json).unwrap();
+        assert_same_notes(&super::read_note_events_json(json.as_slice()).unwrap(), &notes());
+    }
+}
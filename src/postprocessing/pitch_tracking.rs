@@ -0,0 +1,257 @@
+use crate::constants::{CONTOURS_BINS_PER_SEMITONE, MIDI_OFFSET};
+
+use super::{helpers::{helpers::contour_bin_to_hz, ported::{librosa::{hz_to_midi, model_frame_to_time}, numpy::arg_max}}, note_event_frames::NoteEventFrame};
+
+/// Maximum pitch movement between frames when decoding monophonic notes: one semitone.
+pub const MONOPHONIC_MAX_TRANSITION_BINS: usize = CONTOURS_BINS_PER_SEMITONE as usize;
+/// Probability of switching between voiced and unvoiced between frames when decoding monophonic notes.
+pub const MONOPHONIC_SWITCH_PROB: f32 = 0.01;
+/// Pitch deviation that starts a new note when decoding monophonic notes, so vibrato stays one note.
+pub const MONOPHONIC_SPLIT_SEMITONES: f32 = 1.0;
+
+#[derive(Debug, Clone)]
+pub struct PitchTrackPoint {
+    pub time_seconds: f32,
+    pub frequency_hz: f32,
+    pub confidence: f32,
+    pub voiced: bool,
+}
+
+/// Track a single fundamental frequency over time from the contour salience using Viterbi decoding.
+///
+/// Every frame is either voiced at one of the contour bins or unvoiced. The most likely path
+/// through these states is found, where voiced frames may only move `max_transition_bins` bins
+/// between frames, which removes octave errors and spurious jumps.
+///
+/// # Arguments
+///
+/// * `contours` - Contour salience matrix (n_times, n_freq_bins_contours).
+/// * `voicing_thresh` - Salience at which a frame is equally likely to be voiced or unvoiced.
+/// * `max_transition_bins` - Maximum number of contour bins the pitch can move between frames.
+/// * `switch_prob` - Probability of switching between voiced and unvoiced between frames.
+///
+/// # Returns
+///
+/// * A point for every frame. Unvoiced frames hold the frequency with the largest salience. Empty if
+///   there are no frames or no bins.
+pub fn track_pitch(
+    contours: &[Vec<f32>],
+    voicing_thresh: f32,
+    max_transition_bins: usize,
+    switch_prob: f32,
+) -> Vec<PitchTrackPoint> {
+    if contours.is_empty() || contours[0].is_empty() {
+        return vec![];
+    }
+
+    let n_frames = contours.len();
+    let n_bins = contours[0].len();
+    let unvoiced = n_bins;
+
+    // log transition weights for a voiced to voiced move of d bins (triangular), relative to staying at the
+    // same bin so that a steady pitch costs as much as staying unvoiced and `voicing_thresh` keeps its meaning
+    let log_move: Vec<f32> = (0..=max_transition_bins)
+        .map(|d| ((max_transition_bins + 1 - d) as f32 / (max_transition_bins + 1) as f32).ln() + (1.0 - switch_prob).ln())
+        .collect();
+    let log_to_unvoiced = switch_prob.ln();
+    let log_to_voiced = (switch_prob / n_bins as f32).ln();
+    let log_stay_unvoiced = (1.0 - switch_prob).ln();
+
+    let log_emission = |row: &[f32], state: usize| -> f32 {
+        if state == unvoiced {
+            voicing_thresh.max(f32::EPSILON).ln()
+        } else {
+            row[state].max(f32::EPSILON).ln()
+        }
+    };
+
+    let mut scores: Vec<f32> = (0..=n_bins).map(|state| log_emission(&contours[0], state)).collect();
+    let mut backpointers: Vec<Vec<usize>> = Vec::with_capacity(n_frames);
+    backpointers.push((0..=n_bins).collect());
+
+    for row in contours.iter().skip(1) {
+        let mut new_scores = vec![f32::NEG_INFINITY; n_bins + 1];
+        let mut pointers = vec![0; n_bins + 1];
+
+        for state in 0..n_bins {
+            // from the unvoiced state
+            let mut best = (scores[unvoiced] + log_to_voiced, unvoiced);
+
+            // from nearby voiced states
+            let start = state.saturating_sub(max_transition_bins);
+            let end = (state + max_transition_bins).min(n_bins - 1);
+            for previous in start..=end {
+                let score = scores[previous] + log_move[state.abs_diff(previous)];
+                if score > best.0 {
+                    best = (score, previous);
+                }
+            }
+
+            new_scores[state] = best.0 + log_emission(row, state);
+            pointers[state] = best.1;
+        }
+
+        let mut best = (scores[unvoiced] + log_stay_unvoiced, unvoiced);
+        for (previous, &previous_score) in scores[..n_bins].iter().enumerate() {
+            let score = previous_score + log_to_unvoiced;
+            if score > best.0 {
+                best = (score, previous);
+            }
+        }
+        new_scores[unvoiced] = best.0 + log_emission(row, unvoiced);
+        pointers[unvoiced] = best.1;
+
+        scores = new_scores;
+        backpointers.push(pointers);
+    }
+
+    // backtrack from the best final state
+    let mut path = vec![0; n_frames];
+    path[n_frames - 1] = arg_max(&scores).unwrap();
+    for t in (1..n_frames).rev() {
+        path[t - 1] = backpointers[t][path[t]];
+    }
+
+    path.iter().zip(contours.iter()).enumerate().map(|(frame, (&state, row))| {
+        let voiced = state != unvoiced;
+        let bin = if voiced { state } else { arg_max(row).unwrap() };
+        PitchTrackPoint {
+            time_seconds: model_frame_to_time(frame),
+            frequency_hz: contour_bin_to_hz(bin as f32),
+            confidence: row[bin],
+            voiced,
+        }
+    }).collect()
+}
+
+/// Segment a pitch track into note events.
+///
+/// A note ends when the pitch track becomes unvoiced, or when the pitch moves more than
+/// `split_semitones` away from the pitch of the current note.
+///
+/// # Arguments
+///
+/// * `pitch_track` - Pitch track with one point per model frame, as returned by `track_pitch`.
+/// * `min_note_len` - Minimum allowed note length in frames.
+/// * `split_semitones` - Pitch deviation in semitones that starts a new note.
+///
+/// # Returns
+///
+/// * List of note events, with pitch bends relative to the note pitch in contour bins and the
+///   confidence as amplitude envelope.
+pub fn pitch_track_to_notes(
+    pitch_track: &[PitchTrackPoint],
+    min_note_len: usize,
+    split_semitones: f32,
+) -> Vec<NoteEventFrame> {
+    let mut note_events = vec![];
+    let mut segment_start = 0;
+    let mut segment_pitch: Option<f32> = None;
+
+    for i in 0..=pitch_track.len() {
+        let point = pitch_track.get(i).filter(|p| p.voiced);
+        let pitch = point.map(|p| hz_to_midi(p.frequency_hz));
+
+        let continues = match (segment_pitch, pitch) {
+            (Some(segment_pitch), Some(pitch)) => (pitch - segment_pitch).abs() <= split_semitones,
+            _ => false,
+        };
+        if continues {
+            continue;
+        }
+
+        if segment_pitch.is_some() && i - segment_start > min_note_len {
+            note_events.push(segment_to_note(&pitch_track[segment_start..i], segment_start));
+        }
+
+        segment_start = i;
+        segment_pitch = pitch.map(|p| p.round());
+    }
+
+    note_events
+}
+
+fn segment_to_note(segment: &[PitchTrackPoint], start_frame: usize) -> NoteEventFrame {
+    let pitches: Vec<f32> = segment.iter().map(|p| hz_to_midi(p.frequency_hz)).collect();
+    let pitch_midi = (pitches.iter().sum::<f32>() / pitches.len() as f32).round();
+    let confidences: Vec<f32> = segment.iter().map(|p| p.confidence).collect();
+
+    NoteEventFrame {
+        start_frame,
+        duration_frames: segment.len(),
+        pitch_midi: (pitch_midi as usize).max(MIDI_OFFSET),
+        amplitude: confidences.iter().sum::<f32>() / confidences.len() as f32,
+        pitch_bends: Some(pitches.iter().map(|p| ((p - pitch_midi) * CONTOURS_BINS_PER_SEMITONE).round()).collect()),
+        amplitude_envelope: Some(confidences),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{constants::{MIDI_OFFSET, N_FREQ_BINS_CONTOURS}, postprocessing::helpers::ported::{librosa::hz_to_midi, numpy::arg_max}};
+
+    use super::{pitch_track_to_notes, track_pitch};
+
+    // MIDI 60 is contour bin 117
+    const C4_BIN: usize = 117;
+
+    fn contours_at(bins: &[Option<usize>]) -> Vec<Vec<f32>> {
+        bins.iter()
+            .map(|bin| {
+                let mut row = vec![0.01; N_FREQ_BINS_CONTOURS];
+                if let Some(bin) = bin {
+                    row[*bin] = 0.9;
+                }
+                row
+            })
+            .collect()
+    }
+
+    #[test]
+    fn one_frame_octave_jumps_are_smoothed_away() {
+        let mut contours = contours_at(&[Some(C4_BIN); 20]);
+        // the octave above is the most salient bin for one frame
+        contours[10][C4_BIN] = 0.3;
+        contours[10][C4_BIN + 36] = 0.95;
+        assert_eq!(arg_max(&contours[10]), Some(C4_BIN + 36));
+
+        let pitch_track = track_pitch(&contours, 0.3, 3, 0.01);
+        assert_eq!(pitch_track.len(), 20);
+        for point in &pitch_track {
+            assert!(point.voiced);
+            assert!((hz_to_midi(point.frequency_hz) - 60.0).abs() < 0.01);
+        }
+        assert!((pitch_track[10].confidence - 0.3).abs() < 1e-6);
+
+        let notes = pitch_track_to_notes(&pitch_track, 5, 1.0);
+        assert_eq!(notes.len(), 1);
+        assert_eq!((notes[0].start_frame, notes[0].duration_frames, notes[0].pitch_midi), (0, 20, 60));
+    }
+
+    #[test]
+    fn unvoiced_frames_and_leaps_split_notes() {
+        // C4, a gap, then E4 that leaps to G4 over a few frames
+        let mut bins = vec![Some(C4_BIN); 15];
+        bins.extend([None; 10]);
+        bins.extend([Some(C4_BIN + 12); 15]);
+        bins.extend([Some(C4_BIN + 14), Some(C4_BIN + 17), Some(C4_BIN + 20)]);
+        bins.extend([Some(C4_BIN + 21); 15]);
+
+        let pitch_track = track_pitch(&contours_at(&bins), 0.3, 3, 0.01);
+        assert!(pitch_track[15..25].iter().all(|point| !point.voiced));
+
+        let notes: Vec<(usize, usize)> = pitch_track_to_notes(&pitch_track, 5, 1.0).iter().map(|note| (note.start_frame, note.pitch_midi)).collect();
+        assert_eq!(notes.len(), 3);
+        assert_eq!(notes[0], (0, 60));
+        assert_eq!(notes[1], (25, 64));
+        assert_eq!(notes[2].1, 67);
+        assert!(notes.iter().all(|&(_, pitch)| pitch >= MIDI_OFFSET));
+    }
+
+    #[test]
+    fn empty_contours_have_no_track() {
+        assert!(track_pitch(&[], 0.3, 3, 0.01).is_empty());
+        assert!(track_pitch(&vec![vec![]; 10], 0.3, 3, 0.01).is_empty());
+        assert!(pitch_track_to_notes(&[], 5, 1.0).is_empty());
+    }
+}
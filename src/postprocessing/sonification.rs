@@ -0,0 +1,288 @@
+use std::{error::Error, f32::consts::PI, path::Path};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::constants::CONTOURS_BINS_PER_SEMITONE;
+
+use super::{helpers::ported::librosa::midi_to_hz, note_event_times::NoteEventTime};
+
+const ATTACK_SECONDS: f32 = 0.005;
+const RELEASE_SECONDS: f32 = 0.05;
+const NOTE_GAIN: f32 = 0.3;
+
+/// Waveform of the built-in synth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    /// A pure sine at the fundamental frequency.
+    Sine,
+    /// The fundamental and `n_harmonics - 1` overtones with amplitudes falling off as 1/k.
+    Additive { n_harmonics: usize },
+}
+
+/// Render note events to audio with the built-in synth.
+///
+/// The pitch of each note follows its pitch bends and the loudness follows its amplitude
+/// envelope if there is one, otherwise its amplitude.
+///
+/// # Arguments
+///
+/// * `notes` - List of time-based note events.
+/// * `sample_rate` - Sample rate of the rendered audio.
+/// * `waveform` - Waveform of the synth.
+///
+/// # Returns
+///
+/// * Mono audio samples, scaled down if needed to stay within [-1, 1].
+pub fn render_notes(notes: &[NoteEventTime], sample_rate: u32, waveform: Waveform) -> Vec<f32> {
+    let sample_rate_f = sample_rate as f32;
+    let end_time = notes
+        .iter()
+        .map(|note| note.start_time_seconds + note.duration_seconds)
+        .fold(0.0, f32::max);
+    let mut audio = vec![0.0f32; ((end_time + RELEASE_SECONDS) * sample_rate_f).ceil() as usize];
+
+    let n_harmonics = match waveform {
+        Waveform::Sine => 1,
+        Waveform::Additive { n_harmonics } => n_harmonics.max(1),
+    };
+
+    for note in notes {
+        let start_sample = (note.start_time_seconds.max(0.0) * sample_rate_f).round() as usize;
+        let n_note_samples = (note.duration_seconds * sample_rate_f).round() as usize;
+        let n_release_samples = (RELEASE_SECONDS * sample_rate_f).round() as usize;
+
+        let mut phase = 0.0f32;
+        for i in 0..n_note_samples + n_release_samples {
+            let Some(sample) = audio.get_mut(start_sample + i) else {
+                break;
+            };
+
+            // position within the note from 0 to 1, held at the end during the release
+            let position = (i as f32 / n_note_samples.max(1) as f32).min(1.0);
+            let bend = note.pitch_bends.as_deref().map_or(0.0, |bends| interpolate(bends, position));
+            let frequency = midi_to_hz(note.pitch_midi as f32 + bend / CONTOURS_BINS_PER_SEMITONE);
+            let amplitude = note.amplitude_envelope.as_deref().map_or(note.amplitude, |envelope| interpolate(envelope, position));
+
+            let time = i as f32 / sample_rate_f;
+            let gain = if time < ATTACK_SECONDS {
+                time / ATTACK_SECONDS
+            } else if i >= n_note_samples {
+                1.0 - (i - n_note_samples) as f32 / n_release_samples as f32
+            } else {
+                1.0
+            };
+
+            let mut value = 0.0;
+            for k in 1..=n_harmonics {
+                if frequency * k as f32 >= sample_rate_f / 2.0 {
+                    break;
+                }
+                value += (phase * k as f32).sin() / k as f32;
+            }
+            *sample += NOTE_GAIN * amplitude * gain * value;
+
+            phase = (phase + 2.0 * PI * frequency / sample_rate_f) % (2.0 * PI);
+        }
+    }
+
+    normalize_peak(&mut audio);
+    audio
+}
+
+/// Linearly interpolate a curve that is spread evenly over a note, at `position` from 0 to 1.
+fn interpolate(values: &[f32], position: f32) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let index = position * (values.len() - 1) as f32;
+    let lower = index.floor() as usize;
+    let upper = (lower + 1).min(values.len() - 1);
+    values[lower] + (index - lower as f32) * (values[upper] - values[lower])
+}
+
+fn normalize_peak(audio: &mut [f32]) {
+    let peak = audio.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    if peak > 1.0 {
+        audio.iter_mut().for_each(|sample| *sample /= peak);
+    }
+}
+
+/// Render note events to audio with a SoundFont.
+///
+/// The notes are written to MIDI with `generate_midi_file_data` first, so the MIDI options
+/// (pitch bend range, channels, programs, expression) apply to the rendering as well.
+///
+/// # Arguments
+///
+/// * `notes` - List of time-based note events.
+/// * `soundfont_path` - Path of the SF2 file.
+/// * `sample_rate` - Sample rate of the rendered audio.
+/// * `midi_options` - Settings for the intermediate MIDI.
+///
+/// # Returns
+///
+/// * Mono audio samples, scaled down if needed to stay within [-1, 1].
+#[cfg(feature = "soundfont")]
+pub fn render_notes_soundfont<P: AsRef<Path>>(
+    notes: &[NoteEventTime],
+    soundfont_path: P,
+    sample_rate: u32,
+    midi_options: &super::midi::MidiOptions,
+) -> Result<Vec<f32>, Box<dyn Error>> {
+    use std::{fs::File, io::Cursor, sync::Arc};
+
+    use rustysynth::{MidiFile, MidiFileSequencer, SoundFont, Synthesizer, SynthesizerSettings};
+
+    let sound_font = Arc::new(SoundFont::new(&mut File::open(soundfont_path)?)?);
+    // the tempo doesn't matter for the rendering, the note times are kept in seconds
+    let midi_buffer = super::midi::generate_midi_file_data(notes, 120, midi_options);
+    let midi_file = Arc::new(MidiFile::new(&mut Cursor::new(midi_buffer))?);
+
+    let settings = SynthesizerSettings::new(sample_rate as i32);
+    let synthesizer = Synthesizer::new(&sound_font, &settings)?;
+    let mut sequencer = MidiFileSequencer::new(synthesizer);
+    sequencer.play(&midi_file, false);
+
+    let n_samples = ((midi_file.get_length() + RELEASE_SECONDS as f64) * sample_rate as f64).ceil() as usize;
+    let mut left = vec![0.0f32; n_samples];
+    let mut right = vec![0.0f32; n_samples];
+    sequencer.render(&mut left[..], &mut right[..]);
+
+    let mut audio: Vec<f32> = left.iter().zip(right.iter()).map(|(l, r)| 0.5 * (l + r)).collect();
+    normalize_peak(&mut audio);
+    Ok(audio)
+}
+
+/// Write audio channels to a 32-bit float WAV file.
+///
+/// # Arguments
+///
+/// * `path` - Path of the WAV file to create.
+/// * `channels` - Samples of each channel. Shorter channels are padded with silence.
+/// * `sample_rate` - Sample rate of the audio.
+pub fn write_wav<P: AsRef<Path>>(path: P, channels: &[&[f32]], sample_rate: u32) -> Result<(), Box<dyn Error>> {
+    let spec = WavSpec {
+        channels: channels.len() as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+
+    let n_samples = channels.iter().map(|channel| channel.len()).max().unwrap_or(0);
+    for i in 0..n_samples {
+        for channel in channels {
+            writer.write_sample(channel.get(i).copied().unwrap_or(0.0))?;
+        }
+    }
+    writer.finalize()?;
+
+    Ok(())
+}
+
+/// Render note events with the built-in synth and write them to a WAV file.
+///
+/// # Arguments
+///
+/// * `path` - Path of the WAV file to create.
+/// * `notes` - List of time-based note events.
+/// * `sample_rate` - Sample rate of the audio.
+/// * `waveform` - Waveform of the synth.
+/// * `original_audio` - If set, written on the left channel with the rendering on the right,
+///   to compare the transcription with the original by ear. Must have the same sample rate.
+pub fn sonify_to_wav<P: AsRef<Path>>(
+    path: P,
+    notes: &[NoteEventTime],
+    sample_rate: u32,
+    waveform: Waveform,
+    original_audio: Option<&[f32]>,
+) -> Result<(), Box<dyn Error>> {
+    let rendered = render_notes(notes, sample_rate, waveform);
+    match original_audio {
+        Some(original_audio) => write_wav(path, &[original_audio, &rendered], sample_rate),
+        None => write_wav(path, &[&rendered], sample_rate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hound::WavReader;
+
+    use crate::postprocessing::note_event_times::NoteEventTime;
+
+    use super::{render_notes, sonify_to_wav, write_wav, Waveform, RELEASE_SECONDS};
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    fn note(pitch_bends: Option<Vec<f32>>, amplitude_envelope: Option<Vec<f32>>) -> NoteEventTime {
+        NoteEventTime { start_time_seconds: 0.5, duration_seconds: 1.0, pitch_midi: 69, amplitude: 0.5, pitch_bends, amplitude_envelope }
+    }
+
+    /// Frequency of a stretch of audio from its upward zero crossings.
+    fn frequency(audio: &[f32]) -> f32 {
+        let crossings: Vec<usize> = (1..audio.len()).filter(|&i| audio[i - 1] < 0.0 && audio[i] >= 0.0).collect();
+        let (first, last) = (crossings[0], crossings[crossings.len() - 1]);
+        (crossings.len() - 1) as f32 * SAMPLE_RATE as f32 / (last - first) as f32
+    }
+
+    fn peak(audio: &[f32]) -> f32 {
+        audio.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    fn seconds(start: f32, end: f32) -> std::ops::Range<usize> {
+        (start * SAMPLE_RATE as f32) as usize..(end * SAMPLE_RATE as f32) as usize
+    }
+
+    #[test]
+    fn notes_are_rendered_at_their_time_and_frequency() {
+        let audio = render_notes(&[note(None, None)], SAMPLE_RATE, Waveform::Sine);
+        assert_eq!(audio.len(), ((1.5 + RELEASE_SECONDS) * SAMPLE_RATE as f32).ceil() as usize);
+        assert_eq!(peak(&audio[seconds(0.0, 0.49)]), 0.0);
+        assert!((frequency(&audio[seconds(0.6, 1.4)]) - 440.0).abs() < 1.0);
+        // the note gain times the amplitude
+        assert!((peak(&audio[seconds(0.6, 1.4)]) - 0.15).abs() < 0.01);
+    }
+
+    #[test]
+    fn pitch_bends_and_amplitude_envelopes_are_followed() {
+        // a whole tone up in the second half, in contour bins of a third of a semitone
+        let bends = vec![0.0, 0.0, 0.0, 6.0, 6.0, 6.0];
+        let envelope = vec![1.0, 1.0, 1.0, 0.2, 0.2, 0.2];
+        let audio = render_notes(&[note(Some(bends), Some(envelope))], SAMPLE_RATE, Waveform::Sine);
+
+        assert!((frequency(&audio[seconds(0.55, 0.85)]) - 440.0).abs() < 2.0);
+        assert!((frequency(&audio[seconds(1.15, 1.45)]) - 493.9).abs() < 2.0);
+        assert!((peak(&audio[seconds(0.55, 0.85)]) - 0.3).abs() < 0.01);
+        assert!((peak(&audio[seconds(1.15, 1.45)]) - 0.06).abs() < 0.01);
+    }
+
+    #[test]
+    fn the_original_audio_is_written_next_to_the_rendering() {
+        let path = std::env::temp_dir().join(format!("basic-pitch-sonification-{}.wav", std::process::id()));
+        let original = vec![0.25f32; SAMPLE_RATE as usize];
+        sonify_to_wav(&path, &[note(None, None)], SAMPLE_RATE, Waveform::Additive { n_harmonics: 3 }, Some(&original)).unwrap();
+
+        let mut reader = WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        let samples: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let (left, right): (Vec<f32>, Vec<f32>) = samples.chunks(2).map(|frame| (frame[0], frame[1])).unzip();
+        let rendered = render_notes(&[note(None, None)], SAMPLE_RATE, Waveform::Additive { n_harmonics: 3 });
+        // the shorter original is padded with silence to the length of the rendering
+        assert_eq!(left.len(), rendered.len());
+        assert_eq!(&left[..original.len()], &original[..]);
+        assert!(left[original.len()..].iter().all(|&sample| sample == 0.0));
+        assert_eq!(right, rendered);
+    }
+
+    #[test]
+    fn short_channels_are_padded_with_silence() {
+        let path = std::env::temp_dir().join(format!("basic-pitch-write-wav-{}.wav", std::process::id()));
+        write_wav(&path, &[&[0.5, -0.5, 0.25], &[1.0]], SAMPLE_RATE).unwrap();
+
+        let samples: Vec<f32> = WavReader::open(&path).unwrap().samples::<f32>().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples, vec![0.5, 1.0, -0.5, 0.0, 0.25, 0.0]);
+    }
+}
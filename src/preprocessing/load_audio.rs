@@ -1,4 +1,11 @@
-use std::{error::Error, path::Path};
+use std::{
+    cell::Cell,
+    error::Error,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    str::FromStr,
+};
 
 use hound::WavReader;
 use ndarray::{concatenate, Array1, Array2, Axis};
@@ -8,37 +15,342 @@ use crate::constants::AUDIO_SAMPLE_RATE;
 
 use crate::preprocessing::windowed_audio::window_audio_file;
 
-fn load_and_convert_audio<P: AsRef<Path>>(path: P, target_sample_rate: u32) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+/// Sample format of headerless PCM audio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcmFormat {
+    /// Signed 16-bit little-endian integers.
+    S16Le,
+    /// Signed 24-bit little-endian integers, packed in three bytes.
+    S24Le,
+    /// Signed 32-bit little-endian integers.
+    S32Le,
+    /// 32-bit little-endian floats in [-1, 1].
+    F32Le,
+    /// 64-bit little-endian floats in [-1, 1].
+    F64Le,
+}
+
+impl PcmFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            PcmFormat::S16Le => 2,
+            PcmFormat::S24Le => 3,
+            PcmFormat::S32Le | PcmFormat::F32Le => 4,
+            PcmFormat::F64Le => 8,
+        }
+    }
+
+    /// Decode one sample of `bytes_per_sample` bytes. Integers are normalized like WAV files are by
+    /// `load_and_convert_audio`, by the largest positive value.
+    fn decode_sample(&self, bytes: &[u8]) -> f64 {
+        match self {
+            PcmFormat::S16Le => i16::from_le_bytes([bytes[0], bytes[1]]) as f64 / i16::MAX as f64,
+            // shifted into the top of an i32 to extend the sign
+            PcmFormat::S24Le => (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as f64 / 8388607.0,
+            PcmFormat::S32Le => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / i32::MAX as f64,
+            PcmFormat::F32Le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            PcmFormat::F64Le => f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+        }
+    }
+}
+
+impl FromStr for PcmFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s16le" => Ok(PcmFormat::S16Le),
+            "s24le" => Ok(PcmFormat::S24Le),
+            "s32le" => Ok(PcmFormat::S32Le),
+            "f32le" => Ok(PcmFormat::F32Le),
+            "f64le" => Ok(PcmFormat::F64Le),
+            _ => Err(format!("invalid PCM format '{}', expected s16le, s24le, s32le, f32le or f64le", s)),
+        }
+    }
+}
+
+/// Converts PCM bytes that arrive in arbitrary pieces to samples.
+#[derive(Debug, Clone)]
+pub struct PcmDecoder {
+    format: PcmFormat,
+    pending: Vec<u8>,
+}
+
+impl PcmDecoder {
+    pub fn new(format: PcmFormat) -> Self {
+        PcmDecoder { format, pending: vec![] }
+    }
+
+    /// Decode the next bytes of the stream. A sample split across pieces is decoded with the next piece.
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<f32> {
+        self.pending.extend_from_slice(bytes);
+        let bytes_per_sample = self.format.bytes_per_sample();
+        let n_bytes = self.pending.len() - self.pending.len() % bytes_per_sample;
+        let samples = self.pending[..n_bytes]
+            .chunks_exact(bytes_per_sample)
+            .map(|sample| self.format.decode_sample(sample) as f32)
+            .collect();
+        self.pending.drain(..n_bytes);
+        samples
+    }
+}
+
+/// The layout of headerless PCM audio, which has to be declared since there is no header to read it from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawAudioFormat {
+    pub pcm_format: PcmFormat,
+    pub sample_rate: u32,
+    /// Number of interleaved channels.
+    pub channels: u16,
+}
+
+/// A segment of audio to load, in seconds from the start. The default is all of the audio.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TimeRange {
+    pub start: f32,
+    /// The end, or None for the end of the audio.
+    pub end: Option<f32>,
+}
+
+impl TimeRange {
+    /// Create a range, checking that it isn't negative or empty.
+    pub fn new(start: f32, end: Option<f32>) -> Result<Self, Box<dyn Error>> {
+        if start.is_nan() || start < 0.0 {
+            return Err(format!("the start time must not be negative, got {}", start).into());
+        }
+        if let Some(end) = end.filter(|&end| end.is_nan() || end <= start) {
+            return Err(format!("the end time {} must be after the start time {}", end, start).into());
+        }
+        Ok(TimeRange { start, end })
+    }
+
+    /// The first frame and the maximum number of frames of the range at a sample rate.
+    fn frames(&self, sample_rate: u32) -> (usize, Option<usize>) {
+        let frame = |seconds: f32| (seconds as f64 * sample_rate as f64).round() as usize;
+        let start = frame(self.start);
+        (start, self.end.map(|end| frame(end).saturating_sub(start)))
+    }
+}
+
+/// Load a WAV file as mono audio, normalized to [-1, 1] and resampled to the target sample rate.
+///
+/// # Arguments
+///
+/// * `path` - Path of the WAV file.
+/// * `target_sample_rate` - Sample rate to resample the audio to.
+///
+/// # Returns
+///
+/// * The audio samples and the length of the audio in samples at the target sample rate.
+pub fn load_and_convert_audio<P: AsRef<Path>>(path: P, target_sample_rate: u32) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+    load_and_convert_audio_range(path, &TimeRange::default(), target_sample_rate)
+}
+
+/// Load a segment of a WAV file as mono audio, normalized to [-1, 1] and resampled to the target sample rate.
+/// Only the segment is read from the file.
+///
+/// # Arguments
+///
+/// * `path` - Path of the WAV file.
+/// * `range` - The segment to load.
+/// * `target_sample_rate` - Sample rate to resample the audio to.
+///
+/// # Returns
+///
+/// * The audio samples and the length of the audio in samples at the target sample rate.
+pub fn load_and_convert_audio_range<P: AsRef<Path>>(
+    path: P,
+    range: &TimeRange,
+    target_sample_rate: u32,
+) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
     // Read the input WAV file
-    let reader = WavReader::open(path)?;
+    let mut reader = WavReader::open(path)?;
+    let (start, n_frames) = range.frames(reader.spec().sample_rate);
+    check_range_start(start, reader.duration() as usize, reader.spec().sample_rate)?;
+    reader.seek(start as u32)?;
+    convert_audio(reader, 0, n_frames, target_sample_rate, None)
+}
+
+/// Load WAV data from a reader as mono audio, normalized to [-1, 1] and resampled to the target sample rate.
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the WAV data from, e.g. an uploaded file in memory. Streamed WAV data, like on
+///   stdin, often ends before the length in its header, it then ends after the last full sample.
+/// * `range` - The segment to load. The audio before it is decoded and dropped since the reader can't seek.
+/// * `target_sample_rate` - Sample rate to resample the audio to.
+///
+/// # Returns
+///
+/// * The audio samples and the length of the audio in samples at the target sample rate.
+pub fn load_and_convert_audio_from_reader<R: Read>(
+    reader: R,
+    range: &TimeRange,
+    target_sample_rate: u32,
+) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+    let at_end = Cell::new(false);
+    let reader = WavReader::new(EndOfDataReader { inner: reader, at_end: &at_end })?;
+    let (start, n_frames) = range.frames(reader.spec().sample_rate);
+    check_range_start(start, reader.duration() as usize, reader.spec().sample_rate)?;
+    convert_audio(reader, start, n_frames, target_sample_rate, Some(&at_end))
+}
+
+/// A reader that remembers when the data it reads from has ended, to tell truncated WAV data from read errors.
+struct EndOfDataReader<'a, R> {
+    inner: R,
+    at_end: &'a Cell<bool>,
+}
+
+impl<R: Read> Read for EndOfDataReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.at_end.set(true);
+        }
+        Ok(n)
+    }
+}
+
+fn check_range_start(start: usize, duration: usize, sample_rate: u32) -> Result<(), Box<dyn Error>> {
+    if start > 0 && start >= duration {
+        return Err(format!("the start time is after the end of the audio ({:.2} s)", duration as f64 / sample_rate as f64).into());
+    }
+    Ok(())
+}
+
+/// Convert the samples of a WAV file, skipping `skip_frames` frames and keeping at most `max_frames` frames.
+///
+/// If `at_end` is given, a sample that can't be read because the data ended is taken as the end of the audio.
+fn convert_audio<R: Read>(
+    reader: WavReader<R>,
+    skip_frames: usize,
+    max_frames: Option<usize>,
+    target_sample_rate: u32,
+    at_end: Option<&Cell<bool>>,
+) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
     let mut spec = reader.spec();
-    let duration = reader.duration() as usize;
+    let duration = max_frames.unwrap_or(reader.duration() as usize);
 
     let max_sample_value = (2.0_f64.powi(spec.bits_per_sample as i32 - 1) - 1.0) as i32;
 
-    let mut reader_samples = reader.into_samples::<i32>();
-    let samples;
+    let mut reader_samples = reader.into_samples::<i32>().take_while(|sample| sample.is_ok() || !at_end.is_some_and(Cell::get));
+    for sample in reader_samples.by_ref().take(skip_frames * spec.channels as usize) {
+        sample?;
+    }
+    let samples: Vec<i32>;
 
     // If it's stereo, convert it to mono
     if spec.channels == 2 {
         let mut mono_samples: Vec<i32> = vec![];
-        while let (Some(left), Some(right)) = (reader_samples.next(), reader_samples.next()) {
-            let left = left?;
-            let right = right?;
-            let mono_sample = (left as i32 + right as i32) / 2;
-            mono_samples.push(mono_sample)
+        while mono_samples.len() < duration {
+            let (Some(left), Some(right)) = (reader_samples.next(), reader_samples.next()) else {
+                break;
+            };
+            let mono_sample = (left? as i64 + right? as i64) / 2;
+            mono_samples.push(mono_sample as i32)
         }
 
         samples = mono_samples;
         spec.channels = 1
     } else {
-        samples = reader_samples.into_iter().map(|s| s.unwrap()).collect();
+        samples = reader_samples.take(duration).collect::<Result<_, _>>()?;
+    }
+
+    let max_sample_value = max_sample_value as f64;
+    let samples = samples.iter().take(duration).map(|&sample| sample as f64 / max_sample_value).collect();
+    resample(samples, spec.sample_rate, target_sample_rate)
+}
+
+/// Load a headerless PCM file as mono audio, normalized to [-1, 1] and resampled to the target sample rate.
+///
+/// # Arguments
+///
+/// * `path` - Path of the PCM file.
+/// * `format` - The layout of the data. The channels are mixed down by averaging them.
+/// * `range` - The segment to load. Only the segment is read from the file.
+/// * `target_sample_rate` - Sample rate to resample the audio to.
+///
+/// # Returns
+///
+/// * The audio samples and the length of the audio in samples at the target sample rate.
+pub fn load_and_convert_raw_audio<P: AsRef<Path>>(
+    path: P,
+    format: &RawAudioFormat,
+    range: &TimeRange,
+    target_sample_rate: u32,
+) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+    check_raw_audio_format(format)?;
+    let mut file = File::open(path)?;
+    let bytes_per_frame = format.pcm_format.bytes_per_sample() * format.channels as usize;
+    let (start, n_frames) = range.frames(format.sample_rate);
+    check_range_start(start, file.metadata()?.len() as usize / bytes_per_frame, format.sample_rate)?;
+    file.seek(SeekFrom::Start((start * bytes_per_frame) as u64))?;
+    convert_raw_audio(BufReader::new(file), format, n_frames, target_sample_rate)
+}
+
+/// Load headerless PCM audio from a reader as mono audio, normalized to [-1, 1] and resampled to the target sample rate.
+///
+/// # Arguments
+///
+/// * `reader` - Where to read the PCM data from, e.g. stdin.
+/// * `format` - The layout of the data. The channels are mixed down by averaging them.
+/// * `range` - The segment to load. The data before it is read and dropped since the reader can't seek.
+/// * `target_sample_rate` - Sample rate to resample the audio to.
+///
+/// # Returns
+///
+/// * The audio samples and the length of the audio in samples at the target sample rate.
+pub fn load_and_convert_raw_audio_from_reader<R: Read>(
+    mut reader: R,
+    format: &RawAudioFormat,
+    range: &TimeRange,
+    target_sample_rate: u32,
+) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+    check_raw_audio_format(format)?;
+    let bytes_per_frame = format.pcm_format.bytes_per_sample() * format.channels as usize;
+    let (start, n_frames) = range.frames(format.sample_rate);
+    let skipped = io::copy(&mut reader.by_ref().take((start * bytes_per_frame) as u64), &mut io::sink())?;
+    // the length of the stream is only known if it ends before the start
+    if (skipped as usize) < start * bytes_per_frame {
+        check_range_start(start, skipped as usize / bytes_per_frame, format.sample_rate)?;
     }
+    convert_raw_audio(reader, format, n_frames, target_sample_rate)
+}
 
-    let mut channel_data: Vec<Vec<f64>> = vec![Vec::new()];
-    for &sample in samples.iter() {
-        channel_data[0].push(sample as f64 / max_sample_value as f64);
+fn check_raw_audio_format(format: &RawAudioFormat) -> Result<(), Box<dyn Error>> {
+    if format.channels == 0 || format.sample_rate == 0 {
+        return Err("the PCM format needs at least one channel and a sample rate".into());
     }
+    Ok(())
+}
+
+/// Convert PCM data from the current position of a reader, keeping at most `max_frames` frames.
+fn convert_raw_audio<R: Read>(
+    reader: R,
+    format: &RawAudioFormat,
+    max_frames: Option<usize>,
+    target_sample_rate: u32,
+) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+    let bytes_per_frame = format.pcm_format.bytes_per_sample() * format.channels as usize;
+    let mut data = vec![];
+    reader.take(max_frames.map_or(u64::MAX, |n_frames| (n_frames * bytes_per_frame) as u64)).read_to_end(&mut data)?;
+
+    // an incomplete sample frame at the end, e.g. from a cut-off pipe, is dropped
+    let channels = format.channels as usize;
+    let mono = data
+        .chunks_exact(format.pcm_format.bytes_per_sample() * channels)
+        .map(|frame| {
+            let sum: f64 = frame.chunks_exact(format.pcm_format.bytes_per_sample()).map(|sample| format.pcm_format.decode_sample(sample)).sum();
+            sum / channels as f64
+        })
+        .collect();
+    resample(mono, format.sample_rate, target_sample_rate)
+}
+
+/// Resample mono audio to the target sample rate.
+fn resample(samples: Vec<f64>, sample_rate: u32, target_sample_rate: u32) -> Result<(Array1<f32>, usize), Box<dyn Error>> {
+    let duration = samples.len();
+    let channel_data = vec![samples];
 
     let params = SincInterpolationParameters {
         sinc_len: 256,
@@ -48,9 +360,9 @@ fn load_and_convert_audio<P: AsRef<Path>>(path: P, target_sample_rate: u32) -> R
         window: WindowFunction::BlackmanHarris2,
     };
 
-    let resample_ratio = target_sample_rate as f64 / spec.sample_rate as f64;
+    let resample_ratio = target_sample_rate as f64 / sample_rate as f64;
     let mut resampler = SincFixedIn::<f64>::new(
-        target_sample_rate as f64 / spec.sample_rate as f64,
+        target_sample_rate as f64 / sample_rate as f64,
         2.0,
         params,
         duration,
@@ -70,7 +382,25 @@ pub fn get_audio_input(
     hop_size: usize,
 ) -> Result<(Vec<Array2<f32>>, usize), Box<dyn Error>> {
     let (audio_original, original_length) = load_and_convert_audio(audio_path, AUDIO_SAMPLE_RATE as u32)?;
-    
+    Ok((window_audio_input(&audio_original, overlap_len, hop_size)?, original_length))
+}
+
+/// Split audio at the model sample rate into the windows the model is run on.
+///
+/// # Arguments
+///
+/// * `audio_original` - Audio samples at the model sample rate.
+/// * `overlap_len` - Number of samples consecutive windows overlap.
+/// * `hop_size` - Number of samples between the starts of consecutive windows.
+///
+/// # Returns
+///
+/// * The windows, each with a leading batch axis.
+pub fn window_audio_input(
+    audio_original: &Array1<f32>,
+    overlap_len: usize,
+    hop_size: usize,
+) -> Result<Vec<Array2<f32>>, Box<dyn Error>> {
     // Padding with half the overlap length
     let padding = Array1::zeros(overlap_len / 2);
     let padded_audio = concatenate(Axis(0), &[padding.view(), audio_original.view()])?;
@@ -82,5 +412,118 @@ pub fn get_audio_input(
         audio_windows.push(expanded_window);
     }
 
-    Ok((audio_windows, original_length))
-}
\ No newline at end of file
+    Ok(audio_windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use hound::{SampleFormat, WavSpec, WavWriter};
+
+    use super::{load_and_convert_audio_from_reader, load_and_convert_raw_audio_from_reader, PcmDecoder, PcmFormat, RawAudioFormat, TimeRange};
+
+    /// 16-bit WAV data of `n_frames` frames of 0.5, cut off after `kept_bytes` bytes of sample data.
+    fn truncated_wav(channels: u16, n_frames: usize, kept_bytes: usize) -> Vec<u8> {
+        let spec = WavSpec { channels, sample_rate: 22050, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        let mut data = Cursor::new(vec![]);
+        let mut writer = WavWriter::new(&mut data, spec).unwrap();
+        for _ in 0..n_frames * channels as usize {
+            writer.write_sample(16384i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut data = data.into_inner();
+        let header_len = data.len() - n_frames * channels as usize * 2;
+        data.truncate(header_len + kept_bytes);
+        data
+    }
+
+    #[test]
+    fn samples_split_across_pieces_are_decoded() {
+        let mut decoder = PcmDecoder::new(PcmFormat::S16Le);
+        assert_eq!(decoder.decode(&[0xff, 0x7f, 0x01]), vec![1.0]);
+        assert_eq!(decoder.decode(&[0x80]), vec![-1.0]);
+        assert!(decoder.decode(&[]).is_empty());
+
+        let mut decoder = PcmDecoder::new(PcmFormat::S24Le);
+        assert_eq!(decoder.decode(&[0xff, 0xff, 0x7f, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00]), vec![1.0, -1.0, 0.0]);
+    }
+
+    #[test]
+    fn every_pcm_format_is_decoded() {
+        let cases: [(PcmFormat, Vec<u8>, [f32; 3]); 5] = [
+            (PcmFormat::S16Le, [i16::MAX, -i16::MAX, 0].iter().flat_map(|s| s.to_le_bytes()).collect(), [1.0, -1.0, 0.0]),
+            (PcmFormat::S24Le, vec![0xff, 0xff, 0x7f, 0x01, 0x00, 0x80, 0x00, 0x00, 0xc0], [1.0, -1.0, -0.5]),
+            (PcmFormat::S32Le, [i32::MAX, -i32::MAX, i32::MAX / 2].iter().flat_map(|s| s.to_le_bytes()).collect(), [1.0, -1.0, 0.5]),
+            (PcmFormat::F32Le, [0.25f32, -1.0, 0.0].iter().flat_map(|s| s.to_le_bytes()).collect(), [0.25, -1.0, 0.0]),
+            (PcmFormat::F64Le, [0.125f64, -0.5, 1.0].iter().flat_map(|s| s.to_le_bytes()).collect(), [0.125, -0.5, 1.0]),
+        ];
+
+        for (format, bytes, expected) in cases {
+            let whole = PcmDecoder::new(format).decode(&bytes);
+            assert_eq!(whole.len(), 3, "{:?}", format);
+            for (sample, expected) in whole.iter().zip(expected) {
+                assert!((sample - expected).abs() < 1e-6, "{:?}: {} != {}", format, sample, expected);
+            }
+
+            // split at every byte, the samples come out the same
+            for split in 1..bytes.len() {
+                let mut decoder = PcmDecoder::new(format);
+                let mut pieces = decoder.decode(&bytes[..split]);
+                assert_eq!(pieces.len(), split / format.bytes_per_sample(), "{:?} split at {}", format, split);
+                pieces.extend(decoder.decode(&bytes[split..]));
+                assert_eq!(pieces, whole, "{:?} split at {}", format, split);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_trailing_frames_split_across_reads_are_dropped() {
+        // stereo s24le frames of 0.5 / 0.25, read in two pieces that split the second frame, and half a frame at the end
+        let frame = [0x00, 0x00, 0x40, 0x00, 0x00, 0x20];
+        let data: Vec<u8> = frame.iter().cycle().take(frame.len() * 100 + 4).copied().collect();
+        let format = RawAudioFormat { pcm_format: PcmFormat::S24Le, sample_rate: 22050, channels: 2 };
+        let reader = std::io::Read::chain(&data[..8], &data[8..]);
+        let (audio, length) = load_and_convert_raw_audio_from_reader(reader, &format, &TimeRange::default(), 22050).unwrap();
+        assert_eq!(length, 100);
+        assert!(audio.iter().take(length).all(|sample| (sample - 0.375).abs() < 1e-3));
+    }
+
+    #[test]
+    fn raw_audio_channels_are_mixed_down() {
+        // stereo 0.5 / -0.25 frames
+        let data: Vec<u8> = (0..1000).flat_map(|_| [0.5f32.to_le_bytes(), (-0.25f32).to_le_bytes()].concat()).collect();
+        let format = RawAudioFormat { pcm_format: PcmFormat::F32Le, sample_rate: 22050, channels: 2 };
+        let (audio, length) = load_and_convert_raw_audio_from_reader(data.as_slice(), &format, &TimeRange::default(), 22050).unwrap();
+        assert_eq!(length, 1000);
+        assert!((audio[500] - 0.125).abs() < 1e-3);
+    }
+
+    #[test]
+    fn only_the_time_range_is_loaded() {
+        // one second of 0.5 and one second of -0.5
+        let data: Vec<u8> = (0..44100).flat_map(|i| if i < 22050 { 0.5f32 } else { -0.5f32 }.to_le_bytes()).collect();
+        let format = RawAudioFormat { pcm_format: PcmFormat::F32Le, sample_rate: 22050, channels: 1 };
+        let range = TimeRange::new(1.0, Some(1.5)).unwrap();
+        let (audio, length) = load_and_convert_raw_audio_from_reader(data.as_slice(), &format, &range, 22050).unwrap();
+        assert_eq!(length, 11025);
+        assert!((audio[5000] + 0.5).abs() < 1e-3);
+
+        let range = TimeRange::new(3.0, None).unwrap();
+        assert!(load_and_convert_raw_audio_from_reader(data.as_slice(), &format, &range, 22050).is_err());
+        assert!(TimeRange::new(2.0, Some(1.0)).is_err());
+    }
+
+    #[test]
+    fn truncated_wav_data_ends_after_the_last_full_sample() {
+        // the header promises 1000 frames, 600 and a half sample arrive
+        let (audio, length) = load_and_convert_audio_from_reader(truncated_wav(1, 1000, 1201).as_slice(), &TimeRange::default(), 22050).unwrap();
+        assert_eq!(length, 600);
+        assert!((audio[300] - 0.5).abs() < 1e-3);
+
+        // 300 full stereo frames and the left sample of the next one
+        let (_, length) = load_and_convert_audio_from_reader(truncated_wav(2, 1000, 1202).as_slice(), &TimeRange::default(), 22050).unwrap();
+        assert_eq!(length, 300);
+    }
+}
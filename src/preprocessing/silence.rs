@@ -0,0 +1,61 @@
+use ndarray::ArrayView1;
+
+/// Detects windows of audio that are too quiet to contain notes, so the model doesn't have to be run on them.
+///
+/// A window is silent if both its RMS level and its peak level are below the thresholds, so short
+/// quiet notes in a window with a low RMS level still reach the model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceGate {
+    /// RMS level in dBFS below which a window can be silent.
+    pub rms_threshold_db: f32,
+    /// Peak level in dBFS below which a window can be silent.
+    pub peak_threshold_db: f32,
+}
+
+impl Default for SilenceGate {
+    fn default() -> Self {
+        SilenceGate { rms_threshold_db: -60.0, peak_threshold_db: -50.0 }
+    }
+}
+
+impl SilenceGate {
+    /// Whether a window is silent. Digital silence, including the zero padding after the audio, always is.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - Audio samples in [-1, 1].
+    pub fn is_silent(&self, window: ArrayView1<f32>) -> bool {
+        let peak = window.iter().fold(0.0f32, |peak, &sample| peak.max(sample.abs()));
+        if peak == 0.0 {
+            return true;
+        }
+        let rms = (window.iter().map(|&sample| sample as f64 * sample as f64).sum::<f64>() / window.len() as f64).sqrt();
+        (rms as f32) < db_to_amplitude(self.rms_threshold_db) && peak < db_to_amplitude(self.peak_threshold_db)
+    }
+}
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array1;
+
+    use super::SilenceGate;
+
+    #[test]
+    fn quiet_windows_are_silent() {
+        let gate = SilenceGate::default();
+        assert!(gate.is_silent(Array1::zeros(1000).view()));
+        // noise at -70 dBFS
+        let noise = Array1::from_iter((0..1000).map(|i| if i % 2 == 0 { 3e-4 } else { -3e-4 }));
+        assert!(gate.is_silent(noise.view()));
+        // a single click is loud enough to keep the window
+        let mut click = noise.clone();
+        click[500] = 0.1;
+        assert!(!gate.is_silent(click.view()));
+        let tone = Array1::from_iter((0..1000).map(|i| (i as f32 * 0.1).sin() * 0.01));
+        assert!(!gate.is_silent(tone.view()));
+    }
+}
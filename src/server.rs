@@ -0,0 +1,497 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{BufRead, BufReader, Cursor, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use ort::Session;
+
+use crate::{
+    batch::midi_notes_and_channels,
+    inference::run_inference_on_reader,
+    model_outputs::{write_model_outputs_npz, ModelOutput, ModelOutputCache},
+    postprocessing::{
+        midi::{generate_midi_file_data, MidiOptions},
+        note_event_frames::{minimum_note_length_frames, NoteDecodingOptions},
+        note_list::write_note_events_json,
+    },
+    preprocessing::load_audio::TimeRange,
+};
+
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// Settings for the transcription server.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Maximum size of an uploaded audio file in bytes.
+    pub max_request_bytes: usize,
+    /// Maximum number of transcriptions at the same time, counted from the start of the upload.
+    /// Requests beyond that get a 503 response without their body being read.
+    pub max_concurrent_requests: usize,
+    /// Decoding options for requests that don't set them in the query.
+    pub decoding: NoteDecodingOptions,
+    /// Cache for the model outputs of uploaded audio, if any.
+    pub cache: Option<ModelOutputCache>,
+    /// Maximum time to wait for a client to send data.
+    pub read_timeout: Duration,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            max_request_bytes: 64 * 1024 * 1024,
+            max_concurrent_requests: 2,
+            decoding: NoteDecodingOptions::default(),
+            cache: None,
+            read_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What a transcription request returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseFormat {
+    /// The note events as a JSON array.
+    Json,
+    /// A MIDI file.
+    Midi,
+    /// The model outputs as NPZ, to decode with other settings later.
+    ModelOutputs,
+}
+
+/// The settings of a transcription request, from its query parameters.
+#[derive(Debug, Clone)]
+pub struct TranscriptionRequest {
+    pub format: ResponseFormat,
+    pub decoding: NoteDecodingOptions,
+    pub midi_tempo: u32,
+    pub multiple_pitch_bends: bool,
+}
+
+/// The request line and headers of an HTTP request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Header values by lowercase name.
+    pub headers: HashMap<String, String>,
+}
+
+/// An HTTP response.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json(status: u16, value: &serde_json::Value) -> Self {
+        HttpResponse { status, content_type: "application/json", body: value.to_string().into_bytes() }
+    }
+
+    pub(crate) fn error(status: u16, message: &str) -> Self {
+        HttpResponse::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    /// Write the response, the connection is closed afterwards.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        if self.status == 503 {
+            write!(writer, "Retry-After: 1\r\n")?;
+        }
+        write!(writer, "\r\n")?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Read the request line and headers of an HTTP/1.1 request.
+///
+/// # Arguments
+///
+/// * `reader` - The connection.
+///
+/// # Returns
+///
+/// * The request head, or the error response to send.
+pub fn read_request_head<R: BufRead>(reader: &mut R) -> Result<RequestHead, HttpResponse> {
+    let mut lines = vec![];
+    let mut n_bytes = 0;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line).map_err(|e| HttpResponse::error(400, &e.to_string()))?;
+        n_bytes += n;
+        if n_bytes > MAX_HEAD_BYTES {
+            return Err(HttpResponse::error(431, "request head too large"));
+        }
+        if n == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        lines.push(line.trim_end().to_string());
+    }
+
+    let request_line = lines.first().ok_or_else(|| HttpResponse::error(400, "empty request"))?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(HttpResponse::error(400, "invalid request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let headers = lines[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    Ok(RequestHead { method: method.to_string(), path: path.to_string(), query: parse_query(query), headers })
+}
+
+/// Parse the query string of a URL into its parameters.
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Read the settings of a transcription request from its query parameters.
+///
+/// The parameters have the names of the command-line options: `format` (`json`, `midi` or `npz`),
+/// `onset_threshold`, `frame_threshold`, `minimum_note_length` (in milliseconds), `minimum_frequency`,
+/// `maximum_frequency`, `melodia`, `energy_tolerance`, `multiple_pitch_bends` and `midi_tempo`.
+///
+/// # Arguments
+///
+/// * `query` - The query parameters.
+/// * `decoding` - Decoding options for the parameters that aren't set.
+///
+/// # Returns
+///
+/// * The request settings, or a description of the invalid parameter.
+pub fn parse_transcription_request(query: &HashMap<String, String>, decoding: &NoteDecodingOptions) -> Result<TranscriptionRequest, String> {
+    fn value<T: std::str::FromStr>(query: &HashMap<String, String>, name: &str) -> Result<Option<T>, String> {
+        query.get(name).map(|v| v.parse::<T>().map_err(|_| format!("invalid {} '{}'", name, v))).transpose()
+    }
+
+    let mut request = TranscriptionRequest {
+        format: ResponseFormat::Json,
+        decoding: decoding.clone(),
+        midi_tempo: 120,
+        multiple_pitch_bends: false,
+    };
+
+    if let Some(format) = query.get("format") {
+        request.format = match format.as_str() {
+            "json" => ResponseFormat::Json,
+            "midi" | "mid" => ResponseFormat::Midi,
+            "npz" => ResponseFormat::ModelOutputs,
+            _ => return Err(format!("invalid format '{}', expected json, midi or npz", format)),
+        };
+    }
+
+    let decoding = &mut request.decoding;
+    if let Some(v) = value(query, "onset_threshold")? {
+        decoding.onset_thresh = v;
+    }
+    if let Some(v) = value(query, "frame_threshold")? {
+        decoding.frame_thresh = v;
+    }
+    if let Some(v) = value(query, "minimum_note_length")? {
+        decoding.min_note_len = minimum_note_length_frames(v);
+    }
+    if let Some(v) = value(query, "minimum_frequency")? {
+        decoding.min_freq = Some(v);
+    }
+    if let Some(v) = value(query, "maximum_frequency")? {
+        decoding.max_freq = Some(v);
+    }
+    if let Some(v) = value(query, "melodia")? {
+        decoding.melodia_trick = v;
+    }
+    if let Some(v) = value(query, "energy_tolerance")? {
+        decoding.energy_tolerance = v;
+    }
+    if let Some(v) = value(query, "monophonic")? {
+        decoding.monophonic = v;
+    }
+    if let Some(v) = value(query, "multiple_pitch_bends")? {
+        request.multiple_pitch_bends = v;
+    }
+    if let Some(v) = value(query, "midi_tempo")? {
+        request.midi_tempo = v;
+    }
+
+    Ok(request)
+}
+
+/// Serve the HTTP API on a listener until the process ends, with one thread per connection and a
+/// shared model.
+///
+/// * `GET /health` returns the status and number of requests being uploaded or transcribed.
+/// * `POST /transcribe` transcribes the WAV file in the request body, see `parse_transcription_request`
+///   for the query parameters.
+///
+/// # Arguments
+///
+/// * `listener` - The socket to accept connections on.
+/// * `model` - The model session from `load_model`.
+/// * `options` - Settings for the server.
+pub fn serve(listener: TcpListener, model: &Session, options: &ServerOptions) -> Result<(), Box<dyn Error>> {
+    let active_requests = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("error: could not accept connection: {}", e);
+                    continue;
+                }
+            };
+            let active_requests = &active_requests;
+            scope.spawn(move || {
+                if let Err(e) = handle_connection(stream, model, options, active_requests) {
+                    eprintln!("error: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, model: &Session, options: &ServerOptions, active_requests: &AtomicUsize) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(options.read_timeout))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let head = match read_request_head(&mut reader) {
+        Ok(head) => head,
+        Err(response) => return response.write_to(&mut writer),
+    };
+
+    let response = match (head.method.as_str(), head.path.as_str()) {
+        ("GET", "/health") => HttpResponse::json(200, &serde_json::json!({
+            "status": "ok",
+            "active_requests": active_requests.load(Ordering::Relaxed),
+            "max_concurrent_requests": options.max_concurrent_requests,
+        })),
+        ("POST", "/transcribe") => {
+            // take a slot before the body is read, so at most that many uploads are buffered at once
+            let Some(_slot) = RequestSlot::acquire(active_requests, options.max_concurrent_requests) else {
+                return HttpResponse::error(503, "too many transcriptions in progress, try again later").write_to(&mut writer);
+            };
+            let body = match read_body(&head, &mut reader, &mut writer, options.max_request_bytes) {
+                Ok(body) => body,
+                Err(response) => return response.write_to(&mut writer),
+            };
+            transcribe(&head, &body, model, options)
+        }
+        (_, "/health") | (_, "/transcribe") => HttpResponse::error(405, "method not allowed"),
+        _ => HttpResponse::error(404, "not found"),
+    };
+    response.write_to(&mut writer)
+}
+
+/// One of a limited number of transcriptions or streams, released when dropped, also if the request panics.
+pub struct RequestSlot<'a> {
+    active_requests: &'a AtomicUsize,
+}
+
+impl<'a> RequestSlot<'a> {
+    /// Take a slot, or None if all are taken.
+    pub fn acquire(active_requests: &'a AtomicUsize, max_concurrent_requests: usize) -> Option<Self> {
+        active_requests
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| (active < max_concurrent_requests).then_some(active + 1))
+            .ok()
+            .map(|_| RequestSlot { active_requests })
+    }
+}
+
+impl Drop for RequestSlot<'_> {
+    fn drop(&mut self) {
+        self.active_requests.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn read_body<R: Read, W: Write>(head: &RequestHead, reader: &mut R, writer: &mut W, max_bytes: usize) -> Result<Vec<u8>, HttpResponse> {
+    let content_length = head
+        .headers
+        .get("content-length")
+        .ok_or_else(|| HttpResponse::error(411, "the request needs a Content-Length header"))?
+        .parse::<usize>()
+        .map_err(|_| HttpResponse::error(400, "invalid Content-Length header"))?;
+    if content_length > max_bytes {
+        return Err(HttpResponse::error(413, &format!("the request body is larger than {} bytes", max_bytes)));
+    }
+
+    // clients such as curl wait for this before uploading larger bodies
+    if head.headers.get("expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue")) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").map_err(|e| HttpResponse::error(400, &e.to_string()))?;
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).map_err(|e| HttpResponse::error(400, &format!("could not read the request body: {}", e)))?;
+    Ok(body)
+}
+
+fn transcribe(head: &RequestHead, body: &[u8], model: &Session, options: &ServerOptions) -> HttpResponse {
+    let request = match parse_transcription_request(&head.query, &options.decoding) {
+        Ok(request) => request,
+        Err(e) => return HttpResponse::error(400, &e),
+    };
+
+    let model_output = match model_output(body, model, options.cache.as_ref()) {
+        Ok(model_output) => model_output,
+        Err(e) => return HttpResponse::error(400, &format!("could not transcribe the audio: {}", e)),
+    };
+
+    let response = match request.format {
+        ResponseFormat::ModelOutputs => {
+            let mut buffer = Cursor::new(vec![]);
+            write_model_outputs_npz(&mut buffer, &model_output)
+                .map(|_| HttpResponse { status: 200, content_type: "application/octet-stream", body: buffer.into_inner() })
+        }
+        ResponseFormat::Json => {
+            let notes = model_output.decode(&request.decoding);
+            let mut body = vec![];
+            write_note_events_json(&notes, &mut body).map(|_| HttpResponse { status: 200, content_type: "application/json", body })
+        }
+        ResponseFormat::Midi => {
+            let (notes, channel_allocation) = midi_notes_and_channels(model_output.decode(&request.decoding), request.multiple_pitch_bends);
+            let midi_options = MidiOptions { channel_allocation, ..MidiOptions::default() };
+            Ok(HttpResponse { status: 200, content_type: "audio/midi", body: generate_midi_file_data(&notes, request.midi_tempo, &midi_options) })
+        }
+    };
+    response.unwrap_or_else(|e| HttpResponse::error(500, &e.to_string()))
+}
+
+fn model_output(audio_data: &[u8], model: &Session, cache: Option<&ModelOutputCache>) -> Result<ModelOutput, Box<dyn Error>> {
+    if let Some(model_output) = cache.map(|cache| cache.get_for_data(audio_data)).transpose()?.flatten() {
+        return Ok(model_output);
+    }
+
+    let model_output = run_inference_on_reader(audio_data, &TimeRange::default(), model)?;
+    if let Some(cache) = cache {
+        cache.insert_for_data(audio_data, &model_output)?;
+    }
+    Ok(model_output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::atomic::{AtomicUsize, Ordering}};
+
+    use crate::postprocessing::note_event_frames::NoteDecodingOptions;
+
+    use super::{parse_query, parse_transcription_request, read_request_head, RequestSlot, ResponseFormat};
+
+    #[test]
+    fn request_heads_are_parsed() {
+        let mut request = Cursor::new(b"POST /transcribe?format=midi&onset_threshold=0.6 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nRIFF".to_vec());
+        let head = read_request_head(&mut request).unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.path, "/transcribe");
+        assert_eq!(head.query["format"], "midi");
+        assert_eq!(head.headers["content-length"], "4");
+        // the body is left for the caller
+        assert_eq!(request.position(), request.get_ref().len() as u64 - 4);
+    }
+
+    #[test]
+    fn query_parameters_are_decoded() {
+        let query = parse_query("a=1%2C2&b=x+y&c&d=%zz");
+        assert_eq!(query["a"], "1,2");
+        assert_eq!(query["b"], "x y");
+        assert_eq!(query["c"], "");
+        assert_eq!(query["d"], "%zz");
+    }
+
+    #[test]
+    fn query_parameters_map_to_decoding_options() {
+        let query = parse_query("format=npz&frame_threshold=0.4&minimum_note_length=58&melodia=false&maximum_frequency=1000");
+        let request = parse_transcription_request(&query, &NoteDecodingOptions::default()).unwrap();
+        assert_eq!(request.format, ResponseFormat::ModelOutputs);
+        assert_eq!(request.decoding.frame_thresh, 0.4);
+        assert_eq!(request.decoding.min_note_len, 5);
+        assert!(!request.decoding.melodia_trick);
+        assert_eq!(request.decoding.max_freq, Some(1000.0));
+        assert_eq!(request.decoding.onset_thresh, 0.5);
+
+        assert!(parse_transcription_request(&parse_query("onset_threshold=high"), &NoteDecodingOptions::default()).is_err());
+        assert!(parse_transcription_request(&parse_query("format=wav"), &NoteDecodingOptions::default()).is_err());
+    }
+
+    #[test]
+    fn request_slots_are_released_when_dropped() {
+        let active_requests = AtomicUsize::new(0);
+        let first = RequestSlot::acquire(&active_requests, 2).unwrap();
+        let second = RequestSlot::acquire(&active_requests, 2).unwrap();
+        assert!(RequestSlot::acquire(&active_requests, 2).is_none());
+        drop(first);
+        assert_eq!(active_requests.load(Ordering::Relaxed), 1);
+
+        // a transcription that panics gives its slot back
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _slot = RequestSlot::acquire(&active_requests, 2).unwrap();
+            panic!("transcription failed");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(active_requests.load(Ordering::Relaxed), 1);
+        drop(second);
+        assert_eq!(active_requests.load(Ordering::Relaxed), 0);
+    }
+}
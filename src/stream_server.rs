@@ -0,0 +1,430 @@
+use std::{
+    error::Error,
+    io::{self, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+    time::Duration,
+};
+
+use ort::Session;
+
+use crate::{
+    osc::OscSender,
+    preprocessing::load_audio::{PcmDecoder, PcmFormat},
+    server::{parse_transcription_request, read_request_head, HttpResponse, RequestHead, RequestSlot},
+    streaming::{StreamEvent, StreamingOptions, StreamingTranscriber},
+};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Settings for the streaming server.
+#[derive(Debug, Clone)]
+pub struct StreamServerOptions {
+    /// Settings for transcribing the streams. WebSocket clients can change the decoding options in the query.
+    pub streaming: StreamingOptions,
+    /// Sample format on plain TCP connections, and on WebSocket connections that don't set `format` in the query.
+    pub pcm_format: PcmFormat,
+    /// Maximum number of streams at the same time. Connections beyond that are turned away.
+    pub max_connections: usize,
+    /// Maximum time to wait for a client to send data.
+    pub read_timeout: Duration,
+    /// Also send the events of all streams as Open Sound Control messages, if set.
+    pub osc: Option<Arc<OscSender>>,
+}
+
+impl Default for StreamServerOptions {
+    fn default() -> Self {
+        StreamServerOptions {
+            streaming: StreamingOptions::default(),
+            pcm_format: PcmFormat::S16Le,
+            max_connections: 4,
+            read_timeout: Duration::from_secs(30),
+            osc: None,
+        }
+    }
+}
+
+/// A frame of the WebSocket protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketFrame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Read a WebSocket frame and unmask its payload.
+///
+/// # Arguments
+///
+/// * `reader` - The connection.
+/// * `max_payload_bytes` - Frames with a larger payload are an `InvalidData` error.
+///
+/// # Returns
+///
+/// * The frame.
+pub fn read_websocket_frame<R: Read>(reader: &mut R, max_payload_bytes: usize) -> io::Result<WebSocketFrame> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    let payload_len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if payload_len > max_payload_bytes as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+
+    let mut mask = [0u8; 4];
+    if head[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; payload_len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(WebSocketFrame { fin: head[0] & 0x80 != 0, opcode: head[0] & 0x0f, payload })
+}
+
+/// Write an unmasked WebSocket frame, as a server sends them.
+pub fn write_websocket_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut head = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head)?;
+    writer.write_all(payload)
+}
+
+/// The `Sec-WebSocket-Accept` header value for the `Sec-WebSocket-Key` of a handshake request.
+pub fn websocket_accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{}{}", key.trim(), WEBSOCKET_GUID).as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, new) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(new);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | ((byte as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((bits >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Serve live transcription until the process ends, with one thread per connection and a shared model.
+///
+/// * Plain TCP: the client sends PCM audio and shuts down its sending side at the end of the stream.
+///   The events are sent back as one JSON object per line.
+/// * WebSocket: the client sends PCM audio in binary messages and the text message `end` at the end
+///   of the stream. The events are sent back as text messages, followed by `{"type":"end"}`. The query
+///   can set the sample format (`format=s16le` or `f32le`) and the decoding options, with the names
+///   of `parse_transcription_request`.
+///
+/// Events are `{"type":"note_on","time":..,"pitch":..,"velocity":..}`, `{"type":"pitch_bend","time":..,"pitch":..,"bend":..}`
+/// and `{"type":"note_off","time":..,"pitch":..}`, with times in seconds since the start of the stream.
+///
+/// # Arguments
+///
+/// * `websocket` - The socket to accept WebSocket connections on, if any.
+/// * `tcp` - The socket to accept plain TCP connections on, if any.
+/// * `model` - The model session from `load_model`.
+/// * `options` - Settings for the server.
+pub fn serve_streams(
+    websocket: Option<TcpListener>,
+    tcp: Option<TcpListener>,
+    model: &Session,
+    options: &StreamServerOptions,
+) -> Result<(), Box<dyn Error>> {
+    let active_streams = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for (listener, is_websocket) in [(websocket, true), (tcp, false)] {
+            let Some(listener) = listener else {
+                continue;
+            };
+            let active_streams = &active_streams;
+            scope.spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("error: could not accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    scope.spawn(move || {
+                        // released when the stream ends, also if transcribing it panics
+                        let slot = RequestSlot::acquire(active_streams, options.max_connections);
+                        let full = slot.is_none();
+                        let result = stream.set_read_timeout(Some(options.read_timeout)).and_then(|_| {
+                            if is_websocket {
+                                handle_websocket(stream, model, options, full)
+                            } else {
+                                handle_tcp(stream, model, options, full)
+                            }
+                        });
+                        drop(slot);
+                        if let Err(e) = result {
+                            eprintln!("error: {}", e);
+                        }
+                    });
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle_tcp(stream: TcpStream, model: &Session, options: &StreamServerOptions, full: bool) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    if full {
+        return send_message(&mut writer, &error_message("too many streams, try again later"), false);
+    }
+
+    let mut reader = stream;
+    let mut decoder = PcmDecoder::new(options.pcm_format);
+    let mut transcriber = StreamingTranscriber::new(options.streaming.clone());
+    let mut buffer = vec![0u8; 16 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return send_events(&mut writer, transcriber.finish(model), false, options);
+        }
+        send_events(&mut writer, transcriber.push_samples(&decoder.decode(&buffer[..n]), model), false, options)?;
+    }
+}
+
+fn handle_websocket(stream: TcpStream, model: &Session, options: &StreamServerOptions, full: bool) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let head = match read_request_head(&mut reader) {
+        Ok(head) => head,
+        Err(response) => return response.write_to(&mut writer),
+    };
+    let (key, format, streaming) = match websocket_settings(&head, options) {
+        Ok(settings) => settings,
+        Err(response) => return response.write_to(&mut writer),
+    };
+    if full {
+        return HttpResponse::error(503, "too many streams, try again later").write_to(&mut writer);
+    }
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        websocket_accept_key(&key)
+    )?;
+    writer.flush()?;
+
+    let mut decoder = PcmDecoder::new(format);
+    let mut transcriber = StreamingTranscriber::new(streaming);
+    let mut message_opcode = OPCODE_BINARY;
+    let mut text = vec![];
+    loop {
+        let frame = match read_websocket_frame(&mut reader, MAX_FRAME_BYTES) {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return write_websocket_frame(&mut writer, OPCODE_CLOSE, &close_payload(1009, "frame too large"));
+            }
+            Err(e) => return Err(e),
+        };
+        if frame.opcode != OPCODE_CONTINUATION && frame.opcode < OPCODE_CLOSE {
+            message_opcode = frame.opcode;
+        }
+
+        match if frame.opcode == OPCODE_CONTINUATION { message_opcode } else { frame.opcode } {
+            OPCODE_BINARY => {
+                send_events(&mut writer, transcriber.push_samples(&decoder.decode(&frame.payload), model), true, options)?;
+            }
+            OPCODE_TEXT => {
+                text.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    continue;
+                }
+                if String::from_utf8_lossy(&text).trim() != "end" {
+                    send_message(&mut writer, &error_message("unknown message, expected 'end'"), true)?;
+                    text.clear();
+                    continue;
+                }
+                send_events(&mut writer, transcriber.finish(model), true, options)?;
+                send_message(&mut writer, &serde_json::json!({ "type": "end" }).to_string(), true)?;
+                return write_websocket_frame(&mut writer, OPCODE_CLOSE, &close_payload(1000, ""));
+            }
+            OPCODE_PING => write_websocket_frame(&mut writer, OPCODE_PONG, &frame.payload)?,
+            OPCODE_PONG => {}
+            OPCODE_CLOSE => {
+                // echo the status code
+                return write_websocket_frame(&mut writer, OPCODE_CLOSE, &frame.payload[..frame.payload.len().min(2)]);
+            }
+            _ => return write_websocket_frame(&mut writer, OPCODE_CLOSE, &close_payload(1002, "unknown opcode")),
+        }
+    }
+}
+
+/// Check a WebSocket handshake request and read the stream settings from its query.
+fn websocket_settings(head: &RequestHead, options: &StreamServerOptions) -> Result<(String, PcmFormat, StreamingOptions), HttpResponse> {
+    if head.method != "GET" {
+        return Err(HttpResponse::error(405, "method not allowed"));
+    }
+    let is_upgrade = head.headers.get("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let Some(key) = head.headers.get("sec-websocket-key").filter(|_| is_upgrade) else {
+        return Err(HttpResponse::error(400, "expected a WebSocket handshake"));
+    };
+
+    let mut query = head.query.clone();
+    let format = match query.remove("format") {
+        Some(format) => format.parse().map_err(|e: String| HttpResponse::error(400, &e))?,
+        None => options.pcm_format,
+    };
+    let request = parse_transcription_request(&query, &options.streaming.decoding).map_err(|e| HttpResponse::error(400, &e))?;
+
+    Ok((key.clone(), format, StreamingOptions { decoding: request.decoding, ..options.streaming.clone() }))
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+fn error_message(message: &str) -> String {
+    serde_json::json!({ "type": "error", "message": message }).to_string()
+}
+
+/// Send a JSON message, as a WebSocket text message or a line.
+fn send_message<W: Write>(writer: &mut W, message: &str, websocket: bool) -> io::Result<()> {
+    if websocket {
+        write_websocket_frame(writer, OPCODE_TEXT, message.as_bytes())?;
+    } else {
+        writeln!(writer, "{}", message)?;
+    }
+    writer.flush()
+}
+
+/// Send the events of the transcriber, and to OSC if enabled, or its error before ending the connection.
+fn send_events<W: Write>(
+    writer: &mut W,
+    events: Result<Vec<StreamEvent>, Box<dyn Error>>,
+    websocket: bool,
+    options: &StreamServerOptions,
+) -> io::Result<()> {
+    match events {
+        Ok(events) => events.iter().try_for_each(|event| {
+            if let Some(osc) = &options.osc {
+                // the client still gets its events when the OSC receiver is gone
+                if let Err(e) = osc.send(event) {
+                    eprintln!("error: could not send OSC message: {}", e);
+                }
+            }
+            send_message(writer, &serde_json::to_string(event)?, websocket)
+        }),
+        Err(e) => {
+            send_message(writer, &error_message(&e.to_string()), websocket)?;
+            Err(io::Error::other(format!("could not transcribe the stream: {}", e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{read_websocket_frame, websocket_accept_key, write_websocket_frame, WebSocketFrame};
+
+    #[test]
+    fn websocket_accept_key_matches_the_rfc_example() {
+        assert_eq!(websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn websocket_frames_are_read_and_written() {
+        // the masked "Hello" text frame of RFC 6455 section 5.7
+        let mut masked = Cursor::new(vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+        let frame = read_websocket_frame(&mut masked, 1024).unwrap();
+        assert_eq!(frame, WebSocketFrame { fin: true, opcode: 0x1, payload: b"Hello".to_vec() });
+
+        let payload = vec![7u8; 300];
+        let mut written = vec![];
+        write_websocket_frame(&mut written, 0x2, &payload).unwrap();
+        assert_eq!(&written[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(read_websocket_frame(&mut Cursor::new(&written), 1024).unwrap().payload, payload);
+        assert!(read_websocket_frame(&mut Cursor::new(&written), 100).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+use ndarray::{concatenate, s, Array2, ArrayView1, Axis};
+use ort::Session;
+
+use crate::{
+    constants::{
+        ANNOTATIONS_FPS, AUDIO_N_SAMPLES, AUDIO_SAMPLE_RATE, CONTOURS_BINS_PER_SEMITONE, HOP_SIZE, MAX_FREQ_IDX,
+        N_FRAMES_PER_WINDOW, N_FREQ_BINS_CONTOURS, OVERLAP_LEN,
+    },
+    inference::run_inference_on_window,
+    model_outputs::ModelOutput,
+    postprocessing::{
+        helpers::ported::librosa::model_frame_to_time, note_event_frames::NoteDecodingOptions, note_event_times::NoteEventTime,
+    },
+};
+
+/// A note event of a live transcription, with its time in seconds since the start of the stream.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "type", rename_all = "snake_case"))]
+pub enum StreamEvent {
+    /// A note started. `velocity` is the MIDI velocity of the note amplitude.
+    NoteOn { time: f32, pitch: usize, velocity: u8 },
+    /// The pitch of a sounding note changed. `bend` is the offset from the note pitch in semitones.
+    PitchBend { time: f32, pitch: usize, bend: f32 },
+    /// A note ended.
+    NoteOff { time: f32, pitch: usize },
+}
+
+impl StreamEvent {
+    /// Time of the event in seconds since the start of the stream.
+    pub fn time(&self) -> f32 {
+        match self {
+            StreamEvent::NoteOn { time, .. } | StreamEvent::PitchBend { time, .. } | StreamEvent::NoteOff { time, .. } => *time,
+        }
+    }
+
+    /// Order of events at the same time: a note ends before the next one on its key starts.
+    fn order(&self) -> u8 {
+        match self {
+            StreamEvent::NoteOff { .. } => 0,
+            StreamEvent::NoteOn { .. } => 1,
+            StreamEvent::PitchBend { .. } => 2,
+        }
+    }
+}
+
+/// The events of finished notes, e.g. to replay an offline transcription like a live one.
+///
+/// # Arguments
+///
+/// * `notes` - List of time-based note events.
+///
+/// # Returns
+///
+/// * The note on, pitch bend and note off events of the notes, in time order. A pitch bend is only
+///   added when the bend changes.
+pub fn note_events_to_stream_events(notes: &[NoteEventTime]) -> Vec<StreamEvent> {
+    let mut events = vec![];
+    for note in notes {
+        let pitch = note.pitch_midi;
+        events.push(StreamEvent::NoteOn {
+            time: note.start_time_seconds,
+            pitch,
+            velocity: (note.amplitude * 127.0).round().clamp(0.0, 127.0) as u8,
+        });
+
+        let pitch_bends = note.pitch_bends.as_deref().unwrap_or_default();
+        let mut last_bend = 0.0;
+        for (i, &pitch_bend) in pitch_bends.iter().enumerate() {
+            let bend = pitch_bend / CONTOURS_BINS_PER_SEMITONE;
+            if bend != last_bend {
+                let time = note.start_time_seconds + i as f32 * note.duration_seconds / pitch_bends.len() as f32;
+                events.push(StreamEvent::PitchBend { time, pitch, bend });
+                last_bend = bend;
+            }
+        }
+
+        events.push(StreamEvent::NoteOff { time: note.start_time_seconds + note.duration_seconds, pitch });
+    }
+    events.sort_by(|a, b| a.time().total_cmp(&b.time()).then(a.order().cmp(&b.order())));
+    events
+}
+
+/// Settings for live transcription.
+#[derive(Debug, Clone)]
+pub struct StreamingOptions {
+    /// Settings for decoding the model output to note events. `refine_times` and `amplitude_envelopes` are ignored.
+    pub decoding: NoteDecodingOptions,
+    /// Length of the model output that is kept and decoded again for every window, in seconds.
+    /// Notes that are held longer are ended.
+    pub max_context_seconds: f32,
+}
+
+impl Default for StreamingOptions {
+    fn default() -> Self {
+        StreamingOptions { decoding: NoteDecodingOptions::default(), max_context_seconds: 30.0 }
+    }
+}
+
+/// A note that has been started but not ended.
+#[derive(Debug, Clone)]
+struct OpenNote {
+    end_frame: usize,
+    next_bend_frame: usize,
+    bend: f32,
+}
+
+/// Transcribes audio that arrives in pieces, e.g. from a microphone, and reports notes as soon as they are final.
+///
+/// The model runs on windows of `AUDIO_N_SAMPLES` samples, so events arrive up to about two seconds
+/// after they are played. Every window, the kept model output is decoded again and the events up to a
+/// few frames before its end, which later audio can no longer change, are reported.
+#[derive(Debug)]
+pub struct StreamingTranscriber {
+    options: StreamingOptions,
+    samples: Vec<f32>,
+    n_samples: usize,
+    context: ModelOutput,
+    context_start_frame: usize,
+    open_notes: HashMap<(usize, usize), OpenNote>,
+    closed_notes: HashSet<(usize, usize)>,
+}
+
+impl StreamingTranscriber {
+    /// Create a transcriber for a new stream.
+    pub fn new(options: StreamingOptions) -> Self {
+        StreamingTranscriber {
+            options,
+            // the same padding as `window_audio_input`, so the windows match an offline transcription
+            samples: vec![0.0; OVERLAP_LEN / 2],
+            n_samples: 0,
+            context: ModelOutput {
+                contours: Array2::zeros((0, N_FREQ_BINS_CONTOURS)),
+                frames: Array2::zeros((0, MAX_FREQ_IDX + 1)),
+                onsets: Array2::zeros((0, MAX_FREQ_IDX + 1)),
+                audio_n_samples: 0,
+            },
+            context_start_frame: 0,
+            open_notes: HashMap::new(),
+            closed_notes: HashSet::new(),
+        }
+    }
+
+    /// Add audio to the stream and run the model on every window that is complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - Mono audio samples at 22050 Hz, in [-1, 1].
+    /// * `model` - The model session from `load_model`.
+    ///
+    /// # Returns
+    ///
+    /// * The events that became final, in time order.
+    pub fn push_samples(&mut self, samples: &[f32], model: &Session) -> Result<Vec<StreamEvent>, Box<dyn Error>> {
+        self.samples.extend_from_slice(samples);
+        self.n_samples += samples.len();
+
+        let mut events = vec![];
+        while self.samples.len() >= AUDIO_N_SAMPLES {
+            let output = self.run_next_window(model)?;
+            events.extend(self.push_model_output(&output));
+        }
+        Ok(events)
+    }
+
+    /// End the stream: transcribe the rest of the audio and end all notes.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model session from `load_model`.
+    ///
+    /// # Returns
+    ///
+    /// * The remaining events, in time order.
+    pub fn finish(mut self, model: &Session) -> Result<Vec<StreamEvent>, Box<dyn Error>> {
+        // the number of frames `run_inference` keeps for audio of this length
+        let n_frames = ((self.n_samples as f32) * (ANNOTATIONS_FPS as f32 / AUDIO_SAMPLE_RATE as f32)).floor() as usize;
+
+        let mut events = vec![];
+        while self.context_start_frame + self.context.frames.nrows() < n_frames {
+            self.samples.resize(self.samples.len().max(AUDIO_N_SAMPLES), 0.0);
+            let output = self.run_next_window(model)?;
+            events.extend(self.push_model_output(&output));
+        }
+        events.extend(self.finish_model_output(n_frames));
+        Ok(events)
+    }
+
+    fn run_next_window(&mut self, model: &Session) -> Result<ModelOutput, Box<dyn Error>> {
+        let output = run_inference_on_window(ArrayView1::from(&self.samples[..AUDIO_N_SAMPLES]), model)?;
+        self.samples.drain(..HOP_SIZE);
+        Ok(output)
+    }
+
+    /// Add the model output of the next window, for running the model separately.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The output of `run_inference_on_window` for the next window of the stream.
+    ///
+    /// # Returns
+    ///
+    /// * The events that became final, in time order.
+    pub fn push_model_output(&mut self, output: &ModelOutput) -> Vec<StreamEvent> {
+        self.context.contours = concatenate(Axis(0), &[self.context.contours.view(), output.contours.view()]).unwrap();
+        self.context.frames = concatenate(Axis(0), &[self.context.frames.view(), output.frames.view()]).unwrap();
+        self.context.onsets = concatenate(Axis(0), &[self.context.onsets.view(), output.onsets.view()]).unwrap();
+
+        // a note can still be extended while it is within the energy tolerance of the end, and an
+        // onset peak needs the frame after it
+        let margin = self.options.decoding.energy_tolerance + 2;
+        let n_final_frames = self.context.frames.nrows().saturating_sub(margin);
+        let events = self.update(n_final_frames, false);
+        self.trim_context();
+        events
+    }
+
+    /// Decode the complete stream of `n_frames` frames and end all notes.
+    fn finish_model_output(&mut self, n_frames: usize) -> Vec<StreamEvent> {
+        let n_context_frames = n_frames.saturating_sub(self.context_start_frame).min(self.context.frames.nrows());
+        self.context.contours = self.context.contours.slice(s![..n_context_frames, ..]).to_owned();
+        self.context.frames = self.context.frames.slice(s![..n_context_frames, ..]).to_owned();
+        self.context.onsets = self.context.onsets.slice(s![..n_context_frames, ..]).to_owned();
+        self.update(n_context_frames, true)
+    }
+
+    /// Decode the context and report the events before `n_final_frames`, or all of them if `last`.
+    fn update(&mut self, n_final_frames: usize, last: bool) -> Vec<StreamEvent> {
+        let notes = if self.context.frames.nrows() > 1 {
+            self.context.decode_frames(&self.options.decoding)
+        } else {
+            vec![]
+        };
+        let final_frame = self.context_start_frame + n_final_frames;
+
+        let mut events = vec![];
+        let mut decoded = HashSet::new();
+        for note in notes {
+            let start_frame = self.context_start_frame + note.start_frame;
+            let end_frame = start_frame + note.duration_frames;
+            let key = (note.pitch_midi, start_frame);
+            if self.closed_notes.contains(&key) || (!last && start_frame >= final_frame) {
+                continue;
+            }
+            decoded.insert(key);
+
+            let open = self.open_notes.entry(key).or_insert_with(|| {
+                events.push(StreamEvent::NoteOn {
+                    time: model_frame_to_time(start_frame),
+                    pitch: note.pitch_midi,
+                    velocity: (note.amplitude * 127.0).round().clamp(0.0, 127.0) as u8,
+                });
+                OpenNote { end_frame, next_bend_frame: start_frame, bend: 0.0 }
+            });
+            open.end_frame = end_frame;
+
+            if let Some(bends) = &note.pitch_bends {
+                let bends_until = if last { end_frame } else { end_frame.min(final_frame) };
+                while open.next_bend_frame < bends_until {
+                    let bend = bends.get(open.next_bend_frame - start_frame).copied().unwrap_or(0.0) / CONTOURS_BINS_PER_SEMITONE;
+                    if bend != open.bend {
+                        events.push(StreamEvent::PitchBend { time: model_frame_to_time(open.next_bend_frame), pitch: note.pitch_midi, bend });
+                        open.bend = bend;
+                    }
+                    open.next_bend_frame += 1;
+                }
+            }
+
+            if last || end_frame < final_frame {
+                events.push(StreamEvent::NoteOff { time: model_frame_to_time(end_frame), pitch: note.pitch_midi });
+                self.open_notes.remove(&key);
+                self.closed_notes.insert(key);
+            }
+        }
+
+        // notes that more audio turned into something else, or that fell out of the context, end where they were last seen
+        let vanished: Vec<(usize, usize)> = self.open_notes.keys().filter(|key| !decoded.contains(key)).copied().collect();
+        for key in vanished {
+            let open = self.open_notes.remove(&key).unwrap();
+            events.push(StreamEvent::NoteOff { time: model_frame_to_time(open.end_frame), pitch: key.0 });
+            self.closed_notes.insert(key);
+        }
+
+        events.sort_by(|a, b| a.time().total_cmp(&b.time()).then(a.order().cmp(&b.order())));
+        events
+    }
+
+    /// Drop the oldest whole windows of the context when it is longer than the maximum.
+    fn trim_context(&mut self) {
+        let max_windows = ((self.options.max_context_seconds * ANNOTATIONS_FPS as f32) as usize / N_FRAMES_PER_WINDOW).max(2);
+        let n_windows = self.context.frames.nrows() / N_FRAMES_PER_WINDOW;
+        if n_windows <= max_windows {
+            return;
+        }
+
+        // whole windows, so the frame times stay aligned with `model_frame_to_time`
+        let n_dropped = (n_windows - max_windows) * N_FRAMES_PER_WINDOW;
+        self.context.contours = self.context.contours.slice(s![n_dropped.., ..]).to_owned();
+        self.context.frames = self.context.frames.slice(s![n_dropped.., ..]).to_owned();
+        self.context.onsets = self.context.onsets.slice(s![n_dropped.., ..]).to_owned();
+        self.context_start_frame += n_dropped;
+
+        let context_start_frame = self.context_start_frame;
+        self.closed_notes.retain(|&(_, start_frame)| start_frame >= context_start_frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use crate::{
+        constants::{MAX_FREQ_IDX, MIDI_OFFSET, N_FRAMES_PER_WINDOW, N_FREQ_BINS_CONTOURS},
+        model_outputs::ModelOutput,
+        postprocessing::{
+            helpers::ported::librosa::model_frame_to_time, note_event_frames::NoteDecodingOptions, note_event_times::NoteEventTime,
+        },
+    };
+
+    use super::{note_events_to_stream_events, StreamEvent, StreamingOptions, StreamingTranscriber};
+
+    /// The model output of window `index`, with a note on `pitch` over the given absolute frames.
+    fn window(index: usize, pitch: usize, start_frame: usize, end_frame: usize) -> ModelOutput {
+        let mut frames = Array2::zeros((N_FRAMES_PER_WINDOW, MAX_FREQ_IDX + 1));
+        let mut onsets = Array2::zeros((N_FRAMES_PER_WINDOW, MAX_FREQ_IDX + 1));
+        for row in 0..N_FRAMES_PER_WINDOW {
+            let frame = index * N_FRAMES_PER_WINDOW + row;
+            if (start_frame..end_frame).contains(&frame) {
+                frames[[row, pitch - MIDI_OFFSET]] = 0.8;
+            }
+            if frame == start_frame {
+                onsets[[row, pitch - MIDI_OFFSET]] = 0.9;
+            }
+        }
+        ModelOutput { contours: Array2::zeros((N_FRAMES_PER_WINDOW, N_FREQ_BINS_CONTOURS)), frames, onsets, audio_n_samples: 0 }
+    }
+
+    #[test]
+    fn notes_are_reported_once_they_are_final() {
+        let options = StreamingOptions {
+            decoding: NoteDecodingOptions { pitch_bend_bins_tolerance: None, ..NoteDecodingOptions::default() },
+            ..StreamingOptions::default()
+        };
+        let mut transcriber = StreamingTranscriber::new(options);
+
+        // the note continues into the next window, so only its start is final
+        let events = transcriber.push_model_output(&window(0, 60, 100, 200));
+        assert_eq!(events, vec![StreamEvent::NoteOn { time: model_frame_to_time(100), pitch: 60, velocity: 102 }]);
+
+        let events = transcriber.push_model_output(&window(1, 60, 100, 200));
+        assert_eq!(events, vec![StreamEvent::NoteOff { time: model_frame_to_time(200), pitch: 60 }]);
+
+        assert!(transcriber.finish_model_output(2 * N_FRAMES_PER_WINDOW).is_empty());
+    }
+
+    #[test]
+    fn open_notes_end_with_the_stream() {
+        let mut transcriber = StreamingTranscriber::new(StreamingOptions::default());
+        let n_frames = N_FRAMES_PER_WINDOW - 3;
+        assert!(transcriber.push_model_output(&window(0, 72, n_frames - 10, n_frames)).is_empty());
+
+        let events = transcriber.finish_model_output(n_frames);
+        assert!(matches!(events[0], StreamEvent::NoteOn { pitch: 72, .. }));
+        // like offline decoding, a note held to the end ends on the last frame
+        assert_eq!(events.last(), Some(&StreamEvent::NoteOff { time: model_frame_to_time(n_frames - 1), pitch: 72 }));
+    }
+
+    #[test]
+    fn finished_notes_become_events_in_time_order() {
+        let note = |start_time_seconds, pitch_midi, pitch_bends| NoteEventTime {
+            start_time_seconds,
+            duration_seconds: 1.0,
+            pitch_midi,
+            amplitude: 0.5,
+            pitch_bends,
+            amplitude_envelope: None,
+        };
+        let events = note_events_to_stream_events(&[note(1.0, 62, None), note(0.0, 60, Some(vec![0.0, 0.0, 3.0, 3.0]))]);
+        assert_eq!(
+            events,
+            vec![
+                StreamEvent::NoteOn { time: 0.0, pitch: 60, velocity: 64 },
+                StreamEvent::PitchBend { time: 0.5, pitch: 60, bend: 1.0 },
+                StreamEvent::NoteOff { time: 1.0, pitch: 60 },
+                StreamEvent::NoteOn { time: 1.0, pitch: 62, velocity: 64 },
+                StreamEvent::NoteOff { time: 2.0, pitch: 62 },
+            ]
+        );
+    }
+}
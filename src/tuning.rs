@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+
+use crate::{
+    evaluation::{evaluate_notes, NoteMatchingOptions},
+    model_outputs::ModelOutput,
+    postprocessing::{note_event_frames::NoteDecodingOptions, note_event_times::NoteEventTime},
+};
+
+/// Candidate values of the decoding parameters to search over.
+#[derive(Debug, Clone)]
+pub struct TuningSpace {
+    /// Onset thresholds to try.
+    pub onset_thresh: Vec<f32>,
+    /// Frame thresholds to try. NaN stands for the adaptive mean + std threshold.
+    pub frame_thresh: Vec<f32>,
+    /// Minimum note lengths in frames to try.
+    pub min_note_len: Vec<usize>,
+    /// Energy tolerances in frames to try.
+    pub energy_tolerance: Vec<usize>,
+}
+
+impl Default for TuningSpace {
+    fn default() -> Self {
+        TuningSpace {
+            onset_thresh: vec![0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8],
+            frame_thresh: vec![0.1, 0.15, 0.2, 0.25, 0.3, 0.35, 0.4, 0.5, 0.6, f32::NAN],
+            min_note_len: vec![3, 5, 7, 9, 11, 15],
+            energy_tolerance: vec![5, 8, 11, 15, 20],
+        }
+    }
+}
+
+/// The note metric to maximize.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TuningMetric {
+    /// F-measure of notes matched on onset and pitch.
+    NoteOnset,
+    /// F-measure of notes matched on onset, pitch and offset.
+    NoteOnsetOffset,
+}
+
+/// How to search the tuning space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TuningStrategy {
+    /// Try every combination of the candidate values.
+    Grid,
+    /// Starting from the values closest to the base options, optimize one parameter at a time
+    /// while keeping the others fixed, until no parameter improves or `max_rounds` is reached.
+    CoordinateSearch { max_rounds: usize },
+}
+
+/// The outcome of a tuning run.
+#[derive(Debug, Clone)]
+pub struct TuningResult {
+    /// The base options with the best parameters found.
+    pub options: NoteDecodingOptions,
+    /// F-measure of the best options, averaged over files.
+    pub score: f32,
+    /// F-measure of the base options, averaged over files.
+    pub base_score: f32,
+    /// Number of parameter combinations evaluated.
+    pub n_evaluations: usize,
+}
+
+/// Find the decoding parameters that give the highest note F-measure on a set of files.
+///
+/// # Arguments
+///
+/// * `items` - Model outputs of the files with their reference notes.
+/// * `base_options` - Options to start from. Parameters that aren't tuned are kept as they are.
+/// * `space` - Candidate values of the tuned parameters.
+/// * `metric` - The note metric to maximize.
+/// * `strategy` - How to search the tuning space.
+/// * `matching_options` - Tolerances for matching the notes to the references.
+///
+/// # Returns
+///
+/// * The best options found and their score.
+pub fn tune_decoding_options(
+    items: &[(ModelOutput, Vec<NoteEventTime>)],
+    base_options: &NoteDecodingOptions,
+    space: &TuningSpace,
+    metric: TuningMetric,
+    strategy: TuningStrategy,
+    matching_options: &NoteMatchingOptions,
+) -> TuningResult {
+    let sizes = [space.onset_thresh.len(), space.frame_thresh.len(), space.min_note_len.len(), space.energy_tolerance.len()];
+
+    let options_at = |indices: &[usize; 4]| -> NoteDecodingOptions {
+        let mut options = base_options.clone();
+        if sizes[0] > 0 {
+            options.onset_thresh = space.onset_thresh[indices[0]];
+        }
+        if sizes[1] > 0 {
+            options.frame_thresh = space.frame_thresh[indices[1]];
+        }
+        if sizes[2] > 0 {
+            options.min_note_len = space.min_note_len[indices[2]];
+        }
+        if sizes[3] > 0 {
+            options.energy_tolerance = space.energy_tolerance[indices[3]];
+        }
+        options
+    };
+
+    let base_score = score_options(items, base_options, metric, matching_options);
+
+    // the same combination comes up repeatedly in a coordinate search, so scores are kept
+    let mut scores: HashMap<[usize; 4], f32> = HashMap::new();
+    let mut score_at = |indices: [usize; 4]| -> f32 {
+        *scores.entry(indices).or_insert_with(|| score_options(items, &options_at(&indices), metric, matching_options))
+    };
+
+    let mut best_indices = [
+        closest_index(&space.onset_thresh, base_options.onset_thresh),
+        closest_index(&space.frame_thresh, base_options.frame_thresh),
+        closest_index(&space.min_note_len.iter().map(|&v| v as f32).collect::<Vec<_>>(), base_options.min_note_len as f32),
+        closest_index(&space.energy_tolerance.iter().map(|&v| v as f32).collect::<Vec<_>>(), base_options.energy_tolerance as f32),
+    ];
+    let mut best_score = score_at(best_indices);
+
+    match strategy {
+        TuningStrategy::Grid => {
+            let n_combinations: usize = sizes.iter().map(|&size| size.max(1)).product();
+            for combination in 0..n_combinations {
+                let mut remainder = combination;
+                let mut indices = [0; 4];
+                for (index, &size) in indices.iter_mut().zip(sizes.iter()) {
+                    *index = remainder % size.max(1);
+                    remainder /= size.max(1);
+                }
+                let score = score_at(indices);
+                if score > best_score {
+                    (best_indices, best_score) = (indices, score);
+                }
+            }
+        }
+        TuningStrategy::CoordinateSearch { max_rounds } => {
+            for _ in 0..max_rounds {
+                let mut improved = false;
+                for (parameter, &size) in sizes.iter().enumerate() {
+                    for value_idx in 0..size {
+                        let mut indices = best_indices;
+                        indices[parameter] = value_idx;
+                        let score = score_at(indices);
+                        if score > best_score {
+                            (best_indices, best_score) = (indices, score);
+                            improved = true;
+                        }
+                    }
+                }
+                if !improved {
+                    break;
+                }
+            }
+        }
+    }
+
+    // the base options may beat every candidate if they are not on the grid
+    let (options, score) = if base_score >= best_score {
+        (base_options.clone(), base_score)
+    } else {
+        (options_at(&best_indices), best_score)
+    };
+
+    TuningResult {
+        options,
+        score,
+        base_score,
+        n_evaluations: scores.len() + 1,
+    }
+}
+
+/// Mean F-measure of the decoded notes over the files.
+fn score_options(
+    items: &[(ModelOutput, Vec<NoteEventTime>)],
+    options: &NoteDecodingOptions,
+    metric: TuningMetric,
+    matching_options: &NoteMatchingOptions,
+) -> f32 {
+    if items.is_empty() {
+        return 0.0;
+    }
+
+    // pitch bends and envelopes don't change which notes are found
+    let options = NoteDecodingOptions {
+        pitch_bend_bins_tolerance: None,
+        amplitude_envelopes: false,
+        ..options.clone()
+    };
+    let match_offsets = metric == TuningMetric::NoteOnsetOffset;
+
+    let total: f32 = items.iter().map(|(model_output, reference)| {
+        let estimated = model_output.decode(&options);
+        evaluate_notes(reference, &estimated, matching_options, match_offsets).f_measure
+    }).sum();
+    total / items.len() as f32
+}
+
+/// Index of the candidate closest to `value`, NaN only matches NaN.
+fn closest_index(candidates: &[f32], value: f32) -> usize {
+    let distance = |candidate: f32| match (candidate.is_nan(), value.is_nan()) {
+        (true, true) => 0.0,
+        (false, false) => (candidate - value).abs(),
+        _ => f32::INFINITY,
+    };
+    (0..candidates.len())
+        .min_by(|&a, &b| distance(candidates[a]).total_cmp(&distance(candidates[b])))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use crate::{
+        evaluation::NoteMatchingOptions,
+        model_outputs::ModelOutput,
+        postprocessing::{note_event_frames::NoteDecodingOptions, note_event_times::NoteEventTime},
+    };
+
+    use super::{closest_index, tune_decoding_options, TuningMetric, TuningSpace, TuningStrategy};
+
+    /// Notes as (pitch index, start frame, length in frames, onset activation).
+    const NOTES: [(usize, usize, usize, f32); 4] = [(30, 10, 40, 0.95), (40, 60, 40, 0.6), (50, 110, 40, 0.4), (35, 160, 8, 0.9)];
+
+    /// Options that find the first, second and fourth note, but not the third with its weak onset.
+    fn target_options() -> NoteDecodingOptions {
+        NoteDecodingOptions {
+            onset_thresh: 0.5,
+            min_note_len: 5,
+            infer_onsets: false,
+            melodia_trick: false,
+            pitch_bend_bins_tolerance: None,
+            ..NoteDecodingOptions::default()
+        }
+    }
+
+    fn model_output() -> ModelOutput {
+        let n_times = 200;
+        let mut frames = Array2::zeros((n_times, 88));
+        let mut onsets = Array2::zeros((n_times, 88));
+        for (pitch, start, length, onset) in NOTES {
+            onsets[[start, pitch]] = onset;
+            for frame in start..start + length {
+                frames[[frame, pitch]] = 0.9;
+            }
+        }
+        ModelOutput { contours: Array2::zeros((n_times, 264)), frames, onsets, audio_n_samples: 22050 }
+    }
+
+    fn items() -> Vec<(ModelOutput, Vec<NoteEventTime>)> {
+        let model_output = model_output();
+        let reference = model_output.decode(&target_options());
+        assert_eq!(reference.len(), 3);
+        vec![(model_output, reference)]
+    }
+
+    fn space() -> TuningSpace {
+        TuningSpace { onset_thresh: vec![0.3, 0.5, 0.7], frame_thresh: vec![0.3], min_note_len: vec![11, 5], energy_tolerance: vec![11] }
+    }
+
+    #[test]
+    fn both_strategies_find_the_best_options() {
+        // too high an onset threshold and too long a minimum length, both miss a note
+        let base_options = NoteDecodingOptions { onset_thresh: 0.7, min_note_len: 11, ..target_options() };
+
+        let grid = tune_decoding_options(&items(), &base_options, &space(), TuningMetric::NoteOnset, TuningStrategy::Grid, &NoteMatchingOptions::default());
+        let search = tune_decoding_options(
+            &items(),
+            &base_options,
+            &space(),
+            TuningMetric::NoteOnset,
+            TuningStrategy::CoordinateSearch { max_rounds: 5 },
+            &NoteMatchingOptions::default(),
+        );
+
+        for result in [&grid, &search] {
+            assert_eq!((result.options.onset_thresh, result.options.min_note_len), (0.5, 5));
+            assert_eq!(result.score, 1.0);
+            assert!(result.base_score < 1.0);
+        }
+        // every combination and the base options
+        assert_eq!(grid.n_evaluations, 3 * 2 + 1);
+        // the start, the other two onset thresholds, the shorter minimum length, then a round without
+        // improvement that tries the other two onset thresholds with it, and the base options
+        assert_eq!(search.n_evaluations, 1 + 2 + 1 + 2 + 1);
+    }
+
+    #[test]
+    fn base_options_that_beat_the_candidates_are_kept() {
+        let space = TuningSpace { onset_thresh: vec![0.3, 0.7], ..space() };
+        for strategy in [TuningStrategy::Grid, TuningStrategy::CoordinateSearch { max_rounds: 5 }] {
+            let result = tune_decoding_options(&items(), &target_options(), &space, TuningMetric::NoteOnsetOffset, strategy, &NoteMatchingOptions::default());
+            assert_eq!(result.options.onset_thresh, 0.5);
+            assert_eq!((result.score, result.base_score), (1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn closest_index_only_matches_nan_with_nan() {
+        let candidates = [0.1, f32::NAN, 0.3];
+        assert_eq!(closest_index(&candidates, f32::NAN), 1);
+        assert_eq!(closest_index(&candidates, 0.28), 2);
+        assert_eq!(closest_index(&candidates, 0.0), 0);
+        assert_eq!(closest_index(&[0.1, 0.3], f32::NAN), 0);
+        assert_eq!(closest_index(&[], 0.2), 0);
+    }
+}
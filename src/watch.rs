@@ -0,0 +1,314 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ort::Session;
+
+use crate::batch::{
+    is_audio_file, is_real_dir, relative_name, transcribe_item, BatchItem, BatchRecord, BatchStatus, ExistingOutputs, TranscriptionOptions,
+};
+
+/// Settings for watching a folder.
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Time between scans of the input directory.
+    pub poll_interval: Duration,
+    /// Time a file must be left unmodified before it is considered fully written.
+    pub settle_time: Duration,
+    /// Directory failed audio files are moved to, with an error report next to each.
+    pub quarantine_dir: PathBuf,
+    /// Process the files that are ready once and return, instead of watching forever.
+    pub once: bool,
+}
+
+/// Size and modification time of a file, to notice when it changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileState {
+    len: u64,
+    modified: SystemTime,
+}
+
+/// Tracks the audio files of a directory and reports new and changed files once they are fully written.
+#[derive(Debug)]
+pub struct FolderWatcher {
+    input_dir: PathBuf,
+    excluded_dirs: Vec<PathBuf>,
+    settle_time: Duration,
+    seen: HashMap<PathBuf, FileState>,
+}
+
+impl FolderWatcher {
+    /// Create a watcher. Nothing has been seen yet, so the first scan reports every file.
+    ///
+    /// # Arguments
+    ///
+    /// * `input_dir` - Directory to watch, recursively.
+    /// * `excluded_dirs` - Directories not to scan, e.g. an output or quarantine directory inside the input directory.
+    /// * `settle_time` - Time a file must be left unmodified before it is reported.
+    pub fn new(input_dir: &Path, excluded_dirs: &[&Path], settle_time: Duration) -> Self {
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        FolderWatcher {
+            input_dir: input_dir.to_path_buf(),
+            excluded_dirs: excluded_dirs.iter().map(|dir| canonical(dir)).collect(),
+            settle_time,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Scan the directory for new and changed audio files.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time.
+    ///
+    /// # Returns
+    ///
+    /// * The files that are new or changed and have not been modified for the settle time, in path
+    ///   order. They are not reported again until they change. Also returns the number of files that
+    ///   are still being written.
+    pub fn poll(&mut self, now: SystemTime) -> Result<(Vec<BatchItem>, usize), Box<dyn Error>> {
+        let mut paths = vec![];
+        let mut unreadable_dirs = vec![];
+        self.collect_audio_files(&self.input_dir, &mut paths, &mut unreadable_dirs);
+        paths.sort();
+
+        // forget files that were removed, so they are transcribed again if they come back. the files
+        // of a directory that couldn't be read are kept until the next scan
+        let present: HashSet<&PathBuf> = paths.iter().collect();
+        self.seen.retain(|path, _| present.contains(path) || unreadable_dirs.iter().any(|dir| path.starts_with(dir)));
+
+        let mut ready = vec![];
+        let mut n_unsettled = 0;
+        for audio_path in paths {
+            // the file may be removed while scanning
+            let Ok(metadata) = fs::metadata(&audio_path) else {
+                continue;
+            };
+            let state = FileState { len: metadata.len(), modified: metadata.modified()? };
+            if self.seen.get(&audio_path) == Some(&state) {
+                continue;
+            }
+
+            let age = now.duration_since(state.modified).unwrap_or(Duration::ZERO);
+            if state.len == 0 || age < self.settle_time {
+                n_unsettled += 1;
+                continue;
+            }
+
+            self.seen.insert(audio_path.clone(), state);
+            ready.push(BatchItem {
+                relative_path: audio_path.strip_prefix(&self.input_dir)?.to_path_buf(),
+                audio_path,
+            });
+        }
+        Ok((ready, n_unsettled))
+    }
+
+    /// Collect the audio files of a directory, recursively. A directory that can't be read, e.g. because
+    /// it was removed during the scan, is reported and skipped for this scan.
+    fn collect_audio_files(&self, directory: &Path, paths: &mut Vec<PathBuf>, unreadable_dirs: &mut Vec<PathBuf>) {
+        let entries = match fs::read_dir(directory).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("error: could not scan '{}': {}", directory.display(), e);
+                unreadable_dirs.push(directory.to_path_buf());
+                return;
+            }
+        };
+        for entry in entries {
+            let path = entry.path();
+            if is_real_dir(&entry).unwrap_or(false) {
+                let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+                if !self.excluded_dirs.contains(&canonical) {
+                    self.collect_audio_files(&path, paths, unreadable_dirs);
+                }
+            } else if is_audio_file(&path)
+                // sonifications, when the outputs are written to the input directory
+                && !path.file_stem().unwrap_or_default().to_string_lossy().ends_with("_basic_pitch")
+            {
+                paths.push(path);
+            }
+        }
+    }
+}
+
+/// Whether all selected outputs of a file exist and were written after the audio was last modified.
+fn outputs_up_to_date(item: &BatchItem, output_dir: &Path, options: &TranscriptionOptions) -> bool {
+    let Ok(audio_modified) = fs::metadata(&item.audio_path).and_then(|m| m.modified()) else {
+        return false;
+    };
+    options.outputs.paths(output_dir, &item.relative_path).iter().all(|path| {
+        fs::metadata(path).and_then(|m| m.modified()).is_ok_and(|modified| modified >= audio_modified)
+    })
+}
+
+/// Move a failed audio file to the quarantine directory and write an error report next to it.
+///
+/// # Arguments
+///
+/// * `item` - The audio file.
+/// * `error` - Why it failed.
+/// * `quarantine_dir` - Directory to move it to, in the layout of the input directory.
+///
+/// # Returns
+///
+/// * The path of the error report.
+pub fn quarantine_item(item: &BatchItem, error: &str, quarantine_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let quarantined_path = quarantine_dir.join(&item.relative_path);
+    if let Some(parent) = quarantined_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // rename doesn't work across file systems
+    if fs::rename(&item.audio_path, &quarantined_path).is_err() {
+        fs::copy(&item.audio_path, &quarantined_path)?;
+        fs::remove_file(&item.audio_path)?;
+    }
+
+    let mut report_name = quarantined_path.file_name().unwrap_or_default().to_os_string();
+    report_name.push(".error.txt");
+    let report_path = quarantined_path.with_file_name(report_name);
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    fs::write(
+        &report_path,
+        format!("audio: {}\ntime: {}\nerror: {}\n", relative_name(&item.relative_path), timestamp, error),
+    )?;
+    Ok(report_path)
+}
+
+/// Watch a directory and transcribe new and changed audio files with one loaded model.
+///
+/// Outputs that are newer than their audio file are kept, so restarting doesn't transcribe
+/// everything again. Outputs of changed files are replaced. Files that fail are moved to the
+/// quarantine directory with an error report.
+///
+/// # Arguments
+///
+/// * `input_dir` - Directory to watch, recursively.
+/// * `output_dir` - Directory to write the outputs to, in the layout of the input directory.
+/// * `model` - The model session from `load_model`, kept for the lifetime of the watch.
+/// * `options` - Settings for decoding and the output files. Existing outputs are always replaced.
+/// * `watch_options` - Settings for watching.
+/// * `on_done` - Called with the record of every processed file, and the error report of failed files.
+pub fn watch_folder<F: FnMut(&BatchRecord, Option<&Path>)>(
+    input_dir: &Path,
+    output_dir: &Path,
+    model: &Session,
+    options: &TranscriptionOptions,
+    watch_options: &WatchOptions,
+    mut on_done: F,
+) -> Result<(), Box<dyn Error>> {
+    let options = TranscriptionOptions { existing_outputs: ExistingOutputs::Overwrite, ..options.clone() };
+    let mut watcher = FolderWatcher::new(
+        input_dir,
+        &[output_dir, &watch_options.quarantine_dir],
+        watch_options.settle_time,
+    );
+
+    loop {
+        let (ready, n_unsettled) = watcher.poll(SystemTime::now())?;
+        for item in ready {
+            if outputs_up_to_date(&item, output_dir, &options) {
+                continue;
+            }
+
+            let mut record = transcribe_item(&item, output_dir, model, &options);
+            let BatchStatus::Failed(error) = &record.status else {
+                on_done(&record, None);
+                continue;
+            };
+            // a daemon shouldn't stop over one file, a file that can't be moved is reported but left in place
+            match quarantine_item(&item, error, &watch_options.quarantine_dir) {
+                Ok(report_path) => on_done(&record, Some(&report_path)),
+                Err(e) => {
+                    record.status = BatchStatus::Failed(format!("{}, and moving it to quarantine failed: {}", error, e));
+                    on_done(&record, None);
+                }
+            }
+        }
+
+        if watch_options.once && n_unsettled == 0 {
+            return Ok(());
+        }
+        thread::sleep(watch_options.poll_interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::{Duration, SystemTime}};
+
+    use super::FolderWatcher;
+
+    #[test]
+    fn files_are_reported_once_settled_and_again_when_changed() {
+        let dir = std::env::temp_dir().join(format!("basic-pitch-watch-{}", std::process::id()));
+        let output_dir = dir.join("output");
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(dir.join("take.wav"), b"RIFF").unwrap();
+        fs::write(output_dir.join("ignored.wav"), b"RIFF").unwrap();
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        let mut watcher = FolderWatcher::new(&dir, &[&output_dir], Duration::from_secs(2));
+        let now = SystemTime::now();
+
+        // still being written
+        let (ready, n_unsettled) = watcher.poll(now).unwrap();
+        assert!(ready.is_empty());
+        assert_eq!(n_unsettled, 1);
+
+        let later = now + Duration::from_secs(3);
+        let (ready, _) = watcher.poll(later).unwrap();
+        assert_eq!(ready.iter().map(|item| item.relative_path.clone()).collect::<Vec<_>>(), vec![PathBuf::from("take.wav")]);
+        assert!(watcher.poll(later).unwrap().0.is_empty());
+
+        fs::write(dir.join("take.wav"), b"RIFF....").unwrap();
+        assert_eq!(watcher.poll(SystemTime::now() + Duration::from_secs(3)).unwrap().0.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pcm_files_are_reported_and_symlinked_directories_are_not_followed() {
+        let dir = std::env::temp_dir().join(format!("basic-pitch-watch-symlinks-{}", std::process::id()));
+        fs::create_dir_all(dir.join("day 1")).unwrap();
+        fs::write(dir.join("day 1/take.wav"), b"RIFF").unwrap();
+        fs::write(dir.join("day 1/take 2.raw"), b"....").unwrap();
+        // a link back up the tree would be scanned forever
+        std::os::unix::fs::symlink(&dir, dir.join("day 1/loop")).unwrap();
+
+        let mut watcher = FolderWatcher::new(&dir, &[], Duration::ZERO);
+        let (ready, _) = watcher.poll(SystemTime::now() + Duration::from_secs(1)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            ready.iter().map(|item| item.relative_path.clone()).collect::<Vec<_>>(),
+            vec![PathBuf::from("day 1/take 2.raw"), PathBuf::from("day 1/take.wav")]
+        );
+    }
+
+    #[test]
+    fn directories_that_cannot_be_read_are_skipped() {
+        let dir = std::env::temp_dir().join(format!("basic-pitch-watch-removed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("take.wav"), b"RIFF").unwrap();
+
+        let mut watcher = FolderWatcher::new(&dir, &[], Duration::ZERO);
+        let later = SystemTime::now() + Duration::from_secs(1);
+        assert_eq!(watcher.poll(later).unwrap().0.len(), 1);
+
+        // e.g. a bounce folder that is removed while the watcher runs
+        fs::remove_dir_all(&dir).unwrap();
+        let (ready, n_unsettled) = watcher.poll(later).unwrap();
+        assert!(ready.is_empty());
+        assert_eq!(n_unsettled, 0);
+        // the files that were seen are kept until the directory can be read again
+        assert_eq!(watcher.seen.len(), 1);
+    }
+}